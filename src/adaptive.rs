@@ -0,0 +1,314 @@
+use std::collections::VecDeque;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::agent::{Agent, EngineAgent, THINK_TIME};
+use crate::ai::{Minimax, SharedEngine, WORK_BACKSTOP};
+use crate::game::Player;
+use crate::logutil::{LogStatus, log};
+use crate::position::Position;
+use crate::rng::Rng;
+use crate::rules::Rules;
+use crate::tablebase::{TableError, open_or_default, seal, unseal, write_file};
+
+const MAGIC: &[u8; 4] = b"TTTA";
+const VERSION: u16 = 1;
+
+pub const MAX_LEVEL: u8 = 10;
+const START_LEVEL: u8 = 5;
+// Games the human's score is taken over
+const WINDOW: usize = 10;
+// The human's score the level is steered towards, counting a draw as half a win, and how
+// far from it the score may drift before the level moves
+const TARGET: f64 = 0.5;
+const MARGIN: f64 = 0.1;
+// Percent of moves played at random for every level below the top one
+const BLUNDERS_PER_LEVEL: usize = 8;
+
+// How the human has been doing against the adaptive opponent on one board, and the level that
+// came of it. Level n searches n plies and blunders on (MAX_LEVEL - n) * 8% of its moves
+//
+// Sealed as "TTTA" (see tablebase::seal). The body, little endian: width u8, height u8, win
+// length u8, rules name length u8 and bytes, level u8, result count u8 and results i8 (the
+// human's Rules::score, oldest first)
+#[derive(Clone, PartialEq, Debug)]
+pub struct Record {
+    width: u8,
+    height: u8,
+    win_length: u8,
+    rules: String,
+    level: u8,
+    results: VecDeque<i8>,
+}
+
+impl Record {
+    pub fn new(width: u8, height: u8, win_length: u8, rules: &dyn Rules) -> Record {
+        Record {
+            width,
+            height,
+            win_length,
+            rules: rules.name().to_string(),
+            level: START_LEVEL,
+            results: VecDeque::new(),
+        }
+    }
+
+    fn covers(&self, width: u8, height: u8, win_length: u8, rules: &dyn Rules) -> bool {
+        (self.width, self.height, self.win_length) == (width, height, win_length)
+            && self.rules == rules.name()
+    }
+
+    // The human's recent score from 0 to 1, a draw counting half, or None before any game
+    fn score(&self) -> Option<f64> {
+        if self.results.is_empty() {
+            return None;
+        }
+        let points: i32 = self.results.iter().map(|r| *r as i32 + 1).sum();
+        Some(points as f64 / (2 * self.results.len()) as f64)
+    }
+
+    // Notes a finished game, 1, 0 or -1 for the human as Rules::score, and moves the level a
+    // step towards where the human scores TARGET
+    pub fn add(&mut self, result: i32) {
+        if self.results.len() == WINDOW {
+            self.results.pop_front();
+        }
+        self.results.push_back(result.clamp(-1, 1) as i8);
+        let score = self.score().unwrap_or(TARGET);
+        if score > TARGET + MARGIN {
+            self.level = (self.level + 1).min(MAX_LEVEL);
+        } else if score < TARGET - MARGIN {
+            self.level = (self.level - 1).max(1);
+        }
+    }
+
+    fn blunder_percent(&self) -> usize {
+        (MAX_LEVEL - self.level) as usize * BLUNDERS_PER_LEVEL
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend([self.width, self.height, self.win_length]);
+        bytes.push(self.rules.len() as u8);
+        bytes.extend(self.rules.as_bytes());
+        bytes.push(self.level);
+        bytes.push(self.results.len() as u8);
+        bytes.extend(self.results.iter().map(|r| *r as u8));
+        seal(MAGIC, VERSION, &bytes)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Record, TableError> {
+        let mut reader = unseal(bytes, MAGIC, VERSION)?;
+        let (width, height, win_length) = (reader.u8()?, reader.u8()?, reader.u8()?);
+        let name_length = reader.u8()? as usize;
+        let rules = String::from_utf8_lossy(reader.take(name_length)?).into_owned();
+        let level = reader.u8()?.clamp(1, MAX_LEVEL);
+        let count = reader.u8()? as usize;
+        let results = reader.take(count)?.iter().map(|r| *r as i8).collect();
+        reader.end()?;
+        Ok(Record {
+            width,
+            height,
+            win_length,
+            rules,
+            level,
+            results,
+        })
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), TableError> {
+        write_file(path, &self.to_bytes())
+    }
+
+    pub fn load(path: &Path) -> Result<Record, TableError> {
+        Record::from_bytes(&fs::read(path)?)
+    }
+}
+
+// Where adaptive opponents keep the human's record for a board
+pub fn default_path(width: u8, height: u8, win_length: u8, rules: &dyn Rules) -> PathBuf {
+    PathBuf::from(format!(
+        "tables/{}x{}k{}-{}.adaptive",
+        width,
+        height,
+        win_length,
+        rules.name()
+    ))
+}
+
+// The record saved at `path` if it is for this board, otherwise a fresh one
+pub fn open(path: &Path, width: u8, height: u8, win_length: u8, rules: &dyn Rules) -> Record {
+    open_or_default(
+        path,
+        Record::load,
+        |record| record.covers(width, height, win_length, rules),
+        || Record::new(width, height, win_length, rules),
+    )
+}
+
+// The search for `level`, bounded by work rather than the clock with `fixed_work`
+fn search(level: u8, fixed_work: bool) -> EngineAgent {
+    let minimax = Minimax::new(level as u32, THINK_TIME).with_fixed_work(fixed_work);
+    let engine: SharedEngine = Arc::new(Mutex::new(minimax));
+    let time = match fixed_work {
+        true => THINK_TIME * WORK_BACKSTOP,
+        false => THINK_TIME,
+    };
+    EngineAgent::new(engine, time)
+}
+
+// Plays a human at the level of its record: a minimax search as deep as the level that now
+// and then plays a random move instead. After every game it adds the human's result to the
+// record, saving it to `path` when there is one, and plays on at the level it comes to
+pub struct Opponent {
+    record: Record,
+    path: Option<PathBuf>,
+    search: EngineAgent,
+    rng: Rng,
+    side: Player,
+    chosen: Option<usize>,
+    fixed_work: bool,
+}
+
+impl Opponent {
+    pub fn new(record: Record, path: Option<PathBuf>, seed: u64, fixed_work: bool) -> Opponent {
+        Opponent {
+            search: search(record.level, fixed_work),
+            record,
+            path,
+            rng: Rng::new(seed),
+            side: Player::None,
+            chosen: None,
+            fixed_work,
+        }
+    }
+}
+
+impl Agent for Opponent {
+    fn name(&self) -> String {
+        format!("adaptive (level {})", self.record.level)
+    }
+
+    fn start(&mut self, position: &Position, rules: Arc<dyn Rules>) {
+        self.side = position.turn().clone();
+        self.chosen = None;
+        if self.rng.below(100) < self.record.blunder_percent() {
+            let moves = rules.legal_moves(position);
+            if !moves.is_empty() {
+                self.chosen = Some(moves[self.rng.below(moves.len())]);
+                return;
+            }
+        }
+        self.search.start(position, rules);
+    }
+
+    fn poll(&mut self) -> Option<usize> {
+        self.chosen.take().or_else(|| self.search.poll())
+    }
+
+    fn cancel(&mut self) {
+        self.chosen = None;
+        self.search.cancel();
+    }
+
+    fn thinking_for(&self) -> Option<Duration> {
+        self.search.thinking_for()
+    }
+
+    fn game_over(&mut self, position: &Position, rules: &dyn Rules) {
+        let Some(outcome) = rules.outcome(position) else {
+            return;
+        };
+        let before = self.record.level;
+        self.record
+            .add(rules.score(&outcome, &self.side.opponent()));
+        if self.record.level != before {
+            self.search = search(self.record.level, self.fixed_work);
+        }
+        log(
+            LogStatus::INFO,
+            format!(
+                "Adaptive level {} -> {} after {:?}",
+                before, self.record.level, outcome
+            )
+            .as_str(),
+        );
+        if let Some(path) = &self.path
+            && let Err(e) = self.record.save(path)
+        {
+            log(
+                LogStatus::ERROR,
+                format!("Could not save {}: {}", path.display(), e).as_str(),
+            );
+        }
+    }
+
+    fn status(&self) -> Option<String> {
+        let level = format!("Adaptive level {}/{}", self.record.level, MAX_LEVEL);
+        Some(match self.record.score() {
+            Some(score) => format!(
+                "{}, your score over the last {} games {:.0}%",
+                level,
+                self.record.results.len(),
+                score * 100.0
+            ),
+            None => level,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::rules::{Misere, Standard};
+
+    #[test]
+    fn level_follows_the_human() {
+        let mut record = Record::new(3, 3, 3, &Standard);
+        record.add(1);
+        record.add(1);
+        assert_eq!(record.level, START_LEVEL + 2);
+        // wins and losses in turn settle on the target, so the level stays put
+        for result in [-1, -1, 1, -1, 1, -1, 1, -1] {
+            record.add(result);
+        }
+        assert_eq!(record.results.len(), WINDOW);
+        assert_eq!(record.score(), Some(0.5));
+        let level = record.level;
+        record.add(1);
+        assert_eq!(record.level, level);
+        for _ in 0..30 {
+            record.add(-1);
+        }
+        assert_eq!(record.level, 1);
+        assert_eq!(record.blunder_percent(), 72);
+        for _ in 0..30 {
+            record.add(1);
+        }
+        assert_eq!(record.level, MAX_LEVEL);
+        assert_eq!(record.blunder_percent(), 0);
+    }
+
+    #[test]
+    fn round_trips_through_bytes() {
+        let mut record = Record::new(4, 4, 3, &Misere);
+        for result in [1, 0, -1, -1] {
+            record.add(result);
+        }
+        let bytes = record.to_bytes();
+        assert_eq!(Record::from_bytes(&bytes).unwrap(), record);
+    }
+
+    #[test]
+    fn human_losses_lower_the_level() {
+        let mut opponent = Opponent::new(Record::new(3, 3, 3, &Standard), None, 1, false);
+        // X has the top row; the opponent played X, so the human lost
+        let position: Position = "xxx/oo./... o k3".parse().unwrap();
+        opponent.side = Player::X;
+        opponent.game_over(&position, &Standard);
+        assert_eq!(opponent.record.level, START_LEVEL - 1);
+        assert!(opponent.status().unwrap().contains("0%"));
+    }
+}
@@ -0,0 +1,437 @@
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::ai::{Search, SharedEngine};
+use crate::logutil::{LogStatus, log};
+use crate::position::Position;
+use crate::rules::Rules;
+
+// How long engines may think about a move in interactive games
+pub const THINK_TIME: Duration = Duration::from_secs(1);
+
+// One side of a game. Game calls `start` when it becomes this side's turn and then polls
+// until a move comes back, so agents are free to answer at once or much later
+pub trait Agent: Send {
+    fn name(&self) -> String;
+
+    fn start(&mut self, position: &Position, rules: Arc<dyn Rules>);
+
+    // The chosen move, once. Game checks it is legal and asks again if it is not
+    fn poll(&mut self) -> Option<usize>;
+
+    // Drops the move being worked on; the next `start` begins afresh
+    fn cancel(&mut self) {}
+
+    // Human agents are fed their moves through `offer` from the keyboard
+    fn is_human(&self) -> bool {
+        false
+    }
+
+    fn offer(&mut self, index: usize) {
+        let _ = index;
+    }
+
+    // How long the current move has taken so far, for agents worth waiting on visibly
+    fn thinking_for(&self) -> Option<Duration> {
+        None
+    }
+
+    // Why no move is coming, once the agent has given up on the one it was asked for
+    fn failure(&self) -> Option<String> {
+        None
+    }
+
+    // Told how each finished game ended, for agents that learn from their games
+    fn game_over(&mut self, position: &Position, rules: &dyn Rules) {
+        let _ = (position, rules);
+    }
+
+    // A line about the agent shown after each game, for agents with something to report
+    fn status(&self) -> Option<String> {
+        None
+    }
+}
+
+pub type SharedAgent = Arc<Mutex<dyn Agent>>;
+
+pub fn shared(agent: impl Agent + 'static) -> SharedAgent {
+    Arc::new(Mutex::new(agent))
+}
+
+// Whoever is at the keyboard
+#[derive(Default)]
+pub struct Human {
+    chosen: Option<usize>,
+}
+
+impl Agent for Human {
+    fn name(&self) -> String {
+        "human".to_string()
+    }
+
+    fn start(&mut self, _: &Position, _: Arc<dyn Rules>) {
+        self.chosen = None;
+    }
+
+    fn poll(&mut self) -> Option<usize> {
+        self.chosen.take()
+    }
+
+    fn cancel(&mut self) {
+        self.chosen = None;
+    }
+
+    fn is_human(&self) -> bool {
+        true
+    }
+
+    fn offer(&mut self, index: usize) {
+        self.chosen = Some(index);
+    }
+}
+
+// Runs an engine on a background search for every move. The search holds the engine's lock
+// while it runs, so the name is taken once up front for the UI to show meanwhile
+pub struct EngineAgent {
+    engine: SharedEngine,
+    name: String,
+    time_limit: Duration,
+    search: Option<Search>,
+    // Whether the last search came back without a move, as engines that crash do
+    failed: bool,
+}
+
+impl EngineAgent {
+    pub fn new(engine: SharedEngine, time_limit: Duration) -> EngineAgent {
+        let name = engine.lock().unwrap().name();
+        EngineAgent {
+            engine,
+            name,
+            time_limit,
+            search: None,
+            failed: false,
+        }
+    }
+}
+
+impl Agent for EngineAgent {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn start(&mut self, position: &Position, rules: Arc<dyn Rules>) {
+        self.cancel();
+        self.search = Some(Search::start(
+            self.engine.clone(),
+            position.clone(),
+            rules,
+            self.time_limit,
+        ));
+    }
+
+    fn poll(&mut self) -> Option<usize> {
+        let search = self.search.as_ref()?;
+        let result = search.result()?;
+        log(
+            LogStatus::DEBUG,
+            format!("AI answered {:?} after {:?}", result, search.elapsed()).as_str(),
+        );
+        self.search = None;
+        self.failed = result.is_none();
+        result
+    }
+
+    fn cancel(&mut self) {
+        self.failed = false;
+        if let Some(search) = self.search.take() {
+            search.cancel();
+        }
+    }
+
+    fn thinking_for(&self) -> Option<Duration> {
+        self.search.as_ref().map(Search::elapsed)
+    }
+
+    fn failure(&self) -> Option<String> {
+        self.failed.then(|| "no move returned".to_string())
+    }
+}
+
+// Plays a fixed list of moves in order, then fails
+pub struct Scripted {
+    moves: Vec<usize>,
+    next: usize,
+    asked: bool,
+}
+
+impl Scripted {
+    pub fn new(moves: Vec<usize>) -> Scripted {
+        Scripted {
+            moves,
+            next: 0,
+            asked: false,
+        }
+    }
+}
+
+impl Agent for Scripted {
+    fn name(&self) -> String {
+        format!("script ({} of {} moves)", self.next, self.moves.len())
+    }
+
+    fn start(&mut self, _: &Position, _: Arc<dyn Rules>) {
+        self.asked = true;
+    }
+
+    fn poll(&mut self) -> Option<usize> {
+        if !self.asked {
+            return None;
+        }
+        let index = *self.moves.get(self.next)?;
+        self.next += 1;
+        self.asked = false;
+        Some(index)
+    }
+
+    fn cancel(&mut self) {
+        self.asked = false;
+    }
+
+    fn failure(&self) -> Option<String> {
+        (self.asked && self.next >= self.moves.len()).then(|| "out of moves".to_string())
+    }
+}
+
+// How long a remote player has to accept the connection
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+// A player on the other end of a TCP connection. For every move it connects to `address`,
+// sends the position in board notation on one line and reads back "<x> <y>" within
+// `timeout`
+pub struct Remote {
+    address: String,
+    timeout: Duration,
+    answer: Option<Arc<OnceLock<Result<usize, String>>>>,
+    started: Instant,
+}
+
+impl Remote {
+    pub fn new(address: String, timeout: Duration) -> Remote {
+        Remote {
+            address,
+            timeout,
+            answer: None,
+            started: Instant::now(),
+        }
+    }
+}
+
+fn ask(address: &str, position: &Position, timeout: Duration) -> io::Result<usize> {
+    let mut stream = connect(address)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    writeln!(stream, "{}", position)?;
+    let mut line = String::new();
+    BufReader::new(&stream)
+        .read_line(&mut line)
+        .map_err(|e| match e.kind() {
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => {
+                io::Error::new(io::ErrorKind::TimedOut, "no reply in time")
+            }
+            _ => e,
+        })?;
+
+    let mut coordinates = line.split_whitespace().map(str::parse::<u8>);
+    match (coordinates.next(), coordinates.next()) {
+        (Some(Ok(x)), Some(Ok(y))) if x < position.width() && y < position.height() => {
+            Ok(position.index(x, y))
+        }
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unexpected reply {:?}", line.trim()),
+        )),
+    }
+}
+
+// The first of the addresses `address` resolves to that accepts within CONNECT_TIMEOUT
+fn connect(address: &str) -> io::Result<TcpStream> {
+    let mut error = io::Error::new(io::ErrorKind::NotFound, "no such address");
+    for address in address.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&address, CONNECT_TIMEOUT) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => error = e,
+        }
+    }
+    Err(error)
+}
+
+impl Agent for Remote {
+    fn name(&self) -> String {
+        format!("remote ({})", self.address)
+    }
+
+    fn start(&mut self, position: &Position, _: Arc<dyn Rules>) {
+        let answer = Arc::new(OnceLock::new());
+        let (slot, address, position) = (answer.clone(), self.address.clone(), position.clone());
+        let timeout = self.timeout;
+        thread::spawn(move || {
+            let index = ask(&address, &position, timeout).map_err(|e| {
+                log(
+                    LogStatus::ERROR,
+                    format!("Remote player at {} failed: {}", address, e).as_str(),
+                );
+                e.to_string()
+            });
+            slot.set(index).ok();
+        });
+        self.answer = Some(answer);
+        self.started = Instant::now();
+    }
+
+    // A failed exchange leaves the move unanswered and shows as the failure
+    fn poll(&mut self) -> Option<usize> {
+        let index = *self.answer.as_ref()?.get()?.as_ref().ok()?;
+        self.answer = None;
+        Some(index)
+    }
+
+    fn cancel(&mut self) {
+        self.answer = None;
+    }
+
+    fn thinking_for(&self) -> Option<Duration> {
+        let answer = self.answer.as_ref()?;
+        answer.get().is_none().then(|| self.started.elapsed())
+    }
+
+    fn failure(&self) -> Option<String> {
+        self.answer.as_ref()?.get()?.as_ref().err().cloned()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::TcpListener;
+
+    use super::*;
+    use crate::ai::{Budget, Mcts, Minimax};
+    use crate::protocol::External;
+    use crate::rules::Standard;
+
+    fn wait(agent: &mut dyn Agent) -> usize {
+        loop {
+            if let Some(index) = agent.poll() {
+                return index;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn human_only_answers_when_offered() {
+        let mut human = Human::default();
+        human.start(&Position::new(3, 3, 3), Arc::new(Standard));
+        assert_eq!(human.poll(), None);
+        human.offer(4);
+        assert_eq!(human.poll(), Some(4));
+        assert_eq!(human.poll(), None);
+    }
+
+    #[test]
+    fn engine_agent_searches_in_the_background() {
+        let engine: SharedEngine = Arc::new(Mutex::new(Minimax::new(9, THINK_TIME)));
+        let mut agent = EngineAgent::new(engine, Duration::from_secs(10));
+        let position: Position = "xx./oo./... x k3".parse().unwrap();
+        agent.start(&position, Arc::new(Standard));
+        assert!(agent.thinking_for().is_some());
+        assert_eq!(wait(&mut agent), 2);
+        assert!(agent.thinking_for().is_none());
+    }
+
+    #[test]
+    fn engine_agent_names_itself_while_searching() {
+        let mcts = Mcts::new(Budget::Time(Duration::from_secs(2)), 1);
+        let engine: SharedEngine = Arc::new(Mutex::new(mcts));
+        let mut agent = EngineAgent::new(engine, Duration::from_secs(10));
+        agent.start(&Position::new(15, 15, 5), Arc::new(Standard));
+        thread::sleep(Duration::from_millis(50));
+        let asked = Instant::now();
+        assert_eq!(agent.name(), "mcts (2000 ms)");
+        assert!(asked.elapsed() < Duration::from_millis(100));
+        assert!(agent.thinking_for().is_some());
+        agent.cancel();
+    }
+
+    #[test]
+    fn engine_agent_reports_engines_that_give_no_move() {
+        let missing = External::new("./no-such-engine".to_string(), THINK_TIME);
+        let mut agent = EngineAgent::new(Arc::new(Mutex::new(missing)), Duration::from_secs(10));
+        let position = Position::new(3, 3, 3);
+        agent.start(&position, Arc::new(Standard));
+        let started = Instant::now();
+        while agent.failure().is_none() {
+            assert!(started.elapsed() < Duration::from_secs(5));
+            assert_eq!(agent.poll(), None);
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(agent.failure().as_deref(), Some("no move returned"));
+        assert!(agent.thinking_for().is_none());
+        // asking again starts over
+        agent.start(&position, Arc::new(Standard));
+        assert_eq!(agent.failure(), None);
+        agent.cancel();
+    }
+
+    #[test]
+    fn scripted_plays_its_moves_in_order() {
+        let mut script = Scripted::new(vec![4, 0]);
+        let position = Position::new(3, 3, 3);
+        assert_eq!(script.poll(), None);
+        for expected in [Some(4), Some(0), None] {
+            assert_eq!(script.failure(), None);
+            script.start(&position, Arc::new(Standard));
+            assert_eq!(script.poll(), expected);
+        }
+        assert_eq!(script.failure().as_deref(), Some("out of moves"));
+    }
+
+    #[test]
+    fn remote_exchanges_positions_for_moves() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut line = String::new();
+            BufReader::new(&stream).read_line(&mut line).unwrap();
+            writeln!(&stream, "2 1").unwrap();
+            line
+        });
+
+        let mut remote = Remote::new(address, Duration::from_secs(10));
+        let position: Position = "x../.o./... x k3".parse().unwrap();
+        remote.start(&position, Arc::new(Standard));
+        assert_eq!(wait(&mut remote), 5);
+        assert_eq!(server.join().unwrap().trim(), "x../.o./... x k3");
+    }
+
+    #[test]
+    fn remote_gives_up_on_silent_players() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let mut remote = Remote::new(address, Duration::from_millis(100));
+        remote.start(&Position::new(3, 3, 3), Arc::new(Standard));
+        // accepted by the listener's backlog, but never answered
+        let started = Instant::now();
+        while remote.failure().is_none() {
+            assert!(started.elapsed() < Duration::from_secs(5));
+            assert_eq!(remote.poll(), None);
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(remote.failure().as_deref(), Some("no reply in time"));
+        assert!(remote.thinking_for().is_none());
+        drop(listener);
+    }
+}
@@ -0,0 +1,2001 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::{BuildHasherDefault, Hasher};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::game::Player;
+use crate::logutil::{LogStatus, log};
+use crate::patterns::Weights;
+use crate::position::Position;
+use crate::rng::Rng;
+use crate::rules::{self, Outcome, Rules};
+use crate::tablebase::{Tablebase, Value};
+
+// Anything that can pick a move for the side to move
+pub trait Engine: Send {
+    fn name(&self) -> String;
+
+    // None when the position has no legal moves. Engines poll `cancel` and return the best
+    // move found so far once it trips
+    fn choose_move(
+        &mut self,
+        position: &Position,
+        rules: &dyn Rules,
+        cancel: &Cancel,
+    ) -> Option<usize>;
+
+    // The chosen move together with what the engine makes of the position
+    fn analyse(
+        &mut self,
+        position: &Position,
+        rules: &dyn Rules,
+        cancel: &Cancel,
+    ) -> Option<(usize, Evaluation)> {
+        self.choose_move(position, rules, cancel)
+            .map(|index| (index, Evaluation::Unknown))
+    }
+
+    // The moves of both sides the last `analyse` expects, starting with the one it chose.
+    // Empty when the engine does not look that far or does not say
+    fn line(&self) -> Vec<usize> {
+        Vec::new()
+    }
+
+    // How every legal move looks for the side to move, for displays. Empty when the engine
+    // has no opinion
+    fn rate_moves(
+        &mut self,
+        position: &Position,
+        rules: &dyn Rules,
+        cancel: &Cancel,
+    ) -> Vec<(usize, Evaluation)> {
+        let _ = (position, rules, cancel);
+        Vec::new()
+    }
+}
+
+pub type SharedEngine = Arc<Mutex<dyn Engine>>;
+
+// The prospects of the side to move. Wins and losses count that side's own moves, the
+// deciding one included
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Evaluation {
+    Win(u32),
+    Draw,
+    Loss(u32),
+    // Not searched to the end; positive favours the side to move
+    Score(i32),
+    // Share of random playouts won, draws counting half
+    Rate(f64),
+    Unknown,
+}
+
+impl Evaluation {
+    fn of_outcome(outcome: &Outcome, rules: &dyn Rules, mover: &Player) -> Evaluation {
+        match rules.score(outcome, mover) {
+            1 => Evaluation::Win(1),
+            -1 => Evaluation::Loss(1),
+            _ => Evaluation::Draw,
+        }
+    }
+
+    // The same evaluation one ply earlier, for the side that made the move
+    pub fn before(self) -> Evaluation {
+        match self {
+            Evaluation::Win(moves) => Evaluation::Loss(moves),
+            Evaluation::Loss(moves) => Evaluation::Win(moves + 1),
+            Evaluation::Draw => Evaluation::Draw,
+            Evaluation::Score(score) => Evaluation::Score(-score),
+            Evaluation::Rate(rate) => Evaluation::Rate(1.0 - rate),
+            Evaluation::Unknown => Evaluation::Unknown,
+        }
+    }
+
+    fn from_plies(plies: u32, win: bool) -> Evaluation {
+        let moves = plies.div_ceil(2);
+        if win {
+            Evaluation::Win(moves)
+        } else {
+            Evaluation::Loss(moves)
+        }
+    }
+}
+
+impl From<Value> for Evaluation {
+    fn from(value: Value) -> Evaluation {
+        match value {
+            Value::Win(plies) => Evaluation::from_plies(plies as u32, true),
+            Value::Draw => Evaluation::Draw,
+            Value::Loss(plies) => Evaluation::from_plies(plies as u32, false),
+        }
+    }
+}
+
+impl fmt::Display for Evaluation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Evaluation::Win(moves) => write!(f, "win in {}", moves),
+            Evaluation::Draw => write!(f, "draw"),
+            Evaluation::Loss(moves) => write!(f, "loss in {}", moves),
+            Evaluation::Score(score) => write!(f, "unclear ({:+})", score),
+            Evaluation::Rate(rate) => write!(f, "wins {:.0}% of playouts", rate * 100.0),
+            Evaluation::Unknown => write!(f, "unclear"),
+        }
+    }
+}
+
+// Stop signal shared between a running search and whoever started it. Trips when
+// cancelled or, if it has one, when the deadline passes
+#[derive(Clone, Default)]
+pub struct Cancel {
+    flag: Arc<AtomicBool>,
+    deadline: Option<Instant>,
+}
+
+impl Cancel {
+    pub fn with_deadline(deadline: Instant) -> Cancel {
+        Cancel {
+            flag: Arc::default(),
+            deadline: Some(deadline),
+        }
+    }
+
+    // Shares this signal but also trips after `limit`, whichever comes first
+    pub fn limited(&self, limit: Duration) -> Cancel {
+        let deadline = Instant::now() + limit;
+        Cancel {
+            flag: self.flag.clone(),
+            deadline: Some(self.deadline.map_or(deadline, |d| d.min(deadline))),
+        }
+    }
+
+    pub fn cancel(&self) {
+        self.flag.store(true, Ordering::Relaxed);
+    }
+
+    // Only whether `cancel` was called, for work that keeps its own time
+    pub fn was_cancelled(&self) -> bool {
+        self.flag.load(Ordering::Relaxed)
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.flag.load(Ordering::Relaxed) || self.deadline.is_some_and(|d| Instant::now() >= d)
+    }
+}
+
+// Work running on its own thread, so the UI can keep drawing while an engine thinks. By
+// default a move search
+#[derive(Clone)]
+pub struct Search<T = Option<(usize, Evaluation)>> {
+    result: Arc<OnceLock<T>>,
+    cancel: Cancel,
+    started: Instant,
+}
+
+impl<T: Send + Sync + 'static> Search<T> {
+    // Runs `work` with a signal that trips after `time_limit` or on `cancel`
+    pub fn spawn(time_limit: Duration, work: impl FnOnce(&Cancel) -> T + Send + 'static) -> Self {
+        let started = Instant::now();
+        let result = Arc::new(OnceLock::new());
+        let cancel = Cancel::with_deadline(started + time_limit);
+
+        let (slot, stop) = (result.clone(), cancel.clone());
+        thread::spawn(move || {
+            slot.set(work(&stop)).ok();
+        });
+
+        Search {
+            result,
+            cancel,
+            started,
+        }
+    }
+
+    // None until the work is done
+    pub fn finished(&self) -> Option<&T> {
+        self.result.get()
+    }
+
+    pub fn cancel(&self) {
+        self.cancel.cancel();
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+}
+
+impl Search {
+    pub fn start(
+        engine: SharedEngine,
+        position: Position,
+        rules: Arc<dyn Rules>,
+        time_limit: Duration,
+    ) -> Search {
+        Search::spawn(time_limit, move |stop| {
+            engine
+                .lock()
+                .unwrap()
+                .analyse(&position, rules.as_ref(), stop)
+        })
+    }
+
+    // None while still thinking, then the engine's answer
+    pub fn result(&self) -> Option<Option<usize>> {
+        self.analysis()
+            .map(|analysis| analysis.map(|(index, _)| index))
+    }
+
+    pub fn analysis(&self) -> Option<Option<(usize, Evaluation)>> {
+        self.finished().cloned()
+    }
+}
+
+const WIN: i32 = 1_000_000;
+// Scores beyond this are forced wins or losses rather than heuristic values
+const FORCED: i32 = WIN - 1000;
+const TABLE_LIMIT: usize = 1 << 20;
+
+// Forced-win scores shrink with the distance from the root; the table stores them
+// relative to the position instead so entries can be reused at any ply
+fn to_table(score: i32, ply: i32) -> i32 {
+    match score {
+        s if s > FORCED => s + ply,
+        s if s < -FORCED => s - ply,
+        s => s,
+    }
+}
+
+fn from_table(score: i32, ply: i32) -> i32 {
+    match score {
+        s if s > FORCED => s - ply,
+        s if s < -FORCED => s + ply,
+        s => s,
+    }
+}
+
+// What a move does to the lines through it for the side to move: how much it extends its
+// own open lines, and how much it blocks the opponent's
+fn line_pressure(position: &Position, index: usize) -> (i32, i32) {
+    let mover = position.turn();
+    let mine = *position.pieces(mover);
+    let theirs = *position.pieces(&mover.opponent());
+    let (mut attack, mut defence) = (0, 0);
+    for line in position.lines().through(index) {
+        let (own, other) = ((mine & *line).count(), (theirs & *line).count());
+        if other == 0 {
+            attack += rules::line_weight(own + 1);
+        }
+        if own == 0 {
+            defence += rules::line_weight(other + 1);
+        }
+    }
+    (attack, defence)
+}
+
+// How promising a move looks before searching it: completing or blocking long lines
+// first, then cells near the centre
+pub fn move_priority(position: &Position, index: usize) -> i32 {
+    let (attack, defence) = line_pressure(position, index);
+    let priority = attack + defence;
+    let (x, y) = (
+        (index % position.width() as usize) as i32,
+        (index / position.width() as usize) as i32,
+    );
+    let (cx, cy) = (position.width() as i32 - 1, position.height() as i32 - 1);
+    priority * 4 - ((2 * x - cx).abs() + (2 * y - cy).abs())
+}
+
+// How an engine picks between moves its search values the same
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Style {
+    Balanced,
+    // builds its own lines
+    Aggressive,
+    // blocks the opponent's lines
+    Defensive,
+    // leaves the opponent the most replies that lose at once
+    Trappy,
+}
+
+impl Style {
+    // The scoring hook styles share: how much this style likes playing `index` for the
+    // side to move. Only compared between moves of equal value
+    pub fn preference(&self, position: &Position, rules: &dyn Rules, index: usize) -> i32 {
+        match self {
+            Style::Balanced => 0,
+            Style::Aggressive => line_pressure(position, index).0,
+            Style::Defensive => line_pressure(position, index).1,
+            Style::Trappy => losing_replies(position, rules, index) as i32,
+        }
+    }
+}
+
+impl FromStr for Style {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Style, String> {
+        match s {
+            "balanced" => Ok(Style::Balanced),
+            "aggressive" => Ok(Style::Aggressive),
+            "defensive" => Ok(Style::Defensive),
+            "trappy" => Ok(Style::Trappy),
+            _ => Err(format!("unknown style {:?}", s)),
+        }
+    }
+}
+
+impl fmt::Display for Style {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Style::Balanced => "balanced",
+            Style::Aggressive => "aggressive",
+            Style::Defensive => "defensive",
+            Style::Trappy => "trappy",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+// How many of the opponent's replies to `index` hand the side to move the game on its next
+// move, or end it in that side's favour straight away
+fn losing_replies(position: &Position, rules: &dyn Rules, index: usize) -> usize {
+    let mover = position.turn().clone();
+    let mut position = position.clone();
+    rules.apply(&mut position, index);
+    if rules.outcome_after(&position, index).is_some() {
+        return 0;
+    }
+    let threats = completing_cells(&position, &mover);
+    let wins = |position: &mut Position, cell: usize| {
+        rules.apply(position, cell);
+        let won = rules
+            .outcome_after(position, cell)
+            .is_some_and(|outcome| rules.score(&outcome, &mover) > 0);
+        rules.undo(position, cell);
+        won
+    };
+    rules
+        .legal_moves(&position)
+        .into_iter()
+        .filter(|reply| {
+            rules.apply(&mut position, *reply);
+            let lost = match rules.outcome_after(&position, *reply) {
+                Some(outcome) => rules.score(&outcome, &mover) > 0,
+                None => threats
+                    .iter()
+                    .any(|cell| cell != reply && wins(&mut position, *cell)),
+            };
+            rules.undo(&mut position, *reply);
+            lost
+        })
+        .count()
+}
+
+// Zobrist hashes are already uniformly distributed, so the table uses them as they are
+#[derive(Default)]
+struct ZobristHasher(u64);
+
+impl Hasher for ZobristHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 = self.0.rotate_left(8) ^ *byte as u64;
+        }
+    }
+
+    fn write_u64(&mut self, hash: u64) {
+        self.0 = hash;
+    }
+}
+
+type Table = HashMap<u64, Entry, BuildHasherDefault<ZobristHasher>>;
+
+#[derive(Clone, Copy, Debug)]
+enum Bound {
+    Exact,
+    Lower,
+    Upper,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Entry {
+    depth: u32,
+    score: i32,
+    bound: Bound,
+    best: Option<usize>,
+}
+
+struct Context<'a> {
+    rules: &'a dyn Rules,
+    cancel: &'a Cancel,
+    // Nodes each thread may search before it stops as if cancelled
+    node_limit: u64,
+}
+
+// Playouts and minimax nodes a second of thinking buys, times the cells of the board, as
+// measured on release builds; both cost about in proportion to the size of the board
+const PLAYOUTS_PER_CELL_SECOND: f64 = 2_000_000.0;
+const NODES_PER_CELL_SECOND: f64 = 4_000_000.0;
+// How many times their time limit searches with fixed work may run before the clock stops
+// them anyway. Generous, as debug builds do the same work dozens of times slower
+pub const WORK_BACKSTOP: u32 = 100;
+
+// The count of playouts or nodes standing in for `time` of searching on `position`'s board
+fn fixed_work(time: Duration, position: &Position, per_cell_second: f64) -> u64 {
+    let cells = position.width() as f64 * position.height() as f64;
+    (time.as_secs_f64() * per_cell_second / cells).max(1.0) as u64
+}
+
+// Negamax with alpha-beta pruning, searched by iterative deepening until the depth limit,
+// the time limit or a forced result is reached. A transposition table keyed by Zobrist
+// hash carries results and best moves between iterations, and killer moves plus the
+// line heuristic order the rest. Positions covered by a solved table are answered from it.
+// With more than one thread the root moves of each iteration are dealt out to helpers
+pub struct Minimax {
+    max_depth: u32,
+    time_limit: Duration,
+    tablebase: Option<Arc<Tablebase>>,
+    weights: Option<Arc<Weights>>,
+    table: Table,
+    table_rules: &'static str,
+    killers: Vec<[Option<usize>; 2]>,
+    nodes: u64,
+    threads: usize,
+    // Searches for the other threads, kept between moves along with their tables
+    helpers: Vec<Minimax>,
+    style: Style,
+    fixed_work: bool,
+}
+
+impl Minimax {
+    pub fn new(max_depth: u32, time_limit: Duration) -> Minimax {
+        Minimax {
+            max_depth,
+            time_limit,
+            tablebase: None,
+            weights: None,
+            table: Table::default(),
+            table_rules: "",
+            killers: Vec::new(),
+            nodes: 0,
+            threads: 1,
+            helpers: Vec::new(),
+            style: Style::Balanced,
+            fixed_work: false,
+        }
+    }
+
+    // Breaks ties between root moves of equal value the way `style` likes
+    pub fn with_style(mut self, style: Style) -> Minimax {
+        self.style = style;
+        self
+    }
+
+    // Searches on `threads` threads. A single thread keeps every search reproducible
+    pub fn with_threads(mut self, threads: usize) -> Minimax {
+        self.threads = threads.max(1);
+        self
+    }
+
+    pub fn with_tablebase(mut self, tablebase: Option<Tablebase>) -> Minimax {
+        self.tablebase = tablebase.map(Arc::new);
+        self
+    }
+
+    // Trained pattern weights replace the rules' own evaluation where they apply
+    pub fn with_weights(mut self, weights: Option<Weights>) -> Minimax {
+        self.weights = weights.map(Arc::new);
+        self
+    }
+
+    // Stops after the nodes the time limit usually buys rather than at the time limit, so a
+    // single thread searches the same tree on every run. The clock only stops searches that
+    // run past WORK_BACKSTOP times the limit
+    pub fn with_fixed_work(mut self, fixed_work: bool) -> Minimax {
+        self.fixed_work = fixed_work;
+        self
+    }
+
+    // Whether the search has to stop: cancelled, or out of nodes
+    fn stopped(&self, context: &Context) -> bool {
+        context.cancel.is_cancelled() || self.nodes >= context.node_limit
+    }
+
+    fn evaluate(&self, rules: &dyn Rules, position: &Position) -> i32 {
+        match &self.weights {
+            Some(weights) if weights.covers(position, rules) => weights.evaluate(position),
+            _ => rules.evaluate(position),
+        }
+    }
+
+    fn ordered_moves(
+        &self,
+        rules: &dyn Rules,
+        position: &Position,
+        ply: usize,
+        table_move: Option<usize>,
+    ) -> Vec<usize> {
+        // the first of several equally good root moves is the one kept, so a style puts its
+        // favourites first there
+        if ply == 0 && self.style != Style::Balanced {
+            let mut moves: Vec<((i32, i32), usize)> = rules
+                .legal_moves(position)
+                .into_iter()
+                .map(|index| {
+                    let preference = self.style.preference(position, rules, index);
+                    ((preference, move_priority(position, index)), index)
+                })
+                .collect();
+            moves.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(&b.1)));
+            return moves.into_iter().map(|(_, index)| index).collect();
+        }
+        let killers = self.killers.get(ply).copied().unwrap_or_default();
+        let mut moves: Vec<(i32, usize)> = rules
+            .legal_moves(position)
+            .into_iter()
+            .map(|index| {
+                let priority = if Some(index) == table_move {
+                    i32::MAX
+                } else if killers.contains(&Some(index)) {
+                    i32::MAX - 1
+                } else {
+                    move_priority(position, index)
+                };
+                (priority, index)
+            })
+            .collect();
+        moves.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(&b.1)));
+        moves.into_iter().map(|(_, index)| index).collect()
+    }
+
+    fn store_killer(&mut self, ply: usize, index: usize) {
+        if self.killers.len() <= ply {
+            self.killers.resize(ply + 1, [None; 2]);
+        }
+        let slot = &mut self.killers[ply];
+        if slot[0] != Some(index) {
+            slot[1] = slot[0];
+            slot[0] = Some(index);
+        }
+    }
+
+    // Score for the side to move; wins found sooner score higher. Meaningless once the
+    // search has stopped
+    fn negamax(
+        &mut self,
+        context: &Context,
+        position: &mut Position,
+        depth: u32,
+        ply: i32,
+        mut alpha: i32,
+        beta: i32,
+    ) -> i32 {
+        if self.stopped(context) {
+            return 0;
+        }
+        self.nodes += 1;
+
+        let original_alpha = alpha;
+        let mut table_move = None;
+        let (key, transform) = position.canonical_hash();
+        let (width, height) = (position.width(), position.height());
+        if let Some(entry) = self.table.get(&key) {
+            table_move = entry
+                .best
+                .map(|best| transform.inverse().map_index(best, width, height));
+            if entry.depth >= depth {
+                let score = from_table(entry.score, ply);
+                match entry.bound {
+                    Bound::Exact => return score,
+                    Bound::Lower if score >= beta => return score,
+                    Bound::Upper if score <= alpha => return score,
+                    _ => {}
+                }
+            }
+        }
+
+        if context.rules.certain_draw(position) {
+            return 0;
+        }
+        let moves = self.ordered_moves(context.rules, position, ply as usize, table_move);
+        if moves.is_empty() {
+            return 0;
+        }
+        let mover = position.turn().clone();
+        let mut best = (-WIN, None);
+        for index in moves {
+            context.rules.apply(position, index);
+            let score = match context.rules.outcome_after(position, index) {
+                Some(outcome) => context.rules.score(&outcome, &mover) * (WIN - ply - 1),
+                None if depth <= 1 => -self.evaluate(context.rules, position),
+                None => -self.negamax(context, position, depth - 1, ply + 1, -beta, -alpha),
+            };
+            context.rules.undo(position, index);
+
+            if score > best.0 {
+                best = (score, Some(index));
+            }
+            alpha = alpha.max(score);
+            if alpha >= beta {
+                self.store_killer(ply as usize, index);
+                break;
+            }
+        }
+
+        if !self.stopped(context) {
+            let bound = if best.0 <= original_alpha {
+                Bound::Upper
+            } else if best.0 >= beta {
+                Bound::Lower
+            } else {
+                Bound::Exact
+            };
+            if self.table.len() >= TABLE_LIMIT {
+                self.table.clear();
+            }
+            // best moves are kept in the coordinates of the canonical variant, so every
+            // rotation or mirror image of this position can reuse them
+            self.table.insert(
+                key,
+                Entry {
+                    depth,
+                    score: to_table(best.0, ply),
+                    bound,
+                    best: best.1.map(|best| transform.map_index(best, width, height)),
+                },
+            );
+        }
+        best.0
+    }
+
+    // Scores `moves` from the root at `depth`, cutting lines that cannot beat the best score
+    // any thread has found so far
+    fn search_moves(
+        &mut self,
+        context: &Context,
+        position: &Position,
+        depth: u32,
+        moves: &[usize],
+        alpha: &AtomicI32,
+    ) -> Vec<(usize, i32, bool)> {
+        let mut position = position.clone();
+        let mover = position.turn().clone();
+        let mut scored = Vec::new();
+        for &index in moves {
+            context.rules.apply(&mut position, index);
+            let (score, exact) = match context.rules.outcome_after(&position, index) {
+                Some(outcome) => (context.rules.score(&outcome, &mover) * (WIN - 1), true),
+                None if depth <= 1 => (-self.evaluate(context.rules, &position), true),
+                None => {
+                    let alpha = alpha.load(Ordering::Relaxed);
+                    let score = -self.negamax(context, &mut position, depth - 1, 1, -WIN, -alpha);
+                    (score, alpha == -WIN || score > alpha)
+                }
+            };
+            context.rules.undo(&mut position, index);
+            alpha.fetch_max(score, Ordering::Relaxed);
+            scored.push((index, score, exact));
+        }
+        scored
+    }
+
+    // One iteration with the root moves dealt out between this search and its helpers,
+    // each searching its share on its own thread with its own table. Scores that beat the
+    // shared alpha are exact; the rest are only upper bounds, so any of those that could
+    // still match the best exact score is searched again with a full window
+    fn split_root(&mut self, context: &Context, position: &Position, depth: u32) -> i32 {
+        while self.helpers.len() + 1 < self.threads {
+            let mut helper = Minimax::new(self.max_depth, self.time_limit);
+            helper.weights = self.weights.clone();
+            self.helpers.push(helper);
+        }
+        let (key, transform) = position.canonical_hash();
+        let (width, height) = (position.width(), position.height());
+        let table_move = self
+            .table
+            .get(&key)
+            .and_then(|entry| entry.best)
+            .map(|best| transform.inverse().map_index(best, width, height));
+        let moves = self.ordered_moves(context.rules, position, 0, table_move);
+        let shares: Vec<Vec<usize>> = (0..self.threads)
+            .map(|thread| {
+                moves
+                    .iter()
+                    .skip(thread)
+                    .step_by(self.threads)
+                    .copied()
+                    .collect()
+            })
+            .collect();
+
+        let alpha = AtomicI32::new(-WIN);
+        let mut helpers = std::mem::take(&mut self.helpers);
+        let mut scored: Vec<(usize, i32, bool)> = thread::scope(|scope| {
+            let alpha = &alpha;
+            let running: Vec<_> = helpers
+                .iter_mut()
+                .zip(&shares[1..])
+                .map(|(helper, share)| {
+                    helper.nodes = 0;
+                    scope.spawn(move || helper.search_moves(context, position, depth, share, alpha))
+                })
+                .collect();
+            let mut scored = self.search_moves(context, position, depth, &shares[0], alpha);
+            for search in running {
+                scored.extend(search.join().unwrap());
+            }
+            scored
+        });
+        self.nodes += helpers.iter().map(|helper| helper.nodes).sum::<u64>();
+        self.helpers = helpers;
+
+        let best_exact = scored
+            .iter()
+            .filter(|(_, _, exact)| *exact)
+            .map(|(_, score, _)| *score)
+            .max()
+            .unwrap_or(-WIN);
+        for entry in scored.iter_mut() {
+            if !entry.2 && entry.1 >= best_exact {
+                let full = AtomicI32::new(-WIN);
+                *entry = self.search_moves(context, position, depth, &[entry.0], &full)[0];
+            }
+        }
+
+        // ties go to the move ordered first, as they would on one thread
+        let Some((best, score)) = moves
+            .iter()
+            .filter_map(|index| scored.iter().find(|(cell, _, _)| cell == index))
+            .map(|(index, score, _)| (*index, *score))
+            .reduce(|best, next| if next.1 > best.1 { next } else { best })
+        else {
+            return 0;
+        };
+        if !self.stopped(context) {
+            self.table.insert(
+                key,
+                Entry {
+                    depth,
+                    score: to_table(score, 0),
+                    bound: Bound::Exact,
+                    best: Some(transform.map_index(best, width, height)),
+                },
+            );
+        }
+        score
+    }
+
+    // Searches for `time`, or the nodes it buys. Forced results are exact; anything else is
+    // the score of the last finished iteration, or a draw once the search has reached the
+    // end of every line
+    fn analyse_within(
+        &mut self,
+        position: &Position,
+        rules: &dyn Rules,
+        cancel: &Cancel,
+        time: Duration,
+    ) -> Option<(usize, Evaluation)> {
+        if rules.outcome(position).is_some() {
+            return None;
+        }
+        if let Some((index, value)) = self
+            .tablebase
+            .as_ref()
+            .and_then(|tablebase| tablebase.best_move(position, rules))
+        {
+            log(
+                LogStatus::DEBUG,
+                format!("minimax table move {} keeps {:?}", index, value).as_str(),
+            );
+            return Some((index, value.into()));
+        }
+        if self.table_rules != rules.name() {
+            self.table.clear();
+            for helper in &mut self.helpers {
+                helper.table.clear();
+            }
+            self.table_rules = rules.name();
+        }
+        self.killers.clear();
+        self.nodes = 0;
+
+        let (stop, node_limit) = match self.fixed_work {
+            true => (
+                cancel.limited(time * WORK_BACKSTOP),
+                fixed_work(time, position, NODES_PER_CELL_SECOND),
+            ),
+            false => (cancel.limited(time), u64::MAX),
+        };
+        let context = Context {
+            rules,
+            cancel: &stop,
+            node_limit,
+        };
+
+        let mut position = position.clone();
+        let remaining = rules.legal_moves(&position).len() as u32;
+        let mut best = self
+            .ordered_moves(rules, &position, 0, None)
+            .first()
+            .copied();
+        let mut evaluation = Evaluation::Unknown;
+        for depth in 1..=self.max_depth.min(remaining) {
+            let score = if self.threads > 1 {
+                self.split_root(&context, &position, depth)
+            } else {
+                self.negamax(&context, &mut position, depth, 0, -WIN, WIN)
+            };
+            if self.stopped(&context) {
+                break;
+            }
+            evaluation = match score {
+                score if score > FORCED => Evaluation::from_plies((WIN - score) as u32, true),
+                score if score < -FORCED => Evaluation::from_plies((WIN + score) as u32, false),
+                0 if depth == remaining => Evaluation::Draw,
+                score => Evaluation::Score(score),
+            };
+            let (key, transform) = position.canonical_hash();
+            best = self
+                .table
+                .get(&key)
+                .and_then(|entry| entry.best)
+                .map(|best| {
+                    transform
+                        .inverse()
+                        .map_index(best, position.width(), position.height())
+                })
+                .or(best);
+            log(
+                LogStatus::DEBUG,
+                format!(
+                    "minimax depth {} score {} move {:?} nodes {}",
+                    depth, score, best, self.nodes
+                )
+                .as_str(),
+            );
+            if score.abs() > FORCED {
+                break;
+            }
+        }
+        best.map(|index| (index, evaluation))
+    }
+}
+
+impl Engine for Minimax {
+    fn name(&self) -> String {
+        format!("minimax ({} ms)", self.time_limit.as_millis())
+    }
+
+    fn choose_move(
+        &mut self,
+        position: &Position,
+        rules: &dyn Rules,
+        cancel: &Cancel,
+    ) -> Option<usize> {
+        self.analyse(position, rules, cancel)
+            .map(|(index, _)| index)
+    }
+
+    fn analyse(
+        &mut self,
+        position: &Position,
+        rules: &dyn Rules,
+        cancel: &Cancel,
+    ) -> Option<(usize, Evaluation)> {
+        self.analyse_within(position, rules, cancel, self.time_limit)
+    }
+
+    // Analyses the position after each move, sharing the time limit between them
+    fn rate_moves(
+        &mut self,
+        position: &Position,
+        rules: &dyn Rules,
+        cancel: &Cancel,
+    ) -> Vec<(usize, Evaluation)> {
+        let moves = rules.legal_moves(position);
+        let share = self.time_limit / moves.len().max(1) as u32;
+        let mover = position.turn().clone();
+        let mut position = position.clone();
+        let mut rated = Vec::new();
+        for index in moves {
+            rules.apply(&mut position, index);
+            let evaluation = match rules.outcome_after(&position, index) {
+                Some(outcome) => Evaluation::of_outcome(&outcome, rules, &mover),
+                None => self
+                    .analyse_within(&position, rules, cancel, share)
+                    .map_or(Evaluation::Unknown, |(_, evaluation)| evaluation.before()),
+            };
+            rules.undo(&mut position, index);
+            rated.push((index, evaluation));
+        }
+        rated
+    }
+}
+
+// Playouts a move needs before its win rate is reported, unless it is the most visited.
+// Fewer cannot tell rates 0.1 apart, so rating moves on them would make every quiet
+// position look full of mistakes
+const RATED_VISITS: u32 = 100;
+
+// Win rates this close to the most visited move's count as equal for a style
+const STYLE_MARGIN: f64 = 0.02;
+
+// How long a search may run
+#[derive(Clone, Copy, Debug)]
+pub enum Budget {
+    Iterations(u32),
+    Time(Duration),
+}
+
+struct Node {
+    index: Option<usize>,
+    parent: Option<usize>,
+    // The side that played `index`; rewards are from its point of view
+    mover: Player,
+    children: Vec<usize>,
+    untried: Vec<usize>,
+    visits: u32,
+    reward: f64,
+}
+
+// Monte Carlo tree search with UCT selection and uniformly random playouts. Needs no
+// knowledge of the game beyond the rules, so it works on any board size and win length.
+// With more than one thread every thread grows a tree of its own and their roots are pooled
+pub struct Mcts {
+    budget: Budget,
+    exploration: f64,
+    rng: Rng,
+    weights: Option<Arc<Weights>>,
+    threads: usize,
+    style: Style,
+    fixed_work: bool,
+}
+
+impl Mcts {
+    pub fn new(budget: Budget, seed: u64) -> Mcts {
+        Mcts {
+            budget,
+            exploration: std::f64::consts::SQRT_2,
+            rng: Rng::new(seed),
+            weights: None,
+            threads: 1,
+            style: Style::Balanced,
+            fixed_work: false,
+        }
+    }
+
+    // Turns a time budget into the playouts it usually buys, so a single thread grows the
+    // same tree on every run
+    pub fn with_fixed_work(mut self, fixed_work: bool) -> Mcts {
+        self.fixed_work = fixed_work;
+        self
+    }
+
+    // Picks between root moves that did about as well as the best the way `style` likes
+    pub fn with_style(mut self, style: Style) -> Mcts {
+        self.style = style;
+        self
+    }
+
+    // Searches on `threads` threads, each within the whole budget. A single thread keeps
+    // every search reproducible for a seed
+    pub fn with_threads(mut self, threads: usize) -> Mcts {
+        self.threads = threads.max(1);
+        self
+    }
+
+    // With trained pattern weights, new leaves are scored by their value instead of by a
+    // random playout
+    pub fn with_weights(mut self, weights: Option<Weights>) -> Mcts {
+        self.weights = weights.map(Arc::new);
+        self
+    }
+
+    // The tree grown from `position` within the budget; empty when the game is over
+    fn search(&mut self, position: &Position, rules: &dyn Rules, cancel: &Cancel) -> Vec<Node> {
+        if rules.outcome(position).is_some() {
+            return Vec::new();
+        }
+        let mut nodes = vec![Node {
+            index: None,
+            parent: None,
+            mover: position.turn().opponent(),
+            children: Vec::new(),
+            untried: rules.legal_moves(position),
+            visits: 0,
+            reward: 0.0,
+        }];
+
+        let budget = match self.budget {
+            Budget::Time(time) if self.fixed_work => {
+                Budget::Iterations(fixed_work(time, position, PLAYOUTS_PER_CELL_SECOND) as u32)
+            }
+            budget => budget,
+        };
+        let start = Instant::now();
+        let mut iterations = 0;
+        loop {
+            let done = match budget {
+                Budget::Iterations(n) => iterations >= n,
+                Budget::Time(t) => iterations > 0 && start.elapsed() >= t,
+            } || (iterations > 0 && cancel.is_cancelled());
+            if done {
+                break;
+            }
+            self.iterate(&mut nodes, position, rules);
+            iterations += 1;
+        }
+        nodes
+    }
+
+    // Every move tried from the root with its visits and total reward
+    fn root_children(
+        &mut self,
+        position: &Position,
+        rules: &dyn Rules,
+        cancel: &Cancel,
+    ) -> Vec<(usize, u32, f64)> {
+        let nodes = self.search(position, rules, cancel);
+        let Some(root) = nodes.first() else {
+            return Vec::new();
+        };
+        root.children
+            .iter()
+            .map(|child| {
+                let node = &nodes[*child];
+                (node.index.unwrap(), node.visits, node.reward)
+            })
+            .collect()
+    }
+
+    // Every move tried from the root with its visits and win rate, summed over the trees of
+    // all threads. Helper trees are seeded from this search's generator
+    fn root_moves(
+        &mut self,
+        position: &Position,
+        rules: &dyn Rules,
+        cancel: &Cancel,
+    ) -> Vec<(usize, u32, f64)> {
+        let mut helpers: Vec<Mcts> = (1..self.threads)
+            .map(|_| Mcts {
+                weights: self.weights.clone(),
+                exploration: self.exploration,
+                fixed_work: self.fixed_work,
+                ..Mcts::new(self.budget, self.rng.next_u64())
+            })
+            .collect();
+        let roots = thread::scope(|scope| {
+            let running: Vec<_> = helpers
+                .iter_mut()
+                .map(|helper| scope.spawn(move || helper.root_children(position, rules, cancel)))
+                .collect();
+            let mut roots = vec![self.root_children(position, rules, cancel)];
+            roots.extend(running.into_iter().map(|search| search.join().unwrap()));
+            roots
+        });
+
+        let mut pooled: Vec<(usize, u32, f64)> = Vec::new();
+        for (index, visits, reward) in roots.into_iter().flatten() {
+            match pooled.iter_mut().find(|(cell, _, _)| *cell == index) {
+                Some(entry) => {
+                    entry.1 += visits;
+                    entry.2 += reward;
+                }
+                None => pooled.push((index, visits, reward)),
+            }
+        }
+        pooled
+            .into_iter()
+            .map(|(index, visits, reward)| (index, visits, reward / visits.max(1) as f64))
+            .collect()
+    }
+
+    fn best_child(&self, nodes: &[Node], node: usize) -> usize {
+        let parent_visits = (nodes[node].visits as f64).ln();
+        let uct = |child: &Node| {
+            child.reward / child.visits as f64
+                + self.exploration * (parent_visits / child.visits as f64).sqrt()
+        };
+        *nodes[node]
+            .children
+            .iter()
+            .max_by(|a, b| uct(&nodes[**a]).total_cmp(&uct(&nodes[**b])))
+            .unwrap()
+    }
+
+    fn iterate(&mut self, nodes: &mut Vec<Node>, root: &Position, rules: &dyn Rules) {
+        let mut position = root.clone();
+        let mut node = 0;
+        let mut outcome = None;
+
+        while nodes[node].untried.is_empty() && !nodes[node].children.is_empty() {
+            node = self.best_child(nodes, node);
+            let index = nodes[node].index.unwrap();
+            rules.apply(&mut position, index);
+            outcome = rules.outcome_after(&position, index);
+        }
+
+        if outcome.is_none() && !nodes[node].untried.is_empty() {
+            let pick = self.rng.below(nodes[node].untried.len());
+            let index = nodes[node].untried.swap_remove(pick);
+            let mover = position.turn().clone();
+            rules.apply(&mut position, index);
+            outcome = rules.outcome_after(&position, index);
+            let untried = match outcome {
+                Some(_) => Vec::new(),
+                None => rules.legal_moves(&position),
+            };
+            nodes.push(Node {
+                index: Some(index),
+                parent: Some(node),
+                mover,
+                children: Vec::new(),
+                untried,
+                visits: 0,
+                reward: 0.0,
+            });
+            let child = nodes.len() - 1;
+            nodes[node].children.push(child);
+            node = child;
+        }
+
+        // chance that X wins, counting draws as half
+        let weights = self.weights.as_ref().filter(|w| w.covers(&position, rules));
+        let x_reward = match (&outcome, weights) {
+            (None, Some(weights)) => {
+                let value = (weights.value(&position) + 1.0) / 2.0;
+                match position.turn() {
+                    Player::X => value,
+                    _ => 1.0 - value,
+                }
+            }
+            _ => self.playout(&mut position, rules, outcome),
+        };
+        let mut current = Some(node);
+        while let Some(n) = current {
+            let node = &mut nodes[n];
+            node.visits += 1;
+            node.reward += match node.mover {
+                Player::X => x_reward,
+                _ => 1.0 - x_reward,
+            };
+            current = node.parent;
+        }
+    }
+
+    // Plays random moves until the game is over and returns X's reward
+    fn playout(
+        &mut self,
+        position: &mut Position,
+        rules: &dyn Rules,
+        outcome: Option<Outcome>,
+    ) -> f64 {
+        let mut outcome = outcome;
+        while outcome.is_none() {
+            let moves = rules.legal_moves(position);
+            if moves.is_empty() {
+                outcome = Some(Outcome::Draw);
+                break;
+            }
+            let index = moves[self.rng.below(moves.len())];
+            rules.apply(position, index);
+            outcome = rules.outcome_after(position, index);
+        }
+        (rules.score(&outcome.unwrap(), &Player::X) + 1) as f64 / 2.0
+    }
+}
+
+impl Engine for Mcts {
+    fn name(&self) -> String {
+        match self.budget {
+            Budget::Iterations(n) => format!("mcts ({} iterations)", n),
+            Budget::Time(t) => format!("mcts ({} ms)", t.as_millis()),
+        }
+    }
+
+    fn choose_move(
+        &mut self,
+        position: &Position,
+        rules: &dyn Rules,
+        cancel: &Cancel,
+    ) -> Option<usize> {
+        self.analyse(position, rules, cancel)
+            .map(|(index, _)| index)
+    }
+
+    // The most visited move, with its playout win rate. A style may prefer another move that
+    // was visited at least half as often and won about as many playouts
+    fn analyse(
+        &mut self,
+        position: &Position,
+        rules: &dyn Rules,
+        cancel: &Cancel,
+    ) -> Option<(usize, Evaluation)> {
+        let moves = self.root_moves(position, rules, cancel);
+        let (_, most, rate) = *moves.iter().max_by_key(|(_, visits, _)| *visits)?;
+        moves
+            .into_iter()
+            .filter(|(_, visits, r)| visits * 2 >= most && (rate - r).abs() <= STYLE_MARGIN)
+            .max_by_key(|(index, visits, _)| {
+                (self.style.preference(position, rules, *index), *visits)
+            })
+            .map(|(index, _, rate)| (index, Evaluation::Rate(rate)))
+    }
+
+    fn rate_moves(
+        &mut self,
+        position: &Position,
+        rules: &dyn Rules,
+        cancel: &Cancel,
+    ) -> Vec<(usize, Evaluation)> {
+        let moves = self.root_moves(position, rules, cancel);
+        let most = moves
+            .iter()
+            .map(|(_, visits, _)| *visits)
+            .max()
+            .unwrap_or(0);
+        moves
+            .into_iter()
+            .map(|(index, visits, rate)| match visits {
+                visits if visits < RATED_VISITS.min(most) => (index, Evaluation::Unknown),
+                _ => (index, Evaluation::Rate(rate)),
+            })
+            .collect()
+    }
+}
+
+// Cells where `player` completes a line that the opponent has not blocked
+fn completing_cells(position: &Position, player: &Player) -> Vec<usize> {
+    let (own, theirs) = (
+        *position.pieces(player),
+        *position.pieces(&player.opponent()),
+    );
+    let needed = position.win_length() as u32 - 1;
+    let mut cells: Vec<usize> = position
+        .lines()
+        .all()
+        .iter()
+        .filter(|line| (**line & theirs).is_empty() && (**line & own).count() == needed)
+        .filter_map(|line| line.without(&own).iter().next())
+        .collect();
+    cells.sort_unstable();
+    cells.dedup();
+    cells
+}
+
+// Empty cells of the open lines where `player` is `missing` stones short of completing one.
+// With `free_ends` only lines that could also be extended at both ends count, as a three
+// against the edge or an opposing stone can be left until it becomes a four
+fn short_line_cells(
+    position: &Position,
+    player: &Player,
+    missing: u32,
+    through: Option<usize>,
+    free_ends: bool,
+) -> Vec<usize> {
+    let (own, theirs) = (
+        *position.pieces(player),
+        *position.pieces(&player.opponent()),
+    );
+    let needed = (position.win_length() as u32).saturating_sub(missing);
+    let lines = position.lines();
+    let indexes: Vec<usize> = match through {
+        Some(index) => lines.indexes_through(index).to_vec(),
+        None => (0..lines.all().len()).collect(),
+    };
+    let mut cells: Vec<usize> = indexes
+        .into_iter()
+        .filter(|i| {
+            let ends = lines.ends(*i);
+            let free = |cell: usize| !own.get(cell) && !theirs.get(cell);
+            !free_ends || ends.iter().all(|end| end.is_some_and(free))
+        })
+        .map(|i| &lines.all()[i])
+        .filter(|line| (**line & theirs).is_empty() && (**line & own).count() == needed)
+        .flat_map(|line| line.without(&own).iter().collect::<Vec<_>>())
+        .collect();
+    cells.sort_unstable();
+    cells.dedup();
+    cells
+}
+
+// Moves that make a four or a three for the side to move, strongest first
+fn threat_moves(position: &Position) -> Vec<usize> {
+    let player = position.turn();
+    let fours = short_line_cells(position, player, 2, None, false);
+    let mut moves = fours.clone();
+    moves.extend(
+        short_line_cells(position, player, 3, None, true)
+            .into_iter()
+            .filter(|index| !fours.contains(index)),
+    );
+    moves
+}
+
+// Threat-space search for k-in-a-row games. Looks for a win forced by a sequence of fours,
+// which the opponent must block at once, and open threes, which must be answered at one
+// of their cost squares or by a four of the opponent's own. Full-width search cannot see
+// that deep on big boards. When there is no such win, the move comes from `fallback`
+pub struct ThreatSpace {
+    max_depth: u32,
+    time_limit: Duration,
+    fallback: Box<dyn Engine>,
+    nodes: u64,
+    fixed_work: bool,
+    node_limit: u64,
+    line: Vec<usize>,
+}
+
+impl ThreatSpace {
+    pub fn new(max_depth: u32, time_limit: Duration, fallback: Box<dyn Engine>) -> ThreatSpace {
+        ThreatSpace {
+            max_depth,
+            time_limit,
+            fallback,
+            nodes: 0,
+            fixed_work: false,
+            node_limit: u64::MAX,
+            line: Vec::new(),
+        }
+    }
+
+    // Stops the threat search after the nodes its time limit usually buys, as
+    // Minimax::with_fixed_work does; the fallback is set up on its own
+    pub fn with_fixed_work(mut self, fixed_work: bool) -> ThreatSpace {
+        self.fixed_work = fixed_work;
+        self
+    }
+
+    // The shortest forced win for the side to move found within `max_depth` threats: the
+    // moves of both sides in order, ending with the one that completes the line. Against
+    // every defence but the longest-lasting one the win comes sooner
+    pub fn forced_win(
+        &mut self,
+        position: &Position,
+        rules: &dyn Rules,
+        cancel: &Cancel,
+    ) -> Option<Vec<usize>> {
+        if !rules.lines_win() || rules.outcome(position).is_some() {
+            return None;
+        }
+        self.nodes = 0;
+        self.node_limit = match self.fixed_work {
+            true => fixed_work(self.time_limit, position, NODES_PER_CELL_SECOND),
+            false => u64::MAX,
+        };
+        let mut position = position.clone();
+        (0..=self.max_depth).find_map(|depth| self.attack(&mut position, rules, cancel, depth))
+    }
+
+    // A winning line for the side to move using at most `depth` threats before the win
+    fn attack(
+        &mut self,
+        position: &mut Position,
+        rules: &dyn Rules,
+        cancel: &Cancel,
+        depth: u32,
+    ) -> Option<Vec<usize>> {
+        if cancel.is_cancelled() || self.nodes >= self.node_limit {
+            return None;
+        }
+        self.nodes += 1;
+
+        let attacker = position.turn().clone();
+        if let Some(win) = completing_cells(position, &attacker).first() {
+            return Some(vec![*win]);
+        }
+        if depth == 0 {
+            return None;
+        }
+        // a four of the defender has to be blocked, and the block has to be a threat too
+        let blocks = completing_cells(position, &attacker.opponent());
+        let candidates = match blocks.len() {
+            0 => threat_moves(position),
+            1 => blocks,
+            _ => return None,
+        };
+
+        for threat in candidates {
+            rules.apply(position, threat);
+            let gains = completing_cells(position, &attacker);
+            let defences = if gains.is_empty() {
+                let mut defences = short_line_cells(position, &attacker, 2, Some(threat), true);
+                if !defences.is_empty() {
+                    defences.extend(short_line_cells(position, position.turn(), 2, None, false));
+                    defences.sort_unstable();
+                    defences.dedup();
+                }
+                defences
+            } else {
+                gains
+            };
+            let line = if defences.is_empty() {
+                None
+            } else {
+                self.refute(position, rules, cancel, depth, &defences)
+            };
+            rules.undo(position, threat);
+
+            if let Some(mut line) = line {
+                line.insert(0, threat);
+                return Some(line);
+            }
+        }
+        None
+    }
+
+    // The attacker still wins after every one of `defences`; the line against the one that
+    // holds out longest
+    fn refute(
+        &mut self,
+        position: &mut Position,
+        rules: &dyn Rules,
+        cancel: &Cancel,
+        depth: u32,
+        defences: &[usize],
+    ) -> Option<Vec<usize>> {
+        let mut longest: Option<Vec<usize>> = None;
+        for &defence in defences {
+            rules.apply(position, defence);
+            let line = match rules.outcome_after(position, defence) {
+                Some(_) => None,
+                None => self.attack(position, rules, cancel, depth - 1),
+            };
+            rules.undo(position, defence);
+
+            let mut line = line?;
+            line.insert(0, defence);
+            if longest
+                .as_ref()
+                .is_none_or(|longest| line.len() > longest.len())
+            {
+                longest = Some(line);
+            }
+        }
+        longest
+    }
+}
+
+impl Engine for ThreatSpace {
+    fn name(&self) -> String {
+        format!("threat space, then {}", self.fallback.name())
+    }
+
+    fn choose_move(
+        &mut self,
+        position: &Position,
+        rules: &dyn Rules,
+        cancel: &Cancel,
+    ) -> Option<usize> {
+        self.analyse(position, rules, cancel)
+            .map(|(index, _)| index)
+    }
+
+    // A forced win comes with its line, anything else is the fallback's analysis
+    fn analyse(
+        &mut self,
+        position: &Position,
+        rules: &dyn Rules,
+        cancel: &Cancel,
+    ) -> Option<(usize, Evaluation)> {
+        let limit = match self.fixed_work {
+            true => self.time_limit * WORK_BACKSTOP,
+            false => self.time_limit,
+        };
+        self.line = self
+            .forced_win(position, rules, &cancel.limited(limit))
+            .unwrap_or_default();
+        let Some(first) = self.line.first().copied() else {
+            return self.fallback.analyse(position, rules, cancel);
+        };
+        let width = position.width() as usize;
+        let moves: Vec<String> = self
+            .line
+            .iter()
+            .map(|index| format!("({}, {})", index % width, index / width))
+            .collect();
+        let wins_in = self.line.len().div_ceil(2) as u32;
+        log(
+            LogStatus::DEBUG,
+            format!(
+                "threat search found a win in {} moves after {} nodes: {}",
+                wins_in,
+                self.nodes,
+                moves.join(" ")
+            )
+            .as_str(),
+        );
+        Some((first, Evaluation::Win(wins_in)))
+    }
+
+    fn line(&self) -> Vec<usize> {
+        self.line.clone()
+    }
+
+    fn rate_moves(
+        &mut self,
+        position: &Position,
+        rules: &dyn Rules,
+        cancel: &Cancel,
+    ) -> Vec<(usize, Evaluation)> {
+        self.fallback.rate_moves(position, rules, cancel)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::rules::{Misere, Standard};
+
+    fn choose(engine: &mut dyn Engine, rules: &dyn Rules, notation: &str) -> Option<usize> {
+        engine.choose_move(&notation.parse().unwrap(), rules, &Cancel::default())
+    }
+
+    fn play_out(x: &mut dyn Engine, o: &mut dyn Engine, mut position: Position) -> Outcome {
+        loop {
+            if let Some(outcome) = Standard.outcome(&position) {
+                return outcome;
+            }
+            let index = match position.turn() {
+                Player::X => x.choose_move(&position, &Standard, &Cancel::default()),
+                _ => o.choose_move(&position, &Standard, &Cancel::default()),
+            }
+            .unwrap();
+            Standard.apply(&mut position, index);
+        }
+    }
+
+    #[test]
+    fn minimax_wins_and_blocks() {
+        let mut minimax = Minimax::new(9, Duration::from_secs(10));
+        assert_eq!(choose(&mut minimax, &Standard, "xx./oo./... x k3"), Some(2));
+        assert_eq!(choose(&mut minimax, &Standard, "xx./o../... o k3"), Some(2));
+        // under misere rules completing the row loses, so anything else will do
+        assert_ne!(choose(&mut minimax, &Misere, "xx./oo./... x k3"), Some(2));
+    }
+
+    #[test]
+    fn minimax_finds_forced_wins() {
+        let mut minimax = Minimax::new(25, Duration::from_secs(10));
+        // only extending to an open three threatens both ends at once
+        let notation = "o...o/...../.xx../...../o...o x k4";
+        assert_eq!(choose(&mut minimax, &Standard, notation), Some(13));
+
+        // the side to move must block the open three
+        let notation = "o...o/...../.xxx./...../o.... o k4";
+        let block = choose(&mut minimax, &Standard, notation);
+        assert!(block == Some(10) || block == Some(14));
+    }
+
+    #[test]
+    fn minimax_solves_4x4() {
+        let mut minimax = Minimax::new(16, Duration::from_secs(60));
+        assert_eq!(
+            choose(&mut minimax, &Standard, "xxx./oo../o.../.... x k4"),
+            Some(3)
+        );
+        assert_eq!(
+            choose(&mut minimax, &Standard, "xxx./oo../..../.... o k4"),
+            Some(3)
+        );
+    }
+
+    #[test]
+    fn minimax_reports_evaluations() {
+        let mut minimax = Minimax::new(16, Duration::from_secs(10));
+        let mut analyse = |notation: &str| {
+            minimax
+                .analyse(&notation.parse().unwrap(), &Standard, &Cancel::default())
+                .unwrap()
+        };
+        assert_eq!(analyse("xx./oo./... x k3"), (2, Evaluation::Win(1)));
+        assert_eq!(analyse("x../.o./o.x x k3"), (2, Evaluation::Win(2)));
+        assert_eq!(analyse("x../.o./o.x x k3").1.to_string(), "win in 2");
+        // X threatens two lines, O can block only one
+        assert_eq!(analyse("x.x/.o./o.x o k3").1, Evaluation::Loss(1));
+        assert_eq!(analyse(".../.../... x k3").1, Evaluation::Draw);
+
+        let mut quick = Minimax::new(1, Duration::from_secs(10));
+        let (_, evaluation) = quick
+            .analyse(&Position::new(5, 5, 4), &Standard, &Cancel::default())
+            .unwrap();
+        assert!(matches!(evaluation, Evaluation::Score(_)));
+
+        let tablebase = Tablebase::solve(3, 3, 3, &Standard).unwrap();
+        let mut table = Minimax::new(9, Duration::ZERO).with_tablebase(Some(tablebase));
+        let position: Position = "x../.o./o.x x k3".parse().unwrap();
+        assert_eq!(
+            table.analyse(&position, &Standard, &Cancel::default()),
+            Some((2, Evaluation::Win(2)))
+        );
+    }
+
+    #[test]
+    fn engines_rate_every_move() {
+        let position: Position = "xx./oo./... x k3".parse().unwrap();
+        let mut minimax = Minimax::new(9, Duration::from_secs(10));
+        let rated = minimax.rate_moves(&position, &Standard, &Cancel::default());
+        assert_eq!(rated.len(), 5);
+        for (index, evaluation) in rated {
+            let expected = match index {
+                2 => Evaluation::Win(1),
+                5 => Evaluation::Draw,
+                // anything else lets O complete the middle row
+                _ => Evaluation::Loss(1),
+            };
+            assert_eq!(evaluation, expected, "move {}", index);
+        }
+
+        let mut mcts = Mcts::new(Budget::Iterations(2000), 7);
+        let rated = mcts.rate_moves(&position, &Standard, &Cancel::default());
+        assert_eq!(rated.len(), 5);
+        let rate = |cell| match rated.iter().find(|(index, _)| *index == cell) {
+            Some((_, Evaluation::Rate(rate))) => *rate,
+            other => panic!("no rate for {}: {:?}", cell, other),
+        };
+        assert_eq!(rate(2), 1.0);
+        // a losing move is soon left alone, too rarely played out to be rated
+        assert!(rated.contains(&(8, Evaluation::Unknown)));
+    }
+
+    #[test]
+    fn minimax_plays_from_a_solved_table() {
+        let tablebase = Tablebase::solve(3, 3, 3, &Standard).unwrap();
+        // no time to search, so the answer can only come from the table
+        let mut minimax = Minimax::new(9, Duration::ZERO).with_tablebase(Some(tablebase));
+        assert_eq!(choose(&mut minimax, &Standard, "x../.o./o.x x k3"), Some(2));
+    }
+
+    #[test]
+    fn table_scores_are_relative_to_the_position() {
+        assert_eq!(from_table(to_table(WIN - 7, 3), 5), WIN - 9);
+        assert_eq!(from_table(to_table(-(WIN - 7), 3), 5), -(WIN - 9));
+        assert_eq!(from_table(to_table(1234, 3), 5), 1234);
+    }
+
+    #[test]
+    fn minimax_self_play_is_a_draw() {
+        let outcome = play_out(
+            &mut Minimax::new(9, Duration::from_secs(10)),
+            &mut Minimax::new(9, Duration::from_secs(10)),
+            Position::new(3, 3, 3),
+        );
+        assert_eq!(outcome, Outcome::Draw);
+    }
+
+    fn replay(position: &Position, line: &[usize]) -> Option<Outcome> {
+        let mut position = position.clone();
+        for index in line {
+            Standard.apply(&mut position, *index);
+        }
+        Standard.outcome(&position)
+    }
+
+    fn threats(max_depth: u32) -> ThreatSpace {
+        let fallback = Minimax::new(2, Duration::from_secs(10));
+        ThreatSpace::new(max_depth, Duration::from_secs(10), Box::new(fallback))
+    }
+
+    #[test]
+    fn threat_search_converts_an_open_three() {
+        let position: Position =
+            "o.......o/........./........./........./...xxx.../........./........./........./o........ x k5"
+                .parse()
+                .unwrap();
+        let line = threats(4)
+            .forced_win(&position, &Standard, &Cancel::default())
+            .unwrap();
+        // open four, either block, five
+        assert_eq!(line.len(), 3);
+        assert!([38, 42].contains(&line[0]));
+        assert_eq!(replay(&position, &line), Some(Outcome::Win(Player::X)));
+    }
+
+    #[test]
+    fn threat_search_reports_its_line() {
+        let position: Position =
+            "o.......o/........./.....x.../.....x.../...xx..../........./........./........./o.......o x k5"
+                .parse()
+                .unwrap();
+        let mut engine = threats(4);
+        let analysis = engine.analyse(&position, &Standard, &Cancel::default());
+        assert_eq!(analysis, Some((position.index(5, 4), Evaluation::Win(3))));
+        assert_eq!(
+            replay(&position, &engine.line()),
+            Some(Outcome::Win(Player::X))
+        );
+
+        // without a forced win the fallback answers and there is no line
+        let quiet: Position = "x......../........./........./........./........./........./........./........./........o x k5"
+            .parse()
+            .unwrap();
+        let (_, evaluation) = engine
+            .analyse(&quiet, &Standard, &Cancel::default())
+            .unwrap();
+        assert!(!matches!(evaluation, Evaluation::Win(_)));
+        assert!(engine.line().is_empty());
+    }
+
+    #[test]
+    fn threat_search_finds_double_threes() {
+        let position: Position =
+            "o.......o/........./.....x.../.....x.../...xx..../........./........./........./o.......o x k5"
+                .parse()
+                .unwrap();
+        assert_eq!(
+            threats(1).forced_win(&position, &Standard, &Cancel::default()),
+            None
+        );
+        let line = threats(4)
+            .forced_win(&position, &Standard, &Cancel::default())
+            .unwrap();
+        assert_eq!(line[0], position.index(5, 4));
+        assert_eq!(line.len(), 5);
+        assert_eq!(replay(&position, &line), Some(Outcome::Win(Player::X)));
+    }
+
+    #[test]
+    fn threat_search_respects_blocks_and_rules() {
+        // O's four has to be blocked first, and the block makes no threat of X's own
+        let position: Position =
+            "oooo...../........./........./........./..oxxx.../........./........./........x/........x x k5"
+                .parse()
+                .unwrap();
+        let mut engine = threats(4);
+        assert_eq!(
+            engine.forced_win(&position, &Standard, &Cancel::default()),
+            None
+        );
+
+        let open: Position =
+            "o.......o/........./........./........./...xxx.../........./........./........./o........ x k5"
+                .parse()
+                .unwrap();
+        assert_eq!(engine.forced_win(&open, &Misere, &Cancel::default()), None);
+    }
+
+    #[test]
+    fn threat_search_needs_open_threes() {
+        // a three against the edge is no threat: one more stone only makes a simple four
+        let edge: Position =
+            "xxx....../........./........./........./........./........./o......../o......../o........ x k5"
+                .parse()
+                .unwrap();
+        assert!(short_line_cells(&edge, &Player::X, 2, None, true).is_empty());
+        assert_eq!(short_line_cells(&edge, &Player::X, 2, None, false), [3, 4]);
+
+        // X's threes here are closed by the edge or by O, so O need not answer them where
+        // the threes are. A depth 7 search finds no win either
+        let closed: Position =
+            "o......../..xo...../ox.x...../....o..../.x......./........./........./........./......... x k5"
+                .parse()
+                .unwrap();
+        assert_eq!(
+            threats(4).forced_win(&closed, &Standard, &Cancel::default()),
+            None
+        );
+    }
+
+    #[test]
+    fn threat_engine_falls_back_without_a_forced_win() {
+        let mut engine = threats(4);
+        let position = "x../.o./... x k3";
+        assert_eq!(
+            choose(&mut engine, &Standard, position),
+            choose(
+                &mut Minimax::new(2, Duration::from_secs(10)),
+                &Standard,
+                position
+            )
+        );
+    }
+
+    #[test]
+    fn mcts_wins_and_blocks() {
+        let mut mcts = Mcts::new(Budget::Iterations(2000), 1);
+        assert_eq!(choose(&mut mcts, &Standard, "xx./oo./... x k3"), Some(2));
+        assert_eq!(choose(&mut mcts, &Standard, "xx./o../... o k3"), Some(2));
+        assert_eq!(choose(&mut mcts, &Standard, "xxx/oo./... o k3"), None);
+    }
+
+    #[test]
+    fn mcts_scores_leaves_with_weights() {
+        // untrained weights value every unfinished leaf as even, so only finished games
+        // tell moves apart
+        let weights = Weights::new(3, &Standard);
+        let mut mcts = Mcts::new(Budget::Iterations(2000), 1).with_weights(Some(weights));
+        assert_eq!(choose(&mut mcts, &Standard, "xx./oo./... x k3"), Some(2));
+        assert_eq!(choose(&mut mcts, &Standard, "xx./o../... o k3"), Some(2));
+    }
+
+    #[test]
+    fn mcts_is_deterministic_for_a_seed() {
+        let position = Position::new(7, 6, 4);
+        let first = Mcts::new(Budget::Iterations(300), 9).choose_move(
+            &position,
+            &Standard,
+            &Cancel::default(),
+        );
+        let second = Mcts::new(Budget::Iterations(300), 9).choose_move(
+            &position,
+            &Standard,
+            &Cancel::default(),
+        );
+        assert!(first.is_some());
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn fixed_work_searches_the_same_tree_every_time() {
+        let position = Position::new(7, 6, 4);
+        let time = Duration::from_millis(10);
+        let minimax = || {
+            let mut minimax = Minimax::new(u32::MAX, time).with_fixed_work(true);
+            let chosen = minimax.choose_move(&position, &Standard, &Cancel::default());
+            (chosen, minimax.nodes)
+        };
+        let (chosen, nodes) = minimax();
+        assert_eq!(nodes, fixed_work(time, &position, NODES_PER_CELL_SECOND));
+        assert_eq!(minimax(), (chosen, nodes));
+
+        let mcts = || {
+            Mcts::new(Budget::Time(time), 9)
+                .with_fixed_work(true)
+                .root_moves(&position, &Standard, &Cancel::default())
+        };
+        let root = mcts();
+        let playouts = fixed_work(time, &position, PLAYOUTS_PER_CELL_SECOND) as u32;
+        assert_eq!(
+            root.iter().map(|(_, visits, _)| visits).sum::<u32>(),
+            playouts
+        );
+        assert_eq!(mcts(), root);
+    }
+
+    #[test]
+    fn mcts_pools_the_roots_of_every_thread() {
+        let position: Position = "xx./oo./... x k3".parse().unwrap();
+        let mut mcts = Mcts::new(Budget::Iterations(300), 4).with_threads(4);
+        let root = mcts.root_moves(&position, &Standard, &Cancel::default());
+        assert_eq!(root.iter().map(|(_, visits, _)| visits).sum::<u32>(), 1200);
+        assert_eq!(
+            mcts.choose_move(&position, &Standard, &Cancel::default()),
+            Some(2)
+        );
+    }
+
+    #[test]
+    fn parallel_minimax_agrees_with_one_thread() {
+        for notation in ["o...o/...../.xx../...../o...o x k4", "x../.o./... x k3"] {
+            let position: Position = notation.parse().unwrap();
+            let single = Minimax::new(25, Duration::from_secs(10)).analyse(
+                &position,
+                &Standard,
+                &Cancel::default(),
+            );
+            let parallel = Minimax::new(25, Duration::from_secs(10))
+                .with_threads(4)
+                .analyse(&position, &Standard, &Cancel::default());
+            assert_eq!(
+                single.map(|(_, evaluation)| evaluation),
+                parallel.map(|(_, e)| e)
+            );
+            if notation.ends_with("k4") {
+                assert_eq!(parallel.map(|(index, _)| index), Some(13));
+            }
+        }
+    }
+
+    #[test]
+    fn parallel_minimax_never_plays_a_refuted_root_move() {
+        // the centre only draws, but fails low to exactly the winning score when a helper
+        // has already raised the shared alpha
+        let position: Position = ".../..x/o.. x k3".parse().unwrap();
+        let (single, evaluation) = Minimax::new(25, Duration::from_secs(10))
+            .analyse(&position, &Standard, &Cancel::default())
+            .unwrap();
+        assert_eq!(single, 8);
+        for _ in 0..100 {
+            let parallel = Minimax::new(25, Duration::from_secs(10))
+                .with_threads(4)
+                .analyse(&position, &Standard, &Cancel::default());
+            assert_eq!(parallel, Some((single, evaluation)));
+        }
+    }
+
+    #[test]
+    fn trappy_style_counts_losing_replies() {
+        let position: Position = "x../.../o.. x k3".parse().unwrap();
+        // a threat along the top row leaves every reply but the block losing
+        assert_eq!(Style::Trappy.preference(&position, &Standard, 1), 5);
+        assert_eq!(Style::Trappy.preference(&position, &Standard, 5), 0);
+        assert_eq!(Style::Balanced.preference(&position, &Standard, 1), 0);
+        assert_eq!("trappy".parse::<Style>(), Ok(Style::Trappy));
+    }
+
+    #[test]
+    fn styles_choose_among_equal_moves() {
+        let position: Position = "x../.o./... x k3".parse().unwrap();
+        let rated = Minimax::new(9, Duration::from_secs(10)).rate_moves(
+            &position,
+            &Standard,
+            &Cancel::default(),
+        );
+        let drawing: Vec<usize> = rated
+            .iter()
+            .filter(|(_, evaluation)| *evaluation == Evaluation::Draw)
+            .map(|(index, _)| *index)
+            .collect();
+        assert!(drawing.len() > 1);
+        for style in [Style::Aggressive, Style::Defensive, Style::Trappy] {
+            let mut minimax = Minimax::new(9, Duration::from_secs(10)).with_style(style);
+            let chosen = choose(&mut minimax, &Standard, "x../.o./... x k3").unwrap();
+            assert!(drawing.contains(&chosen));
+            let favourite = drawing
+                .iter()
+                .map(|index| style.preference(&position, &Standard, *index))
+                .max();
+            assert_eq!(
+                Some(style.preference(&position, &Standard, chosen)),
+                favourite
+            );
+        }
+    }
+
+    #[test]
+    fn mcts_holds_minimax_to_a_draw() {
+        let outcome = play_out(
+            &mut Minimax::new(9, Duration::from_secs(10)),
+            &mut Mcts::new(Budget::Iterations(3000), 5),
+            Position::new(3, 3, 3),
+        );
+        assert_eq!(outcome, Outcome::Draw);
+    }
+
+    #[test]
+    fn search_runs_in_the_background() {
+        let engine: SharedEngine = Arc::new(Mutex::new(Minimax::new(9, Duration::from_secs(10))));
+        let position: Position = "xx./oo./... x k3".parse().unwrap();
+        let search = Search::start(engine, position, Arc::new(Standard), Duration::from_secs(5));
+        while search.result().is_none() {
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(search.result(), Some(Some(2)));
+    }
+
+    #[test]
+    fn cancelled_search_still_answers() {
+        let engine: SharedEngine = Arc::new(Mutex::new(Mcts::new(Budget::Iterations(u32::MAX), 3)));
+        let position = Position::new(15, 15, 5);
+        let search = Search::start(
+            engine,
+            position,
+            Arc::new(Standard),
+            Duration::from_secs(60),
+        );
+        search.cancel();
+        while search.result().is_none() {
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert!(search.result().unwrap().is_some());
+        assert!(search.elapsed() < Duration::from_secs(60));
+    }
+
+    #[test]
+    fn deadline_stops_minimax() {
+        let cancel = Cancel::with_deadline(Instant::now());
+        let position = Position::new(5, 5, 4);
+        let index =
+            Minimax::new(25, Duration::from_secs(10)).choose_move(&position, &Standard, &cancel);
+        // nothing was searched, so the best-ordered move: the centre
+        assert_eq!(index, Some(12));
+    }
+}
@@ -0,0 +1,611 @@
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::ai::{Budget, Cancel, Engine, Mcts, Minimax, ThreatSpace};
+use crate::game::Player;
+use crate::logutil::{LogStatus, log};
+use crate::piskvork::Piskvork;
+use crate::position::{self, Position};
+use crate::protocol::External;
+use crate::rng::Rng;
+use crate::rules::{Misere, Outcome, Rules, Standard};
+
+#[derive(Clone, PartialEq, Debug)]
+enum EngineKind {
+    Minimax,
+    Mcts,
+    Threats,
+    // the command line of a program speaking the protocol in protocol.rs
+    External(String),
+    // the command line of a Gomocup brain
+    Piskvork(String),
+}
+
+// An engine configuration as written on the command line: `kind` or `kind:<ms per move>`,
+// followed by `=<command>` for external engines
+#[derive(Clone, PartialEq, Debug)]
+pub struct EngineSpec {
+    kind: EngineKind,
+    time: Duration,
+    // Search threads for the built-in engines
+    threads: usize,
+    // Whether the built-in engines search for the work their time buys instead, see
+    // Minimax::with_fixed_work
+    fixed_work: bool,
+}
+
+impl EngineSpec {
+    pub fn build(&self, seed: u64) -> Box<dyn Engine> {
+        let minimax = || {
+            Minimax::new(u32::MAX, self.time)
+                .with_threads(self.threads)
+                .with_fixed_work(self.fixed_work)
+        };
+        match &self.kind {
+            EngineKind::Minimax => Box::new(minimax()),
+            EngineKind::Mcts => Box::new(
+                Mcts::new(Budget::Time(self.time), seed)
+                    .with_threads(self.threads)
+                    .with_fixed_work(self.fixed_work),
+            ),
+            EngineKind::Threats => Box::new(
+                ThreatSpace::new(8, self.time / 3, Box::new(minimax()))
+                    .with_fixed_work(self.fixed_work),
+            ),
+            EngineKind::External(command) => Box::new(External::new(command.clone(), self.time)),
+            EngineKind::Piskvork(command) => Box::new(Piskvork::new(command.clone(), self.time)),
+        }
+    }
+
+    pub fn with_time(self, time: Duration) -> EngineSpec {
+        EngineSpec { time, ..self }
+    }
+
+    pub fn with_threads(self, threads: usize) -> EngineSpec {
+        EngineSpec { threads, ..self }
+    }
+
+    pub fn with_fixed_work(self, fixed_work: bool) -> EngineSpec {
+        EngineSpec { fixed_work, ..self }
+    }
+}
+
+impl FromStr for EngineSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<EngineSpec, String> {
+        let (name, command) = match s.split_once('=') {
+            Some((name, command)) => (name, Some(command)),
+            None => (s, None),
+        };
+        let (kind, time) = name.split_once(':').unwrap_or((name, "100"));
+        let kind = match (kind, command) {
+            ("minimax", None) => EngineKind::Minimax,
+            ("mcts", None) => EngineKind::Mcts,
+            ("threats", None) => EngineKind::Threats,
+            ("external", Some(command)) if !command.trim().is_empty() => {
+                EngineKind::External(command.to_string())
+            }
+            ("piskvork", Some(command)) if !command.trim().is_empty() => {
+                EngineKind::Piskvork(command.to_string())
+            }
+            ("external" | "piskvork", _) => return Err(format!("{:?} needs =<command>", s)),
+            _ => return Err(format!("unknown engine {:?}", kind)),
+        };
+        let time = time
+            .parse()
+            .map_err(|_| format!("invalid time {:?} in {:?}", time, s))?;
+        Ok(EngineSpec {
+            kind,
+            time: Duration::from_millis(time),
+            threads: 1,
+            fixed_work: false,
+        })
+    }
+}
+
+impl fmt::Display for EngineSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match &self.kind {
+            EngineKind::Minimax => "minimax",
+            EngineKind::Mcts => "mcts",
+            EngineKind::Threats => "threats",
+            EngineKind::External(_) => "external",
+            EngineKind::Piskvork(_) => "piskvork",
+        };
+        write!(f, "{}:{}", kind, self.time.as_millis())?;
+        if let EngineKind::External(command) | EngineKind::Piskvork(command) = &self.kind {
+            write!(f, "={}", command)?;
+        }
+        Ok(())
+    }
+}
+
+// Results from one side's point of view
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+pub struct Record {
+    wins: u32,
+    draws: u32,
+    losses: u32,
+}
+
+impl Record {
+    fn add(&mut self, score: i32) {
+        match score {
+            1 => self.wins += 1,
+            -1 => self.losses += 1,
+            _ => self.draws += 1,
+        }
+    }
+
+    fn reversed(self) -> Record {
+        Record {
+            wins: self.losses,
+            draws: self.draws,
+            losses: self.wins,
+        }
+    }
+
+    fn merge(&mut self, other: Record) {
+        self.wins += other.wins;
+        self.draws += other.draws;
+        self.losses += other.losses;
+    }
+
+    fn games(&self) -> u32 {
+        self.wins + self.draws + self.losses
+    }
+
+    // Points per game, draws counting half
+    fn score(&self) -> f64 {
+        (self.wins as f64 + self.draws as f64 / 2.0) / self.games().max(1) as f64
+    }
+
+    pub fn elo(&self) -> f64 {
+        elo(self.score())
+    }
+
+    // Half the width of the 95% confidence interval of `elo`, from the spread of the
+    // individual game scores
+    pub fn elo_error(&self) -> f64 {
+        let (games, score) = (self.games().max(1) as f64, self.score());
+        let variance = (self.wins as f64 * (1.0 - score).powi(2)
+            + self.draws as f64 * (0.5 - score).powi(2)
+            + self.losses as f64 * score.powi(2))
+            / games;
+        let margin = 1.96 * (variance / games).sqrt();
+        let (low, high) = (elo(score - margin), elo(score + margin));
+        (high - low) / 2.0
+    }
+}
+
+// Rating difference implied by an expected score; infinite for clean sweeps
+// Adding zero turns the -0 of an even score into 0
+fn elo(score: f64) -> f64 {
+    -400.0 * (1.0 / score.clamp(0.0, 1.0) - 1.0).log10() + 0.0
+}
+
+pub struct Settings {
+    width: u8,
+    height: u8,
+    win_length: u8,
+    rules: Arc<dyn Rules>,
+    // Per pairing, split evenly between both colours
+    games: u32,
+    // Random plies played before the engines take over
+    opening_plies: u32,
+    seed: u64,
+    gauntlet: bool,
+    csv: String,
+}
+
+pub const USAGE: &str = "usage: arena <engine> <engine>... [--games N] [--gauntlet] \
+    [--board <width>x<height>] [--k <win length>] [--misere] [--openings <plies>] \
+    [--seed N] [--threads N] [--csv <path>]\n  engines: minimax, mcts or threats, optionally :<ms per move>, or \
+    external[:<ms per move>]=<command> for a program speaking the engine protocol, or \
+    piskvork[:<ms per move>]=<command> for a Gomocup brain";
+
+impl Settings {
+    // Engines first, then options; see USAGE
+    pub fn from_args(args: &[String]) -> Result<(Vec<EngineSpec>, Settings), String> {
+        let mut specs = Vec::new();
+        let mut settings = Settings {
+            width: 3,
+            height: 3,
+            win_length: 3,
+            rules: Arc::new(Standard),
+            games: 10,
+            opening_plies: 2,
+            seed: 1,
+            gauntlet: false,
+            csv: "arena.csv".to_string(),
+        };
+        let mut win_length = None;
+        let mut threads = 1;
+        // a seed given on purpose is meant to replay the run, which timed searches cannot
+        let mut fixed_work = false;
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let mut value = |name: &str| {
+                args.next()
+                    .ok_or_else(|| format!("{} needs a value", name))
+                    .cloned()
+            };
+            let number = |name: &str, text: String| {
+                text.parse::<u64>()
+                    .map_err(|_| format!("invalid {} {:?}", name, text))
+            };
+            let small = |name: &str, text: String| {
+                text.parse::<u8>()
+                    .map_err(|_| format!("invalid {} {:?}", name, text))
+            };
+            match arg.as_str() {
+                "--games" => settings.games = number("game count", value(arg)?)? as u32,
+                "--openings" => settings.opening_plies = number("plies", value(arg)?)? as u32,
+                "--seed" => {
+                    settings.seed = number("seed", value(arg)?)?;
+                    fixed_work = true;
+                }
+                "--threads" => threads = number("thread count", value(arg)?)?.max(1) as usize,
+                "--k" => win_length = Some(small("win length", value(arg)?)?),
+                "--csv" => settings.csv = value(arg)?,
+                "--gauntlet" => settings.gauntlet = true,
+                "--misere" => settings.rules = Arc::new(Misere),
+                "--board" => {
+                    let board = value(arg)?;
+                    let (width, height) = board
+                        .split_once('x')
+                        .ok_or_else(|| format!("invalid board {:?}", board))?;
+                    settings.width = small("width", width.to_string())?;
+                    settings.height = small("height", height.to_string())?;
+                }
+                spec => specs.push(spec.parse()?),
+            }
+        }
+        if specs.len() < 2 {
+            return Err("need at least two engines".to_string());
+        }
+        let specs = specs
+            .into_iter()
+            .map(|spec: EngineSpec| spec.with_threads(threads).with_fixed_work(fixed_work))
+            .collect();
+        // the whole side by default, up to gomoku's five
+        settings.win_length = win_length.unwrap_or(settings.width.min(settings.height).min(5));
+        if !position::playable(settings.width, settings.height, settings.win_length) {
+            return Err("board or win length out of range".to_string());
+        }
+        Ok((specs, settings))
+    }
+
+    pub fn csv_path(&self) -> &str {
+        &self.csv
+    }
+
+    // Which engines meet: every pair, or the first engine against each of the others
+    fn schedule(&self, engines: usize) -> Vec<(usize, usize)> {
+        (0..engines)
+            .flat_map(|a| (a + 1..engines).map(move |b| (a, b)))
+            .filter(|(a, _)| !self.gauntlet || *a == 0)
+            .collect()
+    }
+
+    // Random but legal first moves, stopping short of a finished game
+    fn opening(&self, rng: &mut Rng) -> Position {
+        let mut position = Position::new(self.width, self.height, self.win_length);
+        for _ in 0..self.opening_plies {
+            let moves = self.rules.legal_moves(&position);
+            if moves.is_empty() {
+                break;
+            }
+            let index = moves[rng.below(moves.len())];
+            self.rules.apply(&mut position, index);
+            if self.rules.outcome_after(&position, index).is_some() {
+                self.rules.undo(&mut position, index);
+                break;
+            }
+        }
+        position
+    }
+}
+
+pub struct Pairing {
+    first: usize,
+    second: usize,
+    // From the first engine's point of view
+    record: Record,
+}
+
+// Plays a game out. An engine that gives back no move in an unfinished game, as crashed,
+// stuck or unsuitable programs do, forfeits it
+fn play(
+    x: &mut dyn Engine,
+    o: &mut dyn Engine,
+    mut position: Position,
+    rules: &dyn Rules,
+) -> Outcome {
+    loop {
+        if let Some(outcome) = rules.outcome(&position) {
+            return outcome;
+        }
+        let cancel = Cancel::default();
+        let mover: &mut dyn Engine = match position.turn() {
+            Player::X => x,
+            _ => o,
+        };
+        match mover.choose_move(&position, rules, &cancel) {
+            Some(index) => rules.apply(&mut position, index),
+            None => {
+                log(
+                    LogStatus::WARN,
+                    format!("{} gave no move on {}, forfeiting", mover.name(), position).as_str(),
+                );
+                return Outcome::Win(position.turn().opponent());
+            }
+        }
+    }
+}
+
+// Plays every scheduled pairing. Each opening is played twice, once with either engine
+// moving first, so an odd game count leaves one opening played once
+pub fn run(specs: &[EngineSpec], settings: &Settings) -> Vec<Pairing> {
+    settings
+        .schedule(specs.len())
+        .into_iter()
+        .map(|(first, second)| {
+            let mut rng = Rng::new(settings.seed);
+            let mut engines = [
+                specs[first].build(rng.next_u64()),
+                specs[second].build(rng.next_u64()),
+            ];
+            let mut record = Record::default();
+            let mut opening = settings.opening(&mut rng);
+            for game in 0..settings.games {
+                if game % 2 == 0 && game > 0 {
+                    opening = settings.opening(&mut rng);
+                }
+                // `first` plays X in even games
+                let (x, o) = engines.split_at_mut(1);
+                let (x, o, first_player) = if game % 2 == 0 {
+                    (&mut x[0], &mut o[0], Player::X)
+                } else {
+                    (&mut o[0], &mut x[0], Player::O)
+                };
+                let outcome = play(
+                    x.as_mut(),
+                    o.as_mut(),
+                    opening.clone(),
+                    settings.rules.as_ref(),
+                );
+                record.add(settings.rules.score(&outcome, &first_player));
+                log(
+                    LogStatus::INFO,
+                    format!(
+                        "Arena game {} of {} vs {} from {}: {:?}",
+                        game + 1,
+                        specs[first],
+                        specs[second],
+                        opening,
+                        outcome
+                    )
+                    .as_str(),
+                );
+            }
+            Pairing {
+                first,
+                second,
+                record,
+            }
+        })
+        .collect()
+}
+
+fn format_elo(elo: f64, error: f64) -> String {
+    if elo.is_finite() {
+        format!("{:+.0} ± {:.0}", elo, error)
+    } else {
+        format!("{:+}", elo)
+    }
+}
+
+// W/D/L per pairing, then each engine's totals with its Elo against the engines it met
+pub fn report(specs: &[EngineSpec], pairings: &[Pairing]) -> String {
+    let mut lines = vec![format!(
+        "{:<16} {:<16} {:>5} {:>5} {:>5} {:>6} {:>12}",
+        "engine", "opponent", "won", "drawn", "lost", "score", "elo"
+    )];
+    for pairing in pairings {
+        let record = pairing.record;
+        lines.push(format!(
+            "{:<16} {:<16} {:>5} {:>5} {:>5} {:>5.1}% {:>12}",
+            specs[pairing.first].to_string(),
+            specs[pairing.second].to_string(),
+            record.wins,
+            record.draws,
+            record.losses,
+            record.score() * 100.0,
+            format_elo(record.elo(), record.elo_error())
+        ));
+    }
+
+    lines.push(String::new());
+    for (engine, spec) in specs.iter().enumerate() {
+        let mut total = Record::default();
+        for pairing in pairings {
+            if pairing.first == engine {
+                total.merge(pairing.record);
+            } else if pairing.second == engine {
+                total.merge(pairing.record.reversed());
+            }
+        }
+        lines.push(format!(
+            "{:<16} {:<16} {:>5} {:>5} {:>5} {:>5.1}% {:>12}",
+            spec.to_string(),
+            "(overall)",
+            total.wins,
+            total.draws,
+            total.losses,
+            total.score() * 100.0,
+            format_elo(total.elo(), total.elo_error())
+        ));
+    }
+    lines.join("\n")
+}
+
+// One row per pairing, with its seed. With --seed the built-in engines search by work, so on
+// one thread the seed reproduces the pairing; otherwise their searches were timed
+pub fn csv(specs: &[EngineSpec], settings: &Settings, pairings: &[Pairing]) -> String {
+    let mut lines =
+        vec!["engine,opponent,games,wins,draws,losses,score,elo,elo_error,seed".to_string()];
+    for pairing in pairings {
+        let record = pairing.record;
+        lines.push(format!(
+            "{},{},{},{},{},{},{:.4},{:.1},{:.1},{}",
+            specs[pairing.first],
+            specs[pairing.second],
+            record.games(),
+            record.wins,
+            record.draws,
+            record.losses,
+            record.score(),
+            record.elo(),
+            record.elo_error(),
+            settings.seed
+        ));
+    }
+    lines.join("\n") + "\n"
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(String::from).collect()
+    }
+
+    #[test]
+    fn parses_engines_and_options() {
+        let (specs, settings) = Settings::from_args(&args(
+            "minimax mcts:250 --games 4 --gauntlet --board 6x6 --k 4",
+        ))
+        .unwrap();
+        assert_eq!(specs[0].to_string(), "minimax:100");
+        assert_eq!(specs[1].to_string(), "mcts:250");
+        assert_eq!(
+            (settings.width, settings.height, settings.win_length),
+            (6, 6, 4)
+        );
+        assert_eq!(settings.games, 4);
+        assert!(settings.gauntlet);
+
+        assert!(Settings::from_args(&args("minimax")).is_err());
+        assert!(Settings::from_args(&args("minimax alphazero")).is_err());
+        assert!(Settings::from_args(&args("minimax mcts --games")).is_err());
+        assert!(Settings::from_args(&args("minimax mcts --board 20x20")).is_err());
+
+        let external: EngineSpec = "external:50=./engine --fast".parse().unwrap();
+        assert_eq!(external.to_string(), "external:50=./engine --fast");
+        assert!("external:50".parse::<EngineSpec>().is_err());
+        let brain: EngineSpec = "piskvork=pbrain-test".parse().unwrap();
+        assert_eq!(brain.to_string(), "piskvork:100=pbrain-test");
+        assert!("minimax=./engine".parse::<EngineSpec>().is_err());
+
+        let (_, settings) = Settings::from_args(&args("minimax mcts --board 15x15")).unwrap();
+        assert_eq!(settings.win_length, 5);
+
+        assert!(!specs[0].fixed_work);
+        let (specs, _) = Settings::from_args(&args("minimax mcts --seed 3")).unwrap();
+        assert!(specs.iter().all(|spec| spec.fixed_work));
+    }
+
+    #[test]
+    fn openings_are_seeded_and_unfinished() {
+        let (_, settings) = Settings::from_args(&args("minimax mcts --openings 4")).unwrap();
+        let first = settings.opening(&mut Rng::new(9));
+        assert_eq!(first, settings.opening(&mut Rng::new(9)));
+        assert_eq!(first.empty_cells().count(), 5);
+        assert!(settings.rules.outcome(&first).is_none());
+    }
+
+    #[test]
+    fn schedules_round_robin_and_gauntlet() {
+        let (_, mut settings) = Settings::from_args(&args("minimax mcts")).unwrap();
+        assert_eq!(settings.schedule(3), vec![(0, 1), (0, 2), (1, 2)]);
+        settings.gauntlet = true;
+        assert_eq!(settings.schedule(3), vec![(0, 1), (0, 2)]);
+    }
+
+    #[test]
+    fn elo_follows_the_score() {
+        let even = Record {
+            wins: 5,
+            draws: 10,
+            losses: 5,
+        };
+        assert_eq!(even.elo(), 0.0);
+        assert!(even.elo_error() > 0.0);
+
+        let ahead = Record {
+            wins: 15,
+            draws: 0,
+            losses: 5,
+        };
+        assert!((ahead.elo() - 190.8).abs() < 0.1);
+        assert!((ahead.reversed().elo() + 190.8).abs() < 0.1);
+        // more games, tighter bounds
+        let more = Record {
+            wins: 150,
+            draws: 0,
+            losses: 50,
+        };
+        assert!(more.elo_error() < ahead.elo_error());
+
+        let sweep = Record {
+            wins: 4,
+            draws: 0,
+            losses: 0,
+        };
+        assert_eq!(sweep.elo(), f64::INFINITY);
+        assert_eq!(format_elo(sweep.elo(), sweep.elo_error()), "+inf");
+    }
+
+    #[test]
+    fn engines_without_a_move_forfeit() {
+        // a program that is not there never answers
+        let mut missing = External::new("./no-such-engine".to_string(), Duration::from_secs(1));
+        let mut minimax = Minimax::new(u32::MAX, Duration::from_secs(1));
+        let position = Position::new(3, 3, 3);
+        assert_eq!(
+            play(&mut missing, &mut minimax, position.clone(), &Standard),
+            Outcome::Win(Player::O)
+        );
+        assert_eq!(
+            play(&mut minimax, &mut missing, position, &Standard),
+            Outcome::Win(Player::X)
+        );
+    }
+
+    #[test]
+    fn perfect_players_draw_every_opening() {
+        let (specs, settings) =
+            Settings::from_args(&args("minimax:1000 threats:1000 --games 4 --openings 0")).unwrap();
+        let pairings = run(&specs, &settings);
+        assert_eq!(pairings.len(), 1);
+        assert_eq!(
+            pairings[0].record,
+            Record {
+                wins: 0,
+                draws: 4,
+                losses: 0
+            }
+        );
+        let csv = csv(&specs, &settings, &pairings);
+        assert_eq!(
+            csv.lines().nth(1),
+            Some("minimax:1000,threats:1000,4,0,4,0,0.5000,0.0,0.0,1")
+        );
+        assert!(report(&specs, &pairings).contains("(overall)"));
+    }
+}
@@ -0,0 +1,212 @@
+use std::collections::HashMap;
+use std::ops::{BitAnd, BitOr};
+use std::sync::{Arc, Mutex, OnceLock};
+
+use crate::position::MAX_DIMENSION;
+
+const WORDS: usize = (MAX_DIMENSION as usize * MAX_DIMENSION as usize).div_ceil(64);
+
+// One bit per cell, bit index = y * width + x
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Default)]
+pub struct Bitboard([u64; WORDS]);
+
+impl Bitboard {
+    // The first `cells` bits set
+    pub fn full(cells: usize) -> Bitboard {
+        let mut board = Bitboard::default();
+        for index in 0..cells {
+            board.set(index);
+        }
+        board
+    }
+
+    pub fn get(&self, index: usize) -> bool {
+        self.0[index / 64] & (1 << (index % 64)) != 0
+    }
+
+    pub fn set(&mut self, index: usize) {
+        self.0[index / 64] |= 1 << (index % 64);
+    }
+
+    pub fn clear(&mut self, index: usize) {
+        self.0[index / 64] &= !(1 << (index % 64));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.iter().all(|word| *word == 0)
+    }
+
+    pub fn count(&self) -> u32 {
+        self.0.iter().map(|word| word.count_ones()).sum()
+    }
+
+    // Every bit of `other` is also set here
+    pub fn contains(&self, other: &Bitboard) -> bool {
+        self.0.iter().zip(other.0.iter()).all(|(a, b)| a & b == *b)
+    }
+
+    // Bits set here but not in `other`
+    pub fn without(&self, other: &Bitboard) -> Bitboard {
+        let mut words = self.0;
+        for (word, remove) in words.iter_mut().zip(other.0.iter()) {
+            *word &= !remove;
+        }
+        Bitboard(words)
+    }
+
+    // Indices of the set bits, lowest first
+    pub fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        self.0.iter().enumerate().flat_map(|(w, word)| {
+            let mut bits = *word;
+            std::iter::from_fn(move || {
+                if bits == 0 {
+                    return None;
+                }
+                let bit = bits.trailing_zeros() as usize;
+                bits &= bits - 1;
+                Some(w * 64 + bit)
+            })
+        })
+    }
+}
+
+impl BitAnd for Bitboard {
+    type Output = Bitboard;
+
+    fn bitand(self, rhs: Bitboard) -> Bitboard {
+        let mut words = self.0;
+        for (word, other) in words.iter_mut().zip(rhs.0.iter()) {
+            *word &= other;
+        }
+        Bitboard(words)
+    }
+}
+
+impl BitOr for Bitboard {
+    type Output = Bitboard;
+
+    fn bitor(self, rhs: Bitboard) -> Bitboard {
+        let mut words = self.0;
+        for (word, other) in words.iter_mut().zip(rhs.0.iter()) {
+            *word |= other;
+        }
+        Bitboard(words)
+    }
+}
+
+// Every winning line of a board geometry, plus which lines pass through each cell and the
+// cells just beyond either end of each line
+#[derive(Debug)]
+pub struct Lines {
+    masks: Vec<Bitboard>,
+    through: Vec<Vec<usize>>,
+    ends: Vec<[Option<usize>; 2]>,
+}
+
+impl Lines {
+    fn new(width: u8, height: u8, win_length: u8) -> Lines {
+        let (width, height, k) = (width as i32, height as i32, win_length as i32);
+        let mut masks = Vec::new();
+        let mut through = vec![Vec::new(); (width * height) as usize];
+        let mut ends = Vec::new();
+        let cell = |x: i32, y: i32| {
+            (x >= 0 && x < width && y >= 0 && y < height).then_some((y * width + x) as usize)
+        };
+
+        for y in 0..height {
+            for x in 0..width {
+                for (dx, dy) in [(1, 0), (0, 1), (1, 1), (1, -1)] {
+                    let (end_x, end_y) = (x + dx * (k - 1), y + dy * (k - 1));
+                    if end_x >= width || end_y < 0 || end_y >= height {
+                        continue;
+                    }
+                    let mut mask = Bitboard::default();
+                    for step in 0..k {
+                        let cell = ((y + dy * step) * width + x + dx * step) as usize;
+                        mask.set(cell);
+                        through[cell].push(masks.len());
+                    }
+                    masks.push(mask);
+                    ends.push([cell(x - dx, y - dy), cell(end_x + dx, end_y + dy)]);
+                }
+            }
+        }
+
+        Lines {
+            masks,
+            through,
+            ends,
+        }
+    }
+
+    pub fn all(&self) -> &[Bitboard] {
+        &self.masks
+    }
+
+    pub fn through(&self, index: usize) -> impl Iterator<Item = &Bitboard> {
+        self.through[index].iter().map(|line| &self.masks[*line])
+    }
+
+    // The same lines as `through`, as indexes into `all`
+    pub fn indexes_through(&self, index: usize) -> &[usize] {
+        &self.through[index]
+    }
+
+    // The cells that would extend line `line` of `all` at either end, where the board has them
+    pub fn ends(&self, line: usize) -> [Option<usize>; 2] {
+        self.ends[line]
+    }
+}
+
+type LineCache = Mutex<HashMap<(u8, u8, u8), Arc<Lines>>>;
+
+// Line masks are shared between every position with the same geometry
+pub fn lines(width: u8, height: u8, win_length: u8) -> Arc<Lines> {
+    static CACHE: OnceLock<LineCache> = OnceLock::new();
+    CACHE
+        .get_or_init(Default::default)
+        .lock()
+        .unwrap()
+        .entry((width, height, win_length))
+        .or_insert_with(|| Arc::new(Lines::new(width, height, win_length)))
+        .clone()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn set_clear_iter() {
+        let mut board = Bitboard::default();
+        for index in [0, 63, 64, 200, 255] {
+            board.set(index);
+        }
+        assert_eq!(board.count(), 5);
+        assert!(board.get(200));
+        assert!(!board.get(201));
+        board.clear(200);
+        assert!(!board.get(200));
+        assert_eq!(board.iter().collect::<Vec<_>>(), vec![0, 63, 64, 255]);
+        assert_eq!(Bitboard::full(70).without(&board).count(), 67);
+    }
+
+    #[test]
+    fn line_counts() {
+        assert_eq!(lines(3, 3, 3).all().len(), 8);
+        // 8 rows and columns of 3, 4 diagonals each way
+        assert_eq!(lines(4, 4, 3).all().len(), 24);
+        assert_eq!(lines(15, 15, 5).all().len(), 2 * 15 * 11 + 2 * 11 * 11);
+        assert_eq!(lines(5, 2, 3).all().len(), 2 * 3);
+
+        let center = lines(3, 3, 3);
+        assert_eq!(center.through(4).count(), 4);
+        assert_eq!(center.through(1).count(), 2);
+
+        // the first line of a 4x1 board with lines of 2 is cells 0 and 1
+        let row = lines(4, 1, 2);
+        assert_eq!(row.ends(0), [None, Some(2)]);
+        assert_eq!(row.ends(1), [Some(0), Some(3)]);
+        assert!((0..center.all().len()).all(|i| center.ends(i) == [None, None]));
+    }
+}
@@ -1,94 +1,574 @@
+use crate::agent::{SharedAgent, THINK_TIME};
+use crate::ai::{Budget, Evaluation, Mcts, Minimax, Search, SharedEngine, ThreatSpace};
 use crate::logutil::LogStatus;
-use crossterm::style::{StyledContent, Stylize};
+use crate::patterns;
+use crate::position::Position;
+use crate::rng::Rng;
+use crate::rules::{self, Outcome, Rules};
+use crate::symmetry;
+use crate::tablebase;
+use crossterm::style::{Color, StyledContent, Stylize};
 use crossterm::{ExecutableCommand, cursor, terminal};
+use std::fmt;
 use std::io::Stdout;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use crate::logutil::log;
 use crate::{ApplicationState, ResultMenu};
 // (x, y) = (ROW, COL)
 
+// Boards up to this many cells are analysed by minimax, bigger ones by a threat search and
+// then playouts
+const SEARCHABLE_CELLS: usize = 16;
+const ADVISOR_SEED: u64 = 0x41d5_0e25;
+
+type Heatmap = Vec<(usize, Evaluation)>;
+
+// Background colour for a cell in the heatmap overlay: green wins, yellow draws, red
+// losses, and a red to green gradient for playout win rates and unproven scores
+fn heat_colour(evaluation: &Evaluation) -> Option<Color> {
+    let gradient = |rate: f64| {
+        let rate = rate.clamp(0.0, 1.0);
+        Color::Rgb {
+            r: (510.0 * (1.0 - rate)).min(255.0) as u8,
+            g: (510.0 * rate).min(255.0) as u8,
+            b: 0,
+        }
+    };
+    match evaluation {
+        Evaluation::Win(_) => Some(Color::Green),
+        Evaluation::Draw => Some(Color::Yellow),
+        Evaluation::Loss(_) => Some(Color::Red),
+        Evaluation::Rate(rate) => Some(gradient(*rate)),
+        Evaluation::Score(score) => Some(gradient(
+            0.5 + *score as f64 / (2 * rules::EVAL_LIMIT) as f64,
+        )),
+        Evaluation::Unknown => None,
+    }
+}
+
+// Delays between moves, offered by the setup menu and stepped through by spectators with +
+// and -; they only apply when no human is playing
+pub const MOVE_DELAYS: [Duration; 6] = [
+    Duration::ZERO,
+    Duration::from_millis(100),
+    Duration::from_millis(250),
+    Duration::from_millis(500),
+    Duration::from_secs(1),
+    Duration::from_secs(2),
+];
+
+// Draws the board centred on the screen, with the background `colour` picks for each cell
+pub fn draw_board(
+    stdout: &mut Stdout,
+    position: &Position,
+    colour: impl Fn(usize) -> Option<Color>,
+) {
+    let (w, h) = terminal::size().expect("Failed to get terminal size");
+    let cells = position.cells();
+    let rows = cells.chunks(position.width() as usize);
+    for (row_index, row) in (0_u8..).zip(rows) {
+        let content = row
+            .iter()
+            .enumerate()
+            .map(|(col, player)| {
+                let s = match player {
+                    Player::X => "X".stylize(),
+                    Player::O => "O".stylize(),
+                    Player::None => " ".stylize(),
+                };
+                match colour(position.index(col as u8, row_index)) {
+                    Some(colour) => s.on(colour).black(),
+                    None => s,
+                }
+            })
+            .collect::<Vec<StyledContent<&str>>>();
+        stdout
+            .execute(cursor::MoveTo(
+                (w / 2).saturating_sub(content.len() as u16),
+                (h / 2 + row_index as u16).saturating_sub((position.height() / 2) as u16),
+            ))
+            .ok();
+
+        for cell in content {
+            print!("[{}]", cell);
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct Game {
-    board: Box<[Player]>,
-    size: u8,
-    turn: Player,
+    position: Position,
+    rules: Arc<dyn Rules>,
+    history: Vec<usize>,
     cursor: (u8, u8),
-    confirm: bool,
-    is_ai: bool,
-    can_player_select: bool,
+    // X first, then O
+    players: [SharedAgent; 2],
+    // Whether the side to move has been asked for its move yet
+    asked: bool,
+    // Pacing for games without a human at the board, so they can be watched
+    delay: Duration,
+    paused: bool,
+    step: bool,
+    last_move: Instant,
+    // Answers hints and fills the heatmap. Made when first needed and kept, so later
+    // requests reuse what it has searched
+    advisor: Option<SharedEngine>,
+    hint_search: Option<Search>,
+    hint: Option<(usize, Evaluation)>,
+    // The moves the advisor expects to follow the hint, when it says
+    hint_line: Vec<usize>,
+    hints: u32,
+    overlay: bool,
+    heatmap: Option<Heatmap>,
+    heatmap_search: Option<Search<Heatmap>>,
 }
 
 impl Game {
-    pub fn new(size: u8, is_ai: bool) -> Game {
-        let mut board = vec![Player::None; size.pow(2) as usize].into_boxed_slice();
-        board[0] = Player::None;
+    pub fn new(size: u8, rules: Arc<dyn Rules>, players: [SharedAgent; 2]) -> Game {
+        Game::from_position(Position::new(size, size, size), rules, players)
+    }
+
+    pub fn from_position(
+        position: Position,
+        rules: Arc<dyn Rules>,
+        players: [SharedAgent; 2],
+    ) -> Game {
+        log(
+            LogStatus::INFO,
+            format!(
+                "New {} game from {}, {} against {}",
+                rules.name(),
+                position,
+                players[0].lock().unwrap().name(),
+                players[1].lock().unwrap().name()
+            )
+            .as_str(),
+        );
         Game {
-            board,
-            size,
-            turn: Player::X,
+            position,
+            rules,
+            history: Vec::new(),
             cursor: (0, 0),
-            confirm: false,
-            is_ai,
-            can_player_select: true,
+            players,
+            asked: false,
+            delay: Duration::ZERO,
+            paused: false,
+            step: false,
+            last_move: Instant::now(),
+            advisor: None,
+            hint_search: None,
+            hint: None,
+            hint_line: Vec::new(),
+            hints: 0,
+            overlay: false,
+            heatmap: None,
+            heatmap_search: None,
+        }
+    }
+
+    // Waits at least `delay` between moves when no human is playing
+    pub fn with_move_delay(mut self, delay: Duration) -> Game {
+        self.delay = delay;
+        self
+    }
+
+    fn is_spectated(&self) -> bool {
+        !self
+            .players
+            .iter()
+            .any(|agent| agent.lock().unwrap().is_human())
+    }
+
+    // Whether the next move may be asked for yet, using up a requested step
+    fn may_move(&mut self) -> bool {
+        if !self.is_spectated() {
+            return true;
+        }
+        if self.paused {
+            return std::mem::take(&mut self.step);
+        }
+        self.last_move.elapsed() >= self.delay
+    }
+
+    // The agent for the side to move
+    fn current(&self) -> SharedAgent {
+        match self.position.turn() {
+            Player::O => self.players[1].clone(),
+            _ => self.players[0].clone(),
         }
     }
 
     pub fn draw(&self, stdout: &mut Stdout) {
         let (w, h) = terminal::size().expect("Failed to get terminal size");
-        let mut row_index: u8 = 0;
-        for row in self.board.chunks(self.size as usize) {
-            let content = row
-                .iter()
-                .enumerate()
-                .map(|(col, player)| {
-                    let s = match player {
-                        Player::X => "X".stylize(),
-                        Player::O => "O".stylize(),
-                        Player::None => " ".stylize(),
-                    };
-                    if self.cursor == (col as u8, (row_index) as u8) {
-                        s.on_white().black()
-                    } else {
-                        s
-                    }
-                })
-                .collect::<Vec<StyledContent<&str>>>();
+        draw_board(stdout, &self.position, |index| {
+            let width = self.position.width() as usize;
+            let cell = ((index % width) as u8, (index / width) as u8);
+            if self.cursor == cell {
+                Some(Color::White)
+            } else if self.hint.is_some_and(|(hint, _)| hint == index) {
+                Some(Color::Green)
+            } else {
+                self.heat(index)
+            }
+        });
+
+        // Below the board, one row each: who is to move, the hint, then spectator controls
+        let agent = self.current();
+        let agent = agent.lock().unwrap();
+        let status = match (agent.failure(), agent.thinking_for()) {
+            (Some(failure), _) => Some(format!(
+                "{} gave up: {} (Esc to leave)",
+                agent.name(),
+                failure
+            )),
+            (None, Some(elapsed)) => {
+                let spinner = ['|', '/', '-', '\\'][(elapsed.as_millis() / 100 % 4) as usize];
+                Some(format!(
+                    "{} is thinking {} {:.1}s (Esc to abort)",
+                    agent.name(),
+                    spinner,
+                    elapsed.as_secs_f32()
+                ))
+            }
+            (None, None) => None,
+        };
+        if let Some(status) = status {
             stdout
                 .execute(cursor::MoveTo(
-                    w / 2 - content.len() as u16,
-                    h / 2 + row_index as u16 - (self.size / 2) as u16,
+                    (w / 2).saturating_sub(status.len() as u16 / 2),
+                    (h / 2 + self.position.height() as u16 / 2 + 2).min(h.saturating_sub(1)),
                 ))
                 .ok();
+            print!("{}", status);
+        }
 
-            for cell in content {
-                print!("[{}]", cell);
+        let hint = match (&self.hint_search, &self.hint) {
+            (Some(_), _) => Some("Looking for a hint...".to_string()),
+            (None, None) if self.heatmap_search.is_some() => {
+                Some("Rating every move...".to_string())
             }
-            row_index += 1;
+            (None, Some((index, evaluation))) => {
+                let width = self.position.width() as usize;
+                let then: Vec<String> = self
+                    .hint_line
+                    .iter()
+                    .skip(1)
+                    .map(|index| format!("({}, {})", index % width, index / width))
+                    .collect();
+                let mut hint = format!(
+                    "Hint: ({}, {}), {}",
+                    index % width,
+                    index / width,
+                    evaluation
+                );
+                if !then.is_empty() {
+                    hint = format!("{}, then {}", hint, then.join(" "));
+                }
+                Some(hint)
+            }
+            (None, None) => None,
+        };
+        if let Some(hint) = hint {
+            stdout
+                .execute(cursor::MoveTo(
+                    (w / 2).saturating_sub(hint.len() as u16 / 2),
+                    (h / 2 + self.position.height() as u16 / 2 + 3).min(h.saturating_sub(1)),
+                ))
+                .ok();
+            print!("{}", hint);
+        }
+
+        if self.is_spectated() {
+            let controls = if self.paused {
+                "Paused (n to step, space to resume)".to_string()
+            } else {
+                format!(
+                    "Move delay {} ms (+/- to change, space to pause)",
+                    self.delay.as_millis()
+                )
+            };
+            stdout
+                .execute(cursor::MoveTo(
+                    (w / 2).saturating_sub(controls.len() as u16 / 2),
+                    (h / 2 + self.position.height() as u16 / 2 + 4).min(h.saturating_sub(1)),
+                ))
+                .ok();
+            print!("{}", controls);
+        }
+    }
+
+    // Someone other than the keyboard is choosing the next move
+    pub fn is_thinking(&self) -> bool {
+        self.rules.outcome(&self.position).is_none() && !self.current().lock().unwrap().is_human()
+    }
+
+    // Something is running in the background that the screen should follow
+    pub fn is_busy(&self) -> bool {
+        self.is_thinking() || self.hint_search.is_some() || self.heatmap_search.is_some()
+    }
+
+    fn heat(&self, index: usize) -> Option<Color> {
+        let heatmap = self.heatmap.as_ref().filter(|_| self.overlay)?;
+        let (_, evaluation) = heatmap.iter().find(|(cell, _)| *cell == index)?;
+        heat_colour(evaluation)
+    }
+
+    pub fn advisor(&mut self) -> SharedEngine {
+        let position = &self.position;
+        let rules = self.rules.clone();
+        self.advisor
+            .get_or_insert_with(|| {
+                let (width, height) = (position.width(), position.height());
+                let engine: SharedEngine = if width as usize * height as usize <= SEARCHABLE_CELLS {
+                    let tablebase =
+                        tablebase::find(width, height, position.win_length(), rules.as_ref());
+                    Arc::new(Mutex::new(
+                        Minimax::new(u32::MAX, THINK_TIME).with_tablebase(tablebase),
+                    ))
+                } else {
+                    let weights = patterns::find(position.win_length(), rules.as_ref());
+                    let mcts =
+                        Mcts::new(Budget::Time(THINK_TIME), ADVISOR_SEED).with_weights(weights);
+                    Arc::new(Mutex::new(ThreatSpace::new(
+                        8,
+                        THINK_TIME / 3,
+                        Box::new(mcts),
+                    )))
+                };
+                engine
+            })
+            .clone()
+    }
+
+    // Shows or hides the heatmap of how every move looks for the human to move
+    #[allow(non_snake_case)]
+    pub fn handleOverlay(&mut self) {
+        self.overlay = !self.overlay;
+        if !self.overlay
+            && let Some(search) = self.heatmap_search.take()
+        {
+            search.cancel();
+        }
+        self.refresh_heatmap();
+    }
+
+    // Starts rating the moves of a human to move if the overlay is on and has nothing yet
+    fn refresh_heatmap(&mut self) {
+        if !self.overlay
+            || self.heatmap.is_some()
+            || self.heatmap_search.is_some()
+            || self.rules.outcome(&self.position).is_some()
+            || !self.current().lock().unwrap().is_human()
+        {
+            return;
+        }
+        let engine = self.advisor();
+        let (position, rules) = (self.position.clone(), self.rules.clone());
+        self.heatmap_search = Some(Search::spawn(THINK_TIME * 2, move |stop| {
+            engine
+                .lock()
+                .unwrap()
+                .rate_moves(&position, rules.as_ref(), stop)
+        }));
+    }
+
+    pub fn hints(&self) -> u32 {
+        self.hints
+    }
+
+    // What the players have to say about themselves, X first
+    pub fn statuses(&self) -> Vec<String> {
+        self.players
+            .iter()
+            .filter_map(|agent| agent.lock().unwrap().status())
+            .collect()
+    }
+
+    // Asks an engine for the best move of the human to move, shown once it answers
+    #[allow(non_snake_case)]
+    pub fn handleHint(&mut self) {
+        if self.hint_search.is_some()
+            || self.hint.is_some()
+            || self.rules.outcome(&self.position).is_some()
+            || !self.current().lock().unwrap().is_human()
+        {
+            return;
+        }
+        self.hint_search = Some(Search::start(
+            self.advisor(),
+            self.position.clone(),
+            self.rules.clone(),
+            THINK_TIME,
+        ));
+        log(
+            LogStatus::INFO,
+            format!("Hint requested on {}", self.position).as_str(),
+        );
+    }
+
+    // Forgets the hint and heatmap, which only hold for the position they were made for
+    fn clear_hint(&mut self) {
+        if let Some(search) = self.hint_search.take() {
+            search.cancel();
+        }
+        if let Some(search) = self.heatmap_search.take() {
+            search.cancel();
+        }
+        self.hint = None;
+        self.hint_line.clear();
+        self.heatmap = None;
+    }
+
+    // Stops whoever is choosing a move; their answer is thrown away
+    pub fn abort(&mut self) {
+        self.clear_hint();
+        if self.asked {
+            log(LogStatus::INFO, "Move search aborted");
+            self.current().lock().unwrap().cancel();
+            self.asked = false;
+        }
+    }
+
+    fn ask(&mut self) {
+        if self.asked {
+            return;
+        }
+        let agent = self.current();
+        let mut agent = agent.lock().unwrap();
+        if !agent.is_human() {
+            log(
+                LogStatus::DEBUG,
+                format!("{} thinking on {}", agent.name(), self.position).as_str(),
+            );
         }
+        agent.start(&self.position, self.rules.clone());
+        self.asked = true;
     }
 
+    // Plays whatever moves the agents have come up with, until one of them is still busy
+    // or the game is over. Watched games stop after every move so each one gets drawn
     pub fn update(mut self) -> ApplicationState {
-        // FUCK
-        if !self.confirm {
-            return ApplicationState::Game(self);
+        if let Some(analysis) = self.hint_search.as_ref().and_then(Search::analysis) {
+            log(LogStatus::DEBUG, format!("Hint: {:?}", analysis).as_str());
+            self.hint_search = None;
+            self.hint = analysis;
+            self.hint_line = self.advisor().lock().unwrap().line();
+            // only hints that were shown count, not ones dropped by a move or Esc
+            if analysis.is_some() {
+                self.hints += 1;
+            }
         }
-        self.confirm = false;
+        if let Some(heatmap) = self.heatmap_search.as_ref().and_then(Search::finished) {
+            self.heatmap = Some(heatmap.clone());
+            self.heatmap_search = None;
+        }
+        while self.rules.outcome(&self.position).is_none() {
+            if !self.asked && !self.may_move() {
+                return ApplicationState::Game(self);
+            }
+            self.ask();
+            let agent = self.current();
+            let Some(index) = agent.lock().unwrap().poll() else {
+                self.refresh_heatmap();
+                return ApplicationState::Game(self);
+            };
+            self.asked = false;
+            if !self.rules.legal_moves(&self.position).contains(&index) {
+                log(
+                    LogStatus::WARN,
+                    format!(
+                        "{} chose illegal move {}",
+                        agent.lock().unwrap().name(),
+                        index
+                    )
+                    .as_str(),
+                );
+                continue;
+            }
+            self.play(index);
+            self.last_move = Instant::now();
+            if self.is_spectated() {
+                break;
+            }
+        }
+        self.finish()
+    }
 
-        let winner = self.try_get_winner();
+    fn finish(mut self) -> ApplicationState {
+        self.refresh_heatmap();
+        let outcome = self.rules.outcome(&self.position);
+        if let Some(outcome) = &outcome {
+            log(
+                LogStatus::INFO,
+                format!(
+                    "Game over: {:?}, score X {} O {}",
+                    outcome,
+                    self.rules.score(outcome, &Player::X),
+                    self.rules.score(outcome, &Player::O)
+                )
+                .as_str(),
+            );
+            for agent in &self.players {
+                agent
+                    .lock()
+                    .unwrap()
+                    .game_over(&self.position, self.rules.as_ref());
+            }
+        }
+        match outcome {
+            Some(Outcome::Win(winner)) => ApplicationState::Result(ResultMenu::new(winner, self)),
+            Some(Outcome::Draw) => ApplicationState::Result(ResultMenu::new(Player::None, self)),
+            None => ApplicationState::Game(self),
+        }
+    }
 
-        if winner.is_some() {
-            ApplicationState::Result(ResultMenu::new(winner.unwrap()))
-        } else if self.board.iter().all(|p| p.ne(&Player::None)) {
-            ApplicationState::Result(ResultMenu::new(Player::None))
-        } else {
-            ApplicationState::Game(self)
+    // A fresh game with the same board, rules and players, who swap sides so that the one
+    // who moved second starts this time
+    pub fn restart(&self) -> Game {
+        let position = Position::new(
+            self.position.width(),
+            self.position.height(),
+            self.position.win_length(),
+        );
+        let [x, o] = self.players.clone();
+        Game::from_position(position, self.rules.clone(), [o, x]).with_move_delay(self.delay)
+    }
+
+    #[allow(non_snake_case)]
+    pub fn handlePause(&mut self) {
+        if self.is_spectated() {
+            self.paused = !self.paused;
+            self.step = false;
         }
     }
 
+    // Lets a paused game play one more move
+    #[allow(non_snake_case)]
+    pub fn handleStep(&mut self) {
+        self.step = self.paused;
+    }
+
+    // Moves one notch along MOVE_DELAYS, towards shorter delays when `faster`
+    #[allow(non_snake_case)]
+    pub fn handleSpeed(&mut self, faster: bool) {
+        let notch = MOVE_DELAYS
+            .iter()
+            .position(|delay| *delay >= self.delay)
+            .unwrap_or(MOVE_DELAYS.len() - 1);
+        let notch = if faster {
+            notch.saturating_sub(1)
+        } else {
+            (notch + 1).min(MOVE_DELAYS.len() - 1)
+        };
+        self.delay = MOVE_DELAYS[notch];
+    }
+
     fn select_cell(&mut self, pos: (u8, u8)) {
         let (x2, y2) = pos;
 
-        if (y2 * self.size + x2) >= self.size.pow(2) {
+        if x2 >= self.position.width() || y2 >= self.position.height() {
             return;
         }
 
@@ -107,7 +587,7 @@ impl Game {
     #[allow(non_snake_case)]
     pub fn handleButtonRight(&mut self) {
         log(LogStatus::DEBUG, "Right button handled in game screen");
-        if self.cursor.0 == self.size - 1 {
+        if self.cursor.0 == self.position.width() - 1 {
             return;
         }
         self.select_cell((self.cursor.0 + 1, self.cursor.1));
@@ -116,7 +596,7 @@ impl Game {
     #[allow(non_snake_case)]
     pub fn handleButtonDown(&mut self) {
         log(LogStatus::DEBUG, "Down button handled in game screen");
-        if self.cursor.1 == self.size - 1 {
+        if self.cursor.1 == self.position.height() - 1 {
             return;
         }
         self.select_cell((self.cursor.0, self.cursor.1 + 1));
@@ -131,116 +611,114 @@ impl Game {
         self.select_cell((self.cursor.0, self.cursor.1 - 1));
     }
 
+    // Hands the cell under the cursor to the side to move, if that is a human
     #[allow(non_snake_case)]
     pub fn handleConfirm(&mut self) {
         log(LogStatus::DEBUG, "Confirmation handled in game screen");
+        if !self.current().lock().unwrap().is_human() {
+            return;
+        }
         let (x, y) = self.cursor;
-        match self.board[(y * self.size + x) as usize] {
-            Player::None => {
-                self.board[(y * self.size + x) as usize] = self.turn.clone();
-                self.turn = match self.turn {
-                    Player::X => Player::O,
-                    Player::O => Player::X,
-                    _ => unreachable!(),
-                };
-                self.confirm = true;
-                log(
-                    LogStatus::DEBUG,
-                    format!("Current board: {:?}", self.board).as_str(),
-                );
-            }
-            _ => return,
+        let index = self.position.index(x, y);
+        if !self.rules.legal_moves(&self.position).contains(&index) {
+            return;
         }
+        self.ask();
+        self.current().lock().unwrap().offer(index);
     }
 
-    fn count_matching_diagonals(&self) -> (u8, u8, Player, Player) {
-        if self.size & 1 == 0 {
-            return (0, 0, Player::None, Player::None);
-        }
-
-        let target_lr_diag = self.board[0].clone();
-        let target_rl_diag = self.board[(self.size - 1) as usize].clone();
-
-        let mut count_lr_diag = 0;
-        let mut count_rl_diag = 0;
-        for y in 0..self.size {
-            let lr_diag_cell = (y * (self.size + 1)) as usize;
-            let rl_diag_cell = ((y + 1) * (self.size - 1)) as usize;
+    fn play(&mut self, index: usize) {
+        self.clear_hint();
+        self.rules.apply(&mut self.position, index);
+        self.history.push(index);
+        let (canonical, transform) = symmetry::canonical(&self.position);
+        log(
+            LogStatus::DEBUG,
+            format!(
+                "Current board: {}, hash: {:016x}, canonical: {} ({:?})",
+                self.position,
+                self.hash(),
+                canonical,
+                transform
+            )
+            .as_str(),
+        );
+    }
 
-            if self.board[lr_diag_cell] == target_lr_diag && target_lr_diag != Player::None {
-                count_lr_diag += 1;
-            }
-            if self.board[rl_diag_cell] == target_rl_diag && target_rl_diag != Player::None {
-                count_rl_diag += 1;
+    #[allow(non_snake_case)]
+    pub fn handleUndo(&mut self) {
+        log(LogStatus::DEBUG, "Undo handled in game screen");
+        if !self.current().lock().unwrap().is_human() {
+            return;
+        }
+        self.abort();
+        self.clear_hint();
+        // take back the other side's replies as well so a human is to move again
+        while let Some(index) = self.history.pop() {
+            self.rules.undo(&mut self.position, index);
+            if self.current().lock().unwrap().is_human() {
+                break;
             }
         }
-
-        (count_lr_diag, count_rl_diag, target_lr_diag, target_rl_diag)
     }
 
-    fn count_matching_rows_cols(&self) -> (u8, u8, Player) {
-        let (sel_row, sel_col) = self.cursor;
-        let target: Player = self.board[(sel_col * self.size + sel_row) as usize].clone();
+    pub fn hash(&self) -> u64 {
+        self.position.hash()
+    }
 
-        let mut count_col: u8 = 0;
-        let mut count_row: u8 = 0;
-        log(
-            LogStatus::DEBUG,
-            format!("Counting rows and col cells; cursor cell {:?}", self.cursor).as_str(),
-        );
-        log(
-            LogStatus::DEBUG,
-            format!("Selected count target {:?} for row", target).as_str(),
-        );
-        for cell in 0..self.size {
-            let current_row = (sel_col * self.size + cell) as usize;
-            let current_col = (cell * self.size + sel_row) as usize;
-            log(
-                LogStatus::DEBUG,
-                format!(
-                    "Current same-column cell {current_col} value {:?}, Current same-row cell {current_row} value {:?}",
-                    self.board[current_col],
-                    self.board[current_row],
-                )
-                .as_str(),
-            );
+    pub fn rules(&self) -> Arc<dyn Rules> {
+        self.rules.clone()
+    }
 
-            if self.board[current_row] == target && target != Player::None {
-                count_row += 1;
-            }
-            if self.board[current_col] == target && target != Player::None {
-                count_col += 1;
-            }
+    // The position the game started from and the moves played since
+    pub fn moves(&self) -> (Position, Vec<usize>) {
+        let mut start = self.position.clone();
+        for index in self.history.iter().rev() {
+            self.rules.undo(&mut start, *index);
         }
-
-        (count_row, count_col, target)
+        (start, self.history.clone())
     }
+}
 
-    fn try_get_winner(&self) -> Option<Player> {
-        let (count_row, count_col, target) = self.count_matching_rows_cols();
+// The side a human takes against an engine. X always moves first
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Side {
+    X,
+    O,
+    Random,
+}
 
-        let (count_lr_diag, count_rl_diag, target_lr_diag, target_rl_diag) =
-            self.count_matching_diagonals();
+impl Side {
+    pub fn next(self) -> Side {
+        match self {
+            Side::X => Side::O,
+            Side::O => Side::Random,
+            Side::Random => Side::X,
+        }
+    }
 
-        let is_match_all = count_row == self.size
-            || count_col == self.size
-            || count_lr_diag == self.size
-            || count_rl_diag == self.size;
+    pub fn previous(self) -> Side {
+        self.next().next()
+    }
 
-        if is_match_all {
-            match vec![count_row, count_col, count_rl_diag, count_lr_diag]
-                .iter()
-                .max()
-                .unwrap()
-            {
-                cr if cr == &count_row => target,
-                cc if cc == &count_col => target,
-                clr if clr == &count_lr_diag => target_lr_diag,
-                crl if crl == &count_rl_diag => target_rl_diag,
-                _ => unreachable!(),
-            };
+    // The players in X, O order, with a random side settled by `rng`
+    pub fn seat<T>(self, human: T, ai: T, rng: &mut Rng) -> [T; 2] {
+        match self {
+            Side::X => [human, ai],
+            Side::O => [ai, human],
+            Side::Random if rng.below(2) == 0 => [human, ai],
+            Side::Random => [ai, human],
+        }
+    }
+}
+
+impl fmt::Display for Side {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Side::X => write!(f, "X, moving first"),
+            Side::O => write!(f, "O, the AI moves first"),
+            Side::Random => write!(f, "a random side"),
         }
-        None
     }
 }
 
@@ -251,115 +729,306 @@ pub enum Player {
     None,
 }
 
+impl Player {
+    pub fn opponent(&self) -> Player {
+        match self {
+            Player::X => Player::O,
+            Player::O => Player::X,
+            Player::None => unreachable!(),
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
-    use crate::game::Player;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
 
-    use super::Game;
-    use super::Player::*;
+    use crate::ApplicationState;
+    use crate::agent::{EngineAgent, Human, Scripted, SharedAgent, shared};
+    use crate::ai::{Minimax, SharedEngine};
+    use crate::position::Position;
+    use crate::rng::Rng;
+    use crate::rules::{Misere, Rules, Standard};
 
-    #[test]
-    fn diagonals() {
-        let r1 = Game {
-            cursor: (0, 0),
-            board: vec![X, O, None, O, X, None, None, O, X].into_boxed_slice(),
-            turn: O,
-            size: 3,
-            confirm: true,
-            is_ai: false,
-            can_player_select: true,
+    use super::{Evaluation, Game, Player, Side};
+
+    fn humans() -> [SharedAgent; 2] {
+        [shared(Human::default()), shared(Human::default())]
+    }
+
+    fn minimax() -> SharedAgent {
+        let engine: SharedEngine = Arc::new(Mutex::new(Minimax::new(9, Duration::from_secs(10))));
+        shared(EngineAgent::new(engine, Duration::from_secs(10)))
+    }
+
+    fn play(notation: &str, cursor: (u8, u8), rules: Arc<dyn Rules>) -> ApplicationState {
+        let position: Position = notation.parse().unwrap();
+        let mut game = Game::from_position(position, rules, humans());
+        game.cursor = cursor;
+        game.handleConfirm();
+        game.update()
+    }
+
+    fn in_game(state: ApplicationState) -> Game {
+        match state {
+            ApplicationState::Game(game) => game,
+            _ => panic!("game should still be going"),
         }
-        .count_matching_diagonals();
+    }
 
-        assert_eq!(r1, (3, 0, Player::X, Player::None));
+    // Updates until no agent is busy any more
+    fn settle(mut state: ApplicationState) -> ApplicationState {
+        while matches!(&state, ApplicationState::Game(game) if game.is_thinking()) {
+            std::thread::sleep(Duration::from_millis(1));
+            state = state.update();
+        }
+        state
+    }
 
-        let r2 = Game {
-            cursor: (0, 0),
-            board: vec![O, O, None, O, X, None, None, O, X].into_boxed_slice(),
-            turn: X,
-            size: 3,
-            confirm: true,
-            is_ai: false,
-            can_player_select: true,
+    // Updates until hints and heatmaps are done as well
+    fn settle_background(mut state: ApplicationState) -> ApplicationState {
+        while matches!(&state, ApplicationState::Game(game) if game.is_busy()) {
+            std::thread::sleep(Duration::from_millis(1));
+            state = state.update();
         }
-        .count_matching_diagonals();
+        state
+    }
 
-        assert_eq!(r2, (1, 0, Player::O, Player::None));
+    #[test]
+    fn confirm_places_piece_and_passes_turn() {
+        let game = in_game(play(".../.../... x k3", (1, 2), Arc::new(Standard)));
+        assert_eq!(game.position.to_string(), ".../.../.x. o k3");
+        assert!(!game.is_thinking());
+    }
 
-        let r3 = Game {
-            cursor: (0, 0),
-            board: vec![X, O, None, O, X, None, None, O, O].into_boxed_slice(),
-            turn: O,
-            size: 3,
-            confirm: true,
-            is_ai: false,
-            can_player_select: true,
+    #[test]
+    fn occupied_cell_is_ignored() {
+        let game = in_game(play("x../.../... o k3", (0, 0), Arc::new(Standard)));
+        assert_eq!(game.position.to_string(), "x../.../... o k3");
+    }
+
+    #[test]
+    fn completing_a_line_ends_the_game() {
+        match play("xx./oo./... x k3", (2, 0), Arc::new(Standard)) {
+            ApplicationState::Result(result) => assert_eq!(result.win, Player::X),
+            _ => panic!("game should be over"),
+        }
+        match play("xx./oo./... x k3", (2, 0), Arc::new(Misere)) {
+            ApplicationState::Result(result) => assert_eq!(result.win, Player::O),
+            _ => panic!("game should be over"),
         }
-        .count_matching_diagonals();
+    }
 
-        assert_eq!(r3, (2, 0, Player::X, Player::None));
+    #[test]
+    fn undo_restores_position_and_hash() {
+        let mut game = in_game(play(".../.x./... o k3", (0, 0), Arc::new(Standard)));
+        let start: Position = ".../.x./... o k3".parse().unwrap();
+        assert_ne!(game.hash(), start.hash());
+
+        game.handleUndo();
+        assert_eq!(game.position, start);
+        assert_eq!(game.hash(), start.hash());
+
+        // nothing left to take back
+        game.handleUndo();
+        assert_eq!(game.position, start);
     }
 
-    fn create_game(board: Vec<Player>, size: u8, cursor: (u8, u8)) -> Game {
-        Game {
-            board: board.into_boxed_slice(),
-            size,
-            turn: Player::X,
-            cursor,
-            confirm: false,
-            is_ai: false,
-            can_player_select: true,
+    #[test]
+    fn ai_replies_after_the_player_moves() {
+        let position: Position = "x../.o./... x k3".parse().unwrap();
+        let players = [shared(Human::default()), minimax()];
+        let mut game = Game::from_position(position, Arc::new(Standard), players);
+        game.cursor = (1, 0);
+        game.handleConfirm();
+        let mut game = in_game(settle(game.update()));
+        // the AI blocks the top row instead of letting X complete it
+        assert_eq!(game.position.to_string(), "xxo/.o./... x k3");
+
+        game.handleUndo();
+        assert_eq!(game.position.to_string(), "x../.o./... x k3");
+    }
+
+    #[test]
+    fn engines_play_each_other() {
+        let game = Game::new(3, Arc::new(Standard), [minimax(), minimax()]);
+        assert!(game.is_thinking());
+        match settle(game.update()) {
+            ApplicationState::Result(result) => assert_eq!(result.win, Player::None),
+            _ => panic!("game should be over"),
         }
     }
 
     #[test]
-    fn test_empty_board() {
-        let size = 3;
-        let board = vec![Player::None; (size * size) as usize];
-        let game = create_game(board, size, (0, 0));
+    fn illegal_moves_are_asked_again() {
+        let players = [
+            shared(Scripted::new(vec![0, 1, 2])),
+            shared(Scripted::new(vec![0, 3, 4])),
+        ];
+        let game = Game::new(3, Arc::new(Standard), players);
+        match settle(game.update()) {
+            ApplicationState::Result(result) => {
+                assert_eq!(result.win, Player::X);
+                assert_eq!(result.game.history, vec![0, 3, 1, 4, 2]);
+            }
+            _ => panic!("game should be over"),
+        }
+    }
+
+    #[test]
+    fn hints_point_at_the_best_move_and_are_counted() {
+        let position: Position = "x../.o./o.x x k3".parse().unwrap();
+        let mut game = Game::from_position(position, Arc::new(Standard), humans());
+        game.handleHint();
+        assert!(game.is_busy());
+        // a hint dropped before it arrives is not counted
+        game.abort();
+        assert_eq!(game.hints(), 0);
+        game.handleHint();
+        let mut game = in_game(settle_background(ApplicationState::Game(game)));
+        assert_eq!(game.hint, Some((2, Evaluation::Win(2))));
+        // asking again while the hint is up costs nothing
+        game.handleHint();
+        assert_eq!(game.hints(), 1);
 
-        let (count_row, count_col, target) = game.count_matching_rows_cols();
-        assert_eq!(count_row, 0);
-        assert_eq!(count_col, 0);
-        assert_eq!(target, Player::None);
+        game.cursor = (2, 0);
+        game.handleConfirm();
+        let game = in_game(game.update());
+        assert_eq!(game.hint, None);
+        assert_eq!(game.hints(), 1);
+        assert_eq!(game.restart().hints(), 0);
     }
 
     #[test]
-    fn test_full_row_match() {
-        let size = 3;
-        let board = vec![
-            X, X, X, // row 0
-            O, None, O, None, O, None,
+    fn hints_on_big_boards_show_forced_wins() {
+        let position: Position =
+            "o.......o/........./........./........./...xxx.../........./........./........./o........ x k5"
+                .parse()
+                .unwrap();
+        let mut game = Game::from_position(position, Arc::new(Standard), humans());
+        game.handleHint();
+        let game = in_game(settle_background(ApplicationState::Game(game)));
+        let (index, evaluation) = game.hint.unwrap();
+        assert!([38, 42].contains(&index));
+        assert_eq!(evaluation, Evaluation::Win(2));
+        assert_eq!(game.hint_line.len(), 3);
+        assert_eq!(game.hint_line[0], index);
+    }
+
+    #[test]
+    fn heatmap_rates_every_empty_cell() {
+        let position: Position = "x../.o./o.x x k3".parse().unwrap();
+        let mut game = Game::from_position(position, Arc::new(Standard), humans());
+        game.handleOverlay();
+        let mut game = in_game(settle_background(ApplicationState::Game(game)));
+        let heatmap = game.heatmap.clone().unwrap();
+        assert_eq!(heatmap.len(), 5);
+        assert!(heatmap.contains(&(2, Evaluation::Win(2))));
+        assert_eq!(game.heat(2), Some(super::Color::Green));
+        assert_eq!(game.heat(0), None);
+
+        // a move makes the old heatmap stale; the next one is for O
+        game.cursor = (2, 0);
+        game.handleConfirm();
+        let mut game = in_game(settle_background(game.update()));
+        assert_eq!(game.heatmap.as_ref().unwrap().len(), 4);
+
+        game.handleOverlay();
+        assert_eq!(game.heat(1), None);
+    }
+
+    #[test]
+    fn heat_colours_follow_the_evaluation() {
+        use super::{Color, heat_colour};
+        assert_eq!(heat_colour(&Evaluation::Loss(3)), Some(Color::Red));
+        assert_eq!(heat_colour(&Evaluation::Draw), Some(Color::Yellow));
+        assert_eq!(
+            heat_colour(&Evaluation::Rate(1.0)),
+            Some(Color::Rgb { r: 0, g: 255, b: 0 })
+        );
+        assert_eq!(
+            heat_colour(&Evaluation::Score(0)),
+            Some(Color::Rgb {
+                r: 255,
+                g: 255,
+                b: 0
+            })
+        );
+        assert_eq!(heat_colour(&Evaluation::Unknown), None);
+    }
+
+    #[test]
+    fn watched_games_can_be_paused_and_stepped() {
+        let players = [
+            shared(Scripted::new(vec![0, 1, 2])),
+            shared(Scripted::new(vec![3, 4])),
         ];
-        let game = create_game(board, size, (1, 0)); // cursor on first row, middle column
+        let mut game = Game::new(3, Arc::new(Standard), players);
+        assert!(game.is_thinking());
+        // one move per update, so every move is drawn
+        game = in_game(game.update());
+        assert_eq!(game.history, vec![0]);
+
+        game.handlePause();
+        game = in_game(game.update());
+        assert_eq!(game.history, vec![0]);
+        game.handleStep();
+        game = in_game(game.update());
+        game = in_game(game.update());
+        assert_eq!(game.history, vec![0, 3]);
+
+        game.handlePause();
+        game.handleSpeed(false);
+        game.handleSpeed(false);
+        assert_eq!(game.delay, Duration::from_millis(250));
+        game = in_game(game.update());
+        assert_eq!(game.history, vec![0, 3]);
+        std::thread::sleep(game.delay);
+        game = in_game(game.update());
+        assert_eq!(game.history, vec![0, 3, 1]);
 
-        let (count_row, count_col, target) = game.count_matching_rows_cols();
-        assert_eq!(count_row, 3); // entire row is X
-        assert_eq!(count_col, 1); // only one X in the column
-        assert_eq!(target, Player::X);
+        game.handleSpeed(true);
+        assert_eq!(game.delay, Duration::from_millis(100));
     }
 
     #[test]
-    fn test_full_col_match() {
-        let size = 3;
-        let board = vec![O, X, None, O, None, X, O, X, None];
-        let game = create_game(board, size, (0, 2)); // cursor bottom-left
+    fn restart_keeps_board_settings() {
+        let game = in_game(play("..../..../..../.... x k3", (0, 0), Arc::new(Misere)));
+        let restarted = game.restart();
+        assert_eq!(restarted.position.to_string(), "..../..../..../.... x k3");
+        assert_eq!(restarted.rules.name(), "misere");
+    }
 
-        let (count_row, count_col, target) = game.count_matching_rows_cols();
-        assert_eq!(count_row, 1); // only one O in row 2
-        assert_eq!(count_col, 3); // full column of O
-        assert_eq!(target, Player::O);
+    #[test]
+    fn restart_alternates_the_starting_side() {
+        let (human, ai) = (shared(Human::default()), minimax());
+        let game = Game::new(3, Arc::new(Standard), [human.clone(), ai.clone()]);
+        assert!(!game.is_thinking());
+        let restarted = game.restart();
+        assert!(Arc::ptr_eq(&restarted.players[0], &ai));
+        assert!(restarted.is_thinking());
+        let again = restarted.restart();
+        assert!(Arc::ptr_eq(&again.players[0], &human));
     }
 
     #[test]
-    fn test_mixed_board() {
-        let size = 3;
-        let board = vec![X, O, X, O, X, O, X, None, O];
-        let game = create_game(board, size, (1, 1)); // cursor at middle
-
-        let (count_row, count_col, target) = game.count_matching_rows_cols();
-        assert_eq!(count_row, 1); // only middle cell matches Player::X
-        assert_eq!(count_col, 1); // top and middle cells are X
-        assert_eq!(target, Player::X);
+    fn humans_can_take_either_side() {
+        let (human, ai) = (shared(Human::default()), minimax());
+        let mut rng = Rng::new(5);
+        let [x, _] = Side::O.seat(human.clone(), ai.clone(), &mut rng);
+        assert!(Arc::ptr_eq(&x, &ai));
+        let [x, _] = Side::X.seat(human.clone(), ai.clone(), &mut rng);
+        assert!(Arc::ptr_eq(&x, &human));
+
+        let humans_first = (0..50)
+            .filter(|_| {
+                let [x, _] = Side::Random.seat(human.clone(), ai.clone(), &mut rng);
+                Arc::ptr_eq(&x, &human)
+            })
+            .count();
+        assert!((10..40).contains(&humans_first));
+        assert_eq!(Side::Random.next(), Side::X);
+        assert_eq!(Side::X.previous(), Side::Random);
     }
 }
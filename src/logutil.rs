@@ -1,7 +1,7 @@
 use std::fs::OpenOptions;
 use std::io::Write;
 
-#[allow(unused)]
+#[allow(unused, clippy::upper_case_acronyms)]
 pub enum LogStatus {
     DEBUG,
     INFO,
@@ -11,6 +11,11 @@ pub enum LogStatus {
 }
 
 pub fn log(status: LogStatus, message: &str) {
+    // keep test runs out of the log file
+    if cfg!(test) {
+        return;
+    }
+
     let line = format!(
         "[{}][{}]: {}",
         match status {
@@ -25,11 +30,7 @@ pub fn log(status: LogStatus, message: &str) {
     );
 
     // this is a really bad idea for optimization but i cant be fucked to write a singleton rn
-    let file = OpenOptions::new()
-        .write(true)
-        .append(true)
-        .create(true)
-        .open(".log");
+    let file = OpenOptions::new().append(true).create(true).open(".log");
 
     if file.is_err() {
         return;
@@ -0,0 +1,407 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+
+use crate::agent::Agent;
+use crate::game::Player;
+use crate::logutil::{LogStatus, log};
+use crate::position::Position;
+use crate::rng::Rng;
+use crate::rules::Rules;
+use crate::tablebase::{TableError, open_or_default, seal, unseal, write_file};
+
+const MAGIC: &[u8; 4] = b"TTTM";
+const VERSION: u16 = 1;
+
+// Beads every move starts with, and what a game adds to each move played in it
+const INITIAL_BEADS: u32 = 4;
+const WIN_BEADS: u32 = 3;
+const DRAW_BEADS: u32 = 1;
+
+// A move played in a game: the box it was drawn from and the move in canonical coordinates
+type Draw = (u64, usize);
+
+// MENACE: one matchbox of beads per position, keyed by canonical hash, with a bead count for
+// every move. Moves are drawn in proportion to their beads; after a game the moves that were
+// played gain beads when they led to a win or draw and lose one when they led to a loss
+//
+// Sealed as "TTTM" (see tablebase::seal). The body, little endian: width u8, height u8, win
+// length u8, rules name length u8 and bytes, box count u32, boxes of hash u64, move count
+// u16 and moves of cell u16 and beads u32 sorted by hash
+pub struct Menace {
+    width: u8,
+    height: u8,
+    win_length: u8,
+    rules: String,
+    boxes: HashMap<u64, Vec<(usize, u32)>>,
+    rng: Rng,
+}
+
+impl Menace {
+    pub fn new(width: u8, height: u8, win_length: u8, rules: &dyn Rules, seed: u64) -> Menace {
+        Menace {
+            width,
+            height,
+            win_length,
+            rules: rules.name().to_string(),
+            boxes: HashMap::new(),
+            rng: Rng::new(seed),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.boxes.len()
+    }
+
+    fn covers(&self, width: u8, height: u8, win_length: u8, rules: &dyn Rules) -> bool {
+        (self.width, self.height, self.win_length) == (width, height, win_length)
+            && self.rules == rules.name()
+    }
+
+    // Draws a bead from the position's box, filling the box first if the position is new.
+    // A box whose beads have all been taken out is refilled, since every move from it lost
+    fn draw(&mut self, position: &Position, rules: &dyn Rules) -> (usize, Draw) {
+        let (key, transform) = position.canonical_hash();
+        let (width, height) = (position.width(), position.height());
+        let beads = self.boxes.entry(key).or_default();
+        if beads.iter().all(|(_, count)| *count == 0) {
+            *beads = rules
+                .legal_moves(position)
+                .into_iter()
+                .map(|index| (transform.map_index(index, width, height), INITIAL_BEADS))
+                .collect();
+        }
+        let total: u32 = beads.iter().map(|(_, count)| count).sum();
+        let mut bead = self.rng.below(total as usize) as u32;
+        let mut cell = beads[0].0;
+        for (candidate, count) in beads.iter() {
+            if bead < *count {
+                cell = *candidate;
+                break;
+            }
+            bead -= count;
+        }
+        let index = transform.inverse().map_index(cell, width, height);
+        (index, (key, cell))
+    }
+
+    // Feeds back how a game went for the side that drew `moves`: 1, 0 or -1 as Rules::score
+    fn reinforce(&mut self, moves: &[Draw], score: i32) {
+        for (key, cell) in moves {
+            let Some(beads) = self.boxes.get_mut(key) else {
+                continue;
+            };
+            let Some((_, count)) = beads.iter_mut().find(|(c, _)| c == cell) else {
+                continue;
+            };
+            *count = match score {
+                1 => *count + WIN_BEADS,
+                0 => *count + DRAW_BEADS,
+                _ => count.saturating_sub(1),
+            };
+        }
+    }
+
+    // Plays `games` games against itself from the empty board, reinforcing both sides after
+    // each. Returns how many were won by X, drawn and won by O
+    pub fn train(&mut self, games: u32, rules: &dyn Rules) -> [u32; 3] {
+        let mut results = [0; 3];
+        for _ in 0..games {
+            let mut position = Position::new(self.width, self.height, self.win_length);
+            let mut moves: [Vec<Draw>; 2] = Default::default();
+            let outcome = loop {
+                if let Some(outcome) = rules.outcome(&position) {
+                    break outcome;
+                }
+                let side = (*position.turn() == Player::O) as usize;
+                let (index, drawn) = self.draw(&position, rules);
+                moves[side].push(drawn);
+                rules.apply(&mut position, index);
+            };
+            for (side, player) in [Player::X, Player::O].iter().enumerate() {
+                self.reinforce(&moves[side], rules.score(&outcome, player));
+            }
+            results[(1 - rules.score(&outcome, &Player::X)) as usize] += 1;
+        }
+        results
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend([self.width, self.height, self.win_length]);
+        bytes.push(self.rules.len() as u8);
+        bytes.extend(self.rules.as_bytes());
+        bytes.extend((self.boxes.len() as u32).to_le_bytes());
+        let mut boxes: Vec<(&u64, &Vec<(usize, u32)>)> = self.boxes.iter().collect();
+        boxes.sort_unstable_by_key(|(key, _)| **key);
+        for (key, beads) in boxes {
+            bytes.extend(key.to_le_bytes());
+            bytes.extend((beads.len() as u16).to_le_bytes());
+            for (cell, count) in beads {
+                bytes.extend((*cell as u16).to_le_bytes());
+                bytes.extend(count.to_le_bytes());
+            }
+        }
+        seal(MAGIC, VERSION, &bytes)
+    }
+
+    pub fn from_bytes(bytes: &[u8], seed: u64) -> Result<Menace, TableError> {
+        let mut reader = unseal(bytes, MAGIC, VERSION)?;
+        let (width, height, win_length) = (reader.u8()?, reader.u8()?, reader.u8()?);
+        let name_length = reader.u8()? as usize;
+        let rules = String::from_utf8_lossy(reader.take(name_length)?).into_owned();
+        let count = reader.u32()? as usize;
+        let mut boxes = HashMap::with_capacity(count);
+        for _ in 0..count {
+            let key = reader.u64()?;
+            let moves = reader.u16()? as usize;
+            let beads = (0..moves)
+                .map(|_| Ok((reader.u16()? as usize, reader.u32()?)))
+                .collect::<Result<_, TableError>>()?;
+            boxes.insert(key, beads);
+        }
+        reader.end()?;
+        Ok(Menace {
+            width,
+            height,
+            win_length,
+            rules,
+            boxes,
+            rng: Rng::new(seed),
+        })
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), TableError> {
+        write_file(path, &self.to_bytes())
+    }
+
+    pub fn load(path: &Path, seed: u64) -> Result<Menace, TableError> {
+        Menace::from_bytes(&fs::read(path)?, seed)
+    }
+}
+
+pub type SharedMenace = Arc<Mutex<Menace>>;
+
+// Where `train` writes learned boxes by default and where learning players keep theirs
+pub fn default_path(width: u8, height: u8, win_length: u8, rules: &dyn Rules) -> PathBuf {
+    PathBuf::from(format!(
+        "tables/{}x{}k{}-{}.menace",
+        width,
+        height,
+        win_length,
+        rules.name()
+    ))
+}
+
+// The boxes saved at `path` if they are for this board, otherwise empty ones
+pub fn open(
+    path: &Path,
+    width: u8,
+    height: u8,
+    win_length: u8,
+    rules: &dyn Rules,
+    seed: u64,
+) -> Menace {
+    open_or_default(
+        path,
+        |path| Menace::load(path, seed),
+        |menace| menace.covers(width, height, win_length, rules),
+        || Menace::new(width, height, win_length, rules, seed),
+    )
+}
+
+type MenaceCache = Mutex<HashMap<PathBuf, SharedMenace>>;
+
+// The boxes for a board, opened from `default_path` the first time they are asked for and
+// shared from then on, so every learning player on that board learns into the same boxes
+pub fn shared(width: u8, height: u8, win_length: u8, rules: &dyn Rules, seed: u64) -> SharedMenace {
+    static CACHE: OnceLock<MenaceCache> = OnceLock::new();
+    let path = default_path(width, height, win_length, rules);
+    CACHE
+        .get_or_init(Default::default)
+        .lock()
+        .unwrap()
+        .entry(path.clone())
+        .or_insert_with(|| {
+            let menace = open(&path, width, height, win_length, rules, seed);
+            Arc::new(Mutex::new(menace))
+        })
+        .clone()
+}
+
+// Plays from MENACE boxes and reinforces them after every game it finishes, saving them to
+// `path` when there is one
+pub struct Learner {
+    menace: SharedMenace,
+    path: Option<PathBuf>,
+    // what was drawn, by how many pieces were on the board at the time, so moves taken back
+    // with undo are forgotten
+    played: Vec<(u32, Draw)>,
+    side: Player,
+    chosen: Option<usize>,
+}
+
+impl Learner {
+    pub fn new(menace: SharedMenace, path: Option<PathBuf>) -> Learner {
+        Learner {
+            menace,
+            path,
+            played: Vec::new(),
+            side: Player::None,
+            chosen: None,
+        }
+    }
+}
+
+fn ply(position: &Position) -> u32 {
+    position.pieces(&Player::X).count() + position.pieces(&Player::O).count()
+}
+
+impl Agent for Learner {
+    fn name(&self) -> String {
+        format!("MENACE ({} boxes)", self.menace.lock().unwrap().len())
+    }
+
+    fn start(&mut self, position: &Position, rules: Arc<dyn Rules>) {
+        let ply = ply(position);
+        self.played.retain(|(at, _)| *at < ply);
+        self.side = position.turn().clone();
+        let (index, drawn) = self.menace.lock().unwrap().draw(position, rules.as_ref());
+        self.played.push((ply, drawn));
+        self.chosen = Some(index);
+    }
+
+    fn poll(&mut self) -> Option<usize> {
+        self.chosen.take()
+    }
+
+    fn cancel(&mut self) {
+        self.chosen = None;
+    }
+
+    fn game_over(&mut self, position: &Position, rules: &dyn Rules) {
+        let Some(outcome) = rules.outcome(position) else {
+            return;
+        };
+        let moves: Vec<Draw> = self.played.drain(..).map(|(_, drawn)| drawn).collect();
+        let score = rules.score(&outcome, &self.side);
+        let mut menace = self.menace.lock().unwrap();
+        menace.reinforce(&moves, score);
+        log(
+            LogStatus::DEBUG,
+            format!("MENACE reinforced {} moves with {}", moves.len(), score).as_str(),
+        );
+        if let Some(path) = &self.path
+            && let Err(e) = menace.save(path)
+        {
+            log(
+                LogStatus::ERROR,
+                format!("Could not save {}: {}", path.display(), e).as_str(),
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::rules::{Misere, Standard};
+
+    // Losses out of `games` games as X against a uniformly random O
+    fn losses_to_random(menace: &mut Menace, games: u32) -> u32 {
+        let mut rng = Rng::new(99);
+        let mut losses = 0;
+        for _ in 0..games {
+            let mut position = Position::new(3, 3, 3);
+            while Standard.outcome(&position).is_none() {
+                let index = match position.turn() {
+                    Player::X => menace.draw(&position, &Standard).0,
+                    _ => {
+                        let moves = Standard.legal_moves(&position);
+                        moves[rng.below(moves.len())]
+                    }
+                };
+                Standard.apply(&mut position, index);
+            }
+            let outcome = Standard.outcome(&position).unwrap();
+            losses += (Standard.score(&outcome, &Player::X) < 0) as u32;
+        }
+        losses
+    }
+
+    #[test]
+    fn training_cuts_losses() {
+        let mut menace = Menace::new(3, 3, 3, &Standard, 1);
+        let before = losses_to_random(&mut Menace::new(3, 3, 3, &Standard, 1), 200);
+        let results = menace.train(10_000, &Standard);
+        assert_eq!(results.iter().sum::<u32>(), 10_000);
+        let after = losses_to_random(&mut menace, 200);
+        assert!(
+            after * 4 < before,
+            "{} losses before, {} after",
+            before,
+            after
+        );
+    }
+
+    #[test]
+    fn draws_follow_symmetry() {
+        let mut menace = Menace::new(3, 3, 3, &Standard, 1);
+        let corner: Position = "x../.../... o k3".parse().unwrap();
+        let other: Position = "..x/.../... o k3".parse().unwrap();
+        menace.draw(&corner, &Standard);
+        let (index, _) = menace.draw(&other, &Standard);
+        assert_eq!(menace.len(), 1);
+        assert_ne!(index, 2);
+    }
+
+    #[test]
+    fn trains_on_rectangular_boards() {
+        let mut menace = Menace::new(4, 3, 3, &Standard, 1);
+        assert_eq!(menace.train(200, &Standard).iter().sum::<u32>(), 200);
+        // a half turn is one of the rectangle's symmetries, so both corners share a box
+        let mut menace = Menace::new(4, 3, 3, &Standard, 1);
+        let corner: Position = "x.../..../.... o k3".parse().unwrap();
+        let opposite: Position = "..../..../...x o k3".parse().unwrap();
+        for position in [&corner, &opposite] {
+            let (index, _) = menace.draw(position, &Standard);
+            assert!(Standard.legal_moves(position).contains(&index));
+        }
+        assert_eq!(menace.len(), 1);
+    }
+
+    #[test]
+    fn games_move_beads() {
+        let menace = Arc::new(Mutex::new(Menace::new(3, 3, 3, &Standard, 1)));
+        let mut learner = Learner::new(menace.clone(), None);
+        // O playing 2 leads to a draw, O playing 6 lets X complete the top row
+        let position: Position = "xx./oox/.xo o k3".parse().unwrap();
+        learner.start(&position, Arc::new(Standard));
+        let index = learner.poll().unwrap();
+        let mut finished = position.clone();
+        finished.play(index);
+        finished.play(if index == 2 { 6 } else { 2 });
+        learner.game_over(&finished, &Standard);
+
+        let menace = menace.lock().unwrap();
+        let (key, transform) = position.canonical_hash();
+        let cell = transform.map_index(index, 3, 3);
+        let expected = match index {
+            2 => INITIAL_BEADS + DRAW_BEADS,
+            _ => INITIAL_BEADS - 1,
+        };
+        assert!(menace.boxes[&key].contains(&(cell, expected)));
+    }
+
+    #[test]
+    fn round_trips_through_bytes() {
+        let mut menace = Menace::new(3, 3, 3, &Misere, 5);
+        menace.train(50, &Misere);
+        let bytes = menace.to_bytes();
+        let loaded = Menace::from_bytes(&bytes, 5).unwrap();
+        assert_eq!(loaded.boxes, menace.boxes);
+        assert!(loaded.covers(3, 3, 3, &Misere));
+        assert!(!loaded.covers(3, 3, 3, &Standard));
+    }
+}
@@ -0,0 +1,328 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::game::Player;
+use crate::position::Position;
+use crate::rng::Rng;
+use crate::rules::{EVAL_LIMIT, Rules};
+use crate::tablebase::{TableError, open_or_default, seal, unseal, write_file};
+
+const MAGIC: &[u8; 4] = b"TTTW";
+const VERSION: u16 = 1;
+
+// A line holding pieces of one side is told apart by how many it holds and by how many of
+// its two ends could extend it: a closed, half-open or open two, three and so on
+const OPENNESS: usize = 3;
+
+// Step size of the normalised TD update, and how often training plays a random move
+const LEARNING_RATE: f64 = 0.1;
+const EXPLORATION: usize = 10;
+
+// How many lines of each pattern the side to move has, followed by the same for the
+// opponent. A line holding pieces of both sides can never be completed and counts for nothing
+pub fn features(position: &Position) -> Vec<f64> {
+    let k = position.win_length() as usize;
+    let half = (k - 1) * OPENNESS;
+    let mut features = vec![0.0; 2 * half];
+    let mover = position.turn();
+    let (mine, theirs) = (*position.pieces(mover), *position.pieces(&mover.opponent()));
+    let empty = position.empty_cells();
+    let lines = position.lines();
+    for (i, line) in lines.all().iter().enumerate() {
+        let (own, other) = ((*line & mine).count(), (*line & theirs).count());
+        let (side, pieces) = match (own, other) {
+            (own, 0) if own > 0 => (0, own as usize),
+            (0, other) if other > 0 => (1, other as usize),
+            _ => continue,
+        };
+        if pieces >= k {
+            continue;
+        }
+        let open = lines
+            .ends(i)
+            .iter()
+            .filter(|end| end.is_some_and(|cell| empty.get(cell)))
+            .count();
+        features[side * half + (pieces - 1) * OPENNESS + open] += 1.0;
+    }
+    features
+}
+
+// Empty cells next to a piece, or the middle of an empty board. Lines far from every piece
+// change no pattern, so training does not look at moves there
+fn candidates(position: &Position, rules: &dyn Rules) -> Vec<usize> {
+    let (width, height) = (position.width() as i32, position.height() as i32);
+    let moves = rules.legal_moves(position);
+    let near = |index: usize| {
+        let (x, y) = ((index as i32) % width, (index as i32) / width);
+        (-1..=1).any(|dy| {
+            (-1..=1).any(|dx| {
+                let (nx, ny) = (x + dx, y + dy);
+                nx >= 0
+                    && nx < width
+                    && ny >= 0
+                    && ny < height
+                    && position.get((ny * width + nx) as usize) != Player::None
+            })
+        })
+    };
+    let nearby: Vec<usize> = moves.iter().copied().filter(|i| near(*i)).collect();
+    let middle = position.index(position.width() / 2, position.height() / 2);
+    if !nearby.is_empty() {
+        nearby
+    } else if moves.contains(&middle) {
+        vec![middle]
+    } else {
+        moves
+    }
+}
+
+// Weights over line patterns for one win length and ruleset. The value of a position for
+// the side to move is tanh of the weighted pattern counts, so -1 is a certain loss and 1 a
+// certain win. Patterns do not depend on the board size, so weights trained on a small
+// board carry over to bigger ones
+//
+// Sealed as "TTTW" (see tablebase::seal). The body, little endian: win length u8, rules
+// name length u8 and bytes, weight count u16, weights as f64
+#[derive(Clone, PartialEq, Debug)]
+pub struct Weights {
+    win_length: u8,
+    rules: String,
+    weights: Vec<f64>,
+}
+
+// One weight per feature: pieces from 1 to k - 1 in a line, by openness, for either side
+fn weight_count(win_length: u8) -> usize {
+    2 * (win_length as usize).saturating_sub(1) * OPENNESS
+}
+
+impl Weights {
+    pub fn new(win_length: u8, rules: &dyn Rules) -> Weights {
+        Weights {
+            win_length,
+            rules: rules.name().to_string(),
+            weights: vec![0.0; weight_count(win_length)],
+        }
+    }
+
+    pub fn covers(&self, position: &Position, rules: &dyn Rules) -> bool {
+        position.win_length() == self.win_length && rules.name() == self.rules
+    }
+
+    fn activation(&self, features: &[f64]) -> f64 {
+        self.weights
+            .iter()
+            .zip(features)
+            .map(|(w, f)| w * f)
+            .sum::<f64>()
+            .tanh()
+    }
+
+    // Expected result for the side to move, from -1 to 1
+    pub fn value(&self, position: &Position) -> f64 {
+        self.activation(&features(position))
+    }
+
+    // `value` scaled for searches, as Rules::evaluate
+    pub fn evaluate(&self, position: &Position) -> i32 {
+        (self.value(position) * EVAL_LIMIT as f64) as i32
+    }
+
+    // Value of `index` for the side to move: the result if it ends the game, otherwise what
+    // the position after it is worth to the opponent, negated
+    fn move_value(&self, position: &mut Position, rules: &dyn Rules, index: usize) -> f64 {
+        let mover = position.turn().clone();
+        rules.apply(position, index);
+        let value = match rules.outcome_after(position, index) {
+            Some(outcome) => rules.score(&outcome, &mover) as f64,
+            None => -self.value(position),
+        };
+        rules.undo(position, index);
+        value
+    }
+
+    // Plays `games` games against itself on a `width` by `height` board, mostly picking the
+    // move it values highest, and moves the value of every position it passes through
+    // towards the value of the move played from it (TD(0)). Returns how many games X won,
+    // drew and O won, and the mean squared difference between successive values
+    pub fn train(
+        &mut self,
+        width: u8,
+        height: u8,
+        games: u32,
+        rules: &dyn Rules,
+        rng: &mut Rng,
+    ) -> ([u32; 3], f64) {
+        let mut results = [0; 3];
+        let (mut error, mut updates) = (0.0, 0);
+        for _ in 0..games {
+            let mut position = Position::new(width, height, self.win_length);
+            let outcome = loop {
+                if let Some(outcome) = rules.outcome(&position) {
+                    break outcome;
+                }
+                let moves = candidates(&position, rules);
+                let scored: Vec<(usize, f64)> = moves
+                    .iter()
+                    .map(|index| (*index, self.move_value(&mut position, rules, *index)))
+                    .collect();
+                let (index, target) = if rng.below(EXPLORATION) == 0 {
+                    scored[rng.below(scored.len())]
+                } else {
+                    let best = scored.iter().map(|(_, v)| *v).fold(f64::MIN, f64::max);
+                    let ties: Vec<&(usize, f64)> =
+                        scored.iter().filter(|(_, v)| *v == best).collect();
+                    *ties[rng.below(ties.len())]
+                };
+
+                let features = features(&position);
+                let value = self.activation(&features);
+                let delta = target - value;
+                let norm = 1.0 + features.iter().map(|f| f * f).sum::<f64>();
+                let step = LEARNING_RATE * delta * (1.0 - value * value) / norm;
+                for (weight, feature) in self.weights.iter_mut().zip(&features) {
+                    *weight += step * feature;
+                }
+                error += delta * delta;
+                updates += 1;
+
+                rules.apply(&mut position, index);
+            };
+            results[(1 - rules.score(&outcome, &Player::X)) as usize] += 1;
+        }
+        (results, error / updates.max(1) as f64)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.push(self.win_length);
+        bytes.push(self.rules.len() as u8);
+        bytes.extend(self.rules.as_bytes());
+        bytes.extend((self.weights.len() as u16).to_le_bytes());
+        for weight in &self.weights {
+            bytes.extend(weight.to_le_bytes());
+        }
+        seal(MAGIC, VERSION, &bytes)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Weights, TableError> {
+        let mut reader = unseal(bytes, MAGIC, VERSION)?;
+        let win_length = reader.u8()?;
+        let name_length = reader.u8()? as usize;
+        let rules = String::from_utf8_lossy(reader.take(name_length)?).into_owned();
+        let count = reader.u16()? as usize;
+        if count != weight_count(win_length) {
+            return Err(TableError::WrongCount {
+                expected: weight_count(win_length),
+                found: count,
+            });
+        }
+        let weights = (0..count)
+            .map(|_| Ok(f64::from_bits(reader.u64()?)))
+            .collect::<Result<Vec<f64>, TableError>>()?;
+        reader.end()?;
+        Ok(Weights {
+            win_length,
+            rules,
+            weights,
+        })
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), TableError> {
+        write_file(path, &self.to_bytes())
+    }
+
+    pub fn load(path: &Path) -> Result<Weights, TableError> {
+        Weights::from_bytes(&fs::read(path)?)
+    }
+}
+
+// Where `train-eval` writes weights by default and where the menu looks for them
+pub fn default_path(win_length: u8, rules: &dyn Rules) -> PathBuf {
+    PathBuf::from(format!("tables/k{}-{}.weights", win_length, rules.name()))
+}
+
+// The weights for a win length if they have been trained, logging why not otherwise
+pub fn find(win_length: u8, rules: &dyn Rules) -> Option<Weights> {
+    let path = default_path(win_length, rules);
+    open_or_default(
+        &path,
+        |path| Weights::load(path).map(Some),
+        |weights| {
+            weights.as_ref().is_some_and(|weights| {
+                weights.win_length == win_length && weights.rules == rules.name()
+            })
+        },
+        || None,
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::rules::{Misere, Standard};
+
+    // index of a pattern of the side to move among `features`
+    fn mine(pieces: usize, open: usize) -> usize {
+        (pieces - 1) * OPENNESS + open
+    }
+
+    #[test]
+    fn counts_patterns_by_openness() {
+        // O to move, with only X pieces on the board
+        let position: Position = "x..../.xx../...../...../..... o k3".parse().unwrap();
+        let features = features(&position);
+        let theirs = |pieces, open| features[2 * OPENNESS + mine(pieces, open)];
+        assert_eq!(features.len(), 4 * OPENNESS);
+        assert!(features[..2 * OPENNESS].iter().all(|f| *f == 0.0));
+        // .xx on the second row and the diagonal from the corner are closed by the edge,
+        // xx. on the second row can grow either way
+        assert_eq!(theirs(2, 1), 2.0);
+        assert_eq!(theirs(2, 2), 1.0);
+        assert!(theirs(1, 1) > 0.0);
+    }
+
+    #[test]
+    fn training_is_reproducible() {
+        let mut first = Weights::new(4, &Standard);
+        let (results, _) = first.train(6, 6, 20, &Standard, &mut Rng::new(3));
+        assert_eq!(results.iter().sum::<u32>(), 20);
+        let mut second = Weights::new(4, &Standard);
+        second.train(6, 6, 20, &Standard, &mut Rng::new(3));
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn training_learns_threes() {
+        let mut weights = Weights::new(4, &Standard);
+        weights.train(6, 6, 500, &Standard, &mut Rng::new(3));
+        // the same open three is good for X to move and bad for O to move
+        let ahead: Position = "....../....../.xxx../....../..o.../.oo... x k4"
+            .parse()
+            .unwrap();
+        let behind: Position = "....../....../.xxx../....../..o.../.oo... o k4"
+            .parse()
+            .unwrap();
+        assert!(weights.value(&ahead) > 0.5);
+        assert!(weights.value(&behind) < 0.0);
+        assert!(weights.weights[mine(3, 2)] > weights.weights[mine(1, 2)]);
+    }
+
+    #[test]
+    fn round_trips_through_bytes() {
+        let mut weights = Weights::new(3, &Misere);
+        weights.train(4, 4, 20, &Misere, &mut Rng::new(1));
+        let bytes = weights.to_bytes();
+        assert_eq!(Weights::from_bytes(&bytes).unwrap(), weights);
+
+        // weights for four in a row under the header of three
+        weights.weights = Weights::new(4, &Misere).weights;
+        assert!(matches!(
+            Weights::from_bytes(&weights.to_bytes()),
+            Err(TableError::WrongCount {
+                expected: 12,
+                found: 18
+            })
+        ));
+    }
+}
@@ -0,0 +1,462 @@
+// The Piskvork protocol that Gomocup tournament managers use to talk to gomoku engines
+// ("brains"). The manager writes commands to the brain's stdin and reads its stdout:
+//
+//   START <size> / RECTSTART <width>,<height>   brain replies OK, or ERROR <text>
+//   RESTART                                      empty board again, brain replies OK
+//   BEGIN                                        brain plays the first move and replies x,y
+//   TURN <x>,<y>                                 the opponent played there; brain replies x,y
+//   BOARD, lines of <x>,<y>,<field>, DONE        the whole position; field 1 is the brain's
+//                                                stone, 2 the opponent's. Brain replies x,y
+//   TAKEBACK <x>,<y>                             the stone is removed, brain replies OK
+//   INFO <key> <value>                           settings such as timeout_turn, no reply
+//   ABOUT                                        brain describes itself
+//   END                                          brain exits
+//
+// Brains may write MESSAGE and DEBUG lines at any time. Only the five in a row game fits
+// the protocol, so it is limited to boards with a win length of five and standard rules
+use std::io::{self, BufRead, Write};
+use std::time::{Duration, Instant};
+
+use crate::ai::{Cancel, Engine};
+use crate::game::Player;
+use crate::logutil::{LogStatus, log};
+use crate::position::{MAX_DIMENSION, Position};
+use crate::protocol::{Process, REPLY_MARGIN, Timed, failure_status};
+use crate::rules::{Rules, Standard};
+
+const WIN_LENGTH: u8 = 5;
+// How long a move may take until the manager says otherwise
+const DEFAULT_TURN_TIME: Duration = Duration::from_secs(5);
+
+// Whether Piskvork brains can play games of `win_length` in a row under `rules`: Gomocup
+// brains only know five in a row
+pub fn plays(win_length: u8, rules: &dyn Rules) -> bool {
+    win_length == WIN_LENGTH && rules.name() == Standard.name()
+}
+
+fn parse_cell(text: &str, position: &Position) -> Option<usize> {
+    let mut fields = text.trim().split(',').map(|f| f.trim().parse::<u8>());
+    match (fields.next(), fields.next()) {
+        (Some(Ok(x)), Some(Ok(y))) if x < position.width() && y < position.height() => {
+            Some(position.index(x, y))
+        }
+        _ => None,
+    }
+}
+
+fn cell_text(position: &Position, index: usize) -> String {
+    let width = position.width() as usize;
+    format!("{},{}", index % width, index / width)
+}
+
+fn board(width: u8, height: u8) -> Result<Position, String> {
+    if width.min(height) < WIN_LENGTH || width.max(height) > MAX_DIMENSION {
+        return Err(format!(
+            "boards from {0}x{0} to {1}x{1} are supported",
+            WIN_LENGTH, MAX_DIMENSION
+        ));
+    }
+    Ok(Position::new(width, height, WIN_LENGTH))
+}
+
+// A position from BOARD stones, with the brain to move. The brain played first when both
+// sides have as many stones
+fn from_stones(width: u8, height: u8, stones: &[(usize, bool)]) -> Result<Position, String> {
+    let own = stones.iter().filter(|(_, mine)| *mine).count();
+    let theirs = stones.len() - own;
+    let (me, opponent) = match theirs.checked_sub(own) {
+        Some(0) => (Player::X, Player::O),
+        Some(1) => (Player::O, Player::X),
+        _ => return Err(format!("{} own and {} opposing stones", own, theirs)),
+    };
+    let mut cells = vec![Player::None; width as usize * height as usize];
+    for (index, mine) in stones {
+        cells[*index] = if *mine { me.clone() } else { opponent.clone() };
+    }
+    Ok(Position::with_cells(width, height, WIN_LENGTH, cells, me))
+}
+
+// Plays as a Piskvork brain on `input` and `output` with `engine` until END or the end of
+// input
+pub fn serve(engine: &mut Timed, input: impl BufRead, mut output: impl Write) -> io::Result<()> {
+    let mut position: Option<Position> = None;
+    let mut turn_time = DEFAULT_TURN_TIME;
+    let mut time_left: Option<Duration> = None;
+    let mut lines = input.lines();
+
+    while let Some(line) = lines.next() {
+        let line = line?;
+        let line = line.trim();
+        let (command, rest) = line.split_once(' ').unwrap_or((line, ""));
+        let mut reply_move = false;
+        match command.to_ascii_uppercase().as_str() {
+            "START" | "RECTSTART" => {
+                let size: Vec<u8> = rest
+                    .split(',')
+                    .filter_map(|n| n.trim().parse().ok())
+                    .collect();
+                let (width, height) = match size[..] {
+                    [size] if command.eq_ignore_ascii_case("START") => (size, size),
+                    [width, height] => (width, height),
+                    _ => (0, 0),
+                };
+                match board(width, height) {
+                    Ok(board) => {
+                        position = Some(board);
+                        writeln!(output, "OK")?;
+                    }
+                    Err(e) => writeln!(output, "ERROR {}", e)?,
+                }
+            }
+            "RESTART" => match &position {
+                Some(current) => {
+                    position = Some(Position::new(current.width(), current.height(), WIN_LENGTH));
+                    writeln!(output, "OK")?;
+                }
+                None => writeln!(output, "ERROR no game started")?,
+            },
+            "BEGIN" => reply_move = true,
+            "TURN" => match position.as_mut() {
+                Some(current) => match parse_cell(rest, current) {
+                    Some(index) if current.get(index) == Player::None => {
+                        Standard.apply(current, index);
+                        reply_move = true;
+                    }
+                    _ => writeln!(output, "ERROR invalid move {:?}", rest)?,
+                },
+                None => writeln!(output, "ERROR no game started")?,
+            },
+            "TAKEBACK" => match position.as_mut() {
+                Some(current) => match parse_cell(rest, current) {
+                    // rebuilt rather than undone, as the stone need not be the last one played
+                    Some(index) if current.get(index) != Player::None => {
+                        let owner = current.get(index);
+                        let mut cells = current.cells();
+                        cells[index] = Player::None;
+                        let (width, height) = (current.width(), current.height());
+                        *current = Position::with_cells(width, height, WIN_LENGTH, cells, owner);
+                        writeln!(output, "OK")?;
+                    }
+                    _ => writeln!(output, "ERROR invalid move {:?}", rest)?,
+                },
+                None => writeln!(output, "ERROR no game started")?,
+            },
+            "BOARD" => {
+                let Some(current) = &position else {
+                    writeln!(output, "ERROR no game started")?;
+                    continue;
+                };
+                let (width, height) = (current.width(), current.height());
+                let mut stones = Vec::new();
+                for line in lines.by_ref() {
+                    let line = line?;
+                    if line.trim().eq_ignore_ascii_case("DONE") {
+                        break;
+                    }
+                    let (cell, field) = line.trim().rsplit_once(',').unwrap_or((line.trim(), ""));
+                    if let Some(index) = parse_cell(cell, current) {
+                        match field.trim() {
+                            "1" => stones.push((index, true)),
+                            "2" => stones.push((index, false)),
+                            _ => {}
+                        }
+                    }
+                }
+                match from_stones(width, height, &stones) {
+                    Ok(board) => {
+                        position = Some(board);
+                        reply_move = true;
+                    }
+                    Err(e) => writeln!(output, "ERROR {}", e)?,
+                }
+            }
+            "INFO" => {
+                let (key, value) = rest.split_once(' ').unwrap_or((rest, ""));
+                let millis = value.trim().parse::<u64>().ok().map(Duration::from_millis);
+                match (key.to_ascii_lowercase().as_str(), millis) {
+                    ("timeout_turn", Some(millis)) => turn_time = millis,
+                    ("time_left", Some(millis)) => time_left = Some(millis),
+                    _ => {}
+                }
+            }
+            "ABOUT" => writeln!(
+                output,
+                "name=\"{}\", version=\"{}\"",
+                env!("CARGO_PKG_NAME"),
+                env!("CARGO_PKG_VERSION")
+            )?,
+            "END" => break,
+            "" => {}
+            _ => writeln!(output, "UNKNOWN {}", line)?,
+        }
+
+        if reply_move {
+            match position.as_mut() {
+                Some(current) => {
+                    // keep a margin for the manager, and spread what is left of the match
+                    let mut time = turn_time;
+                    if let Some(left) = time_left {
+                        time = time.min(left / 10);
+                    }
+                    let time = (time * 4 / 5).max(Duration::from_millis(10));
+                    match engine.analyse(current, &Standard, time) {
+                        Some((index, evaluation)) => {
+                            writeln!(output, "MESSAGE {}", evaluation)?;
+                            writeln!(output, "{}", cell_text(current, index))?;
+                            Standard.apply(current, index);
+                        }
+                        None => writeln!(output, "ERROR no move to play")?,
+                    }
+                }
+                None => writeln!(output, "ERROR no game started")?,
+            }
+        }
+        output.flush()?;
+    }
+    Ok(())
+}
+
+// The next line from a brain that is not a message, as Ok for answers and Err for errors
+fn answer(process: &mut Process, due: Instant, cancel: &Cancel) -> io::Result<String> {
+    loop {
+        let line = process.read_line(due, cancel)?;
+        let (kind, rest) = line.split_once(' ').unwrap_or((&line, ""));
+        match kind.to_ascii_uppercase().as_str() {
+            "MESSAGE" | "DEBUG" => log(LogStatus::DEBUG, format!("Brain: {}", rest).as_str()),
+            "ERROR" | "UNKNOWN" => return Err(io::Error::other(line.clone())),
+            _ => return Ok(line),
+        }
+    }
+}
+
+fn best_move(
+    process: &mut Process,
+    position: &Position,
+    time: Duration,
+    cancel: &Cancel,
+) -> io::Result<usize> {
+    let due = Instant::now() + time + REPLY_MARGIN;
+    let (width, height) = (position.width(), position.height());
+    let start = if width == height {
+        format!("START {}", width)
+    } else {
+        format!("RECTSTART {},{}", width, height)
+    };
+    if process.setup.as_ref() != Some(&start) {
+        process.send(&start)?;
+        answer(process, due, cancel)?;
+        process.send(&format!("INFO timeout_turn {}", time.as_millis()))?;
+        // freestyle: five or more in a row wins
+        process.send("INFO rule 0")?;
+        process.setup = Some(start);
+    }
+
+    let mover = position.turn();
+    process.send("BOARD")?;
+    for (index, player) in position.cells().iter().enumerate() {
+        if *player != Player::None {
+            let field = if player == mover { 1 } else { 2 };
+            process.send(&format!("{},{}", cell_text(position, index), field))?;
+        }
+    }
+    process.send("DONE")?;
+
+    let reply = answer(process, due, cancel)?;
+    parse_cell(&reply, position).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unexpected move {:?}", reply),
+        )
+    })
+}
+
+// A Piskvork brain run as a subprocess. The board is sent whole for every move, so games
+// can be restarted and moves taken back without the brain keeping track. Like External, a
+// brain that fails, is cancelled or overruns its time by REPLY_MARGIN is stopped
+pub struct Piskvork {
+    command: String,
+    time_limit: Duration,
+    process: Option<Process>,
+}
+
+impl Piskvork {
+    pub fn new(command: String, time_limit: Duration) -> Piskvork {
+        Piskvork {
+            command,
+            time_limit,
+            process: None,
+        }
+    }
+}
+
+impl Engine for Piskvork {
+    fn name(&self) -> String {
+        format!("piskvork ({})", self.command)
+    }
+
+    fn choose_move(
+        &mut self,
+        position: &Position,
+        rules: &dyn Rules,
+        cancel: &Cancel,
+    ) -> Option<usize> {
+        if !plays(position.win_length(), rules) {
+            log(
+                LogStatus::ERROR,
+                "Piskvork brains only play five in a row under standard rules",
+            );
+            return None;
+        }
+        let result = match &mut self.process {
+            Some(process) => Ok(process),
+            None => {
+                Process::spawn(&self.command, "END").map(|process| self.process.insert(process))
+            }
+        }
+        .and_then(|process| best_move(process, position, self.time_limit, cancel));
+        result
+            .inspect_err(|e| {
+                log(
+                    failure_status(e),
+                    format!("Piskvork brain {:?} failed: {}", self.command, e).as_str(),
+                );
+                self.process = None;
+            })
+            .ok()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::rules::Misere;
+
+    fn session(commands: &str) -> Vec<String> {
+        let mut engine = Timed::new("minimax".parse().unwrap(), 1);
+        let mut output = Vec::new();
+        serve(&mut engine, Cursor::new(commands), &mut output).unwrap();
+        String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .filter(|line| !line.starts_with("MESSAGE"))
+            .map(str::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn brain_completes_five_from_a_board() {
+        let replies = session(
+            "START 9\nINFO timeout_turn 200\nBOARD\n0,0,1\n1,0,1\n2,0,1\n3,0,1\n\
+             0,1,2\n1,1,2\n2,1,2\n8,8,2\nDONE\n",
+        );
+        assert_eq!(replies, ["OK", "4,0"]);
+    }
+
+    #[test]
+    fn brain_follows_turns_and_takebacks() {
+        let replies = session("START 7\nINFO timeout_turn 50\nBEGIN\nEND\nBEGIN\n");
+        assert_eq!(replies.len(), 2);
+        let first = replies[1].clone();
+        assert!(parse_cell(&first, &Position::new(7, 7, 5)).is_some());
+
+        let replies = session(
+            "START 7\nINFO timeout_turn 50\nTURN 3,3\nTAKEBACK 3,3\nTAKEBACK 3,3\nTURN 9,9\n",
+        );
+        assert_eq!(replies.len(), 5);
+        assert_eq!(replies[0], "OK");
+        assert_ne!(replies[1], "3,3");
+        assert_eq!(
+            replies[2..],
+            [
+                "OK",
+                "ERROR invalid move \"3,3\"",
+                "ERROR invalid move \"9,9\""
+            ]
+        );
+    }
+
+    #[test]
+    fn brain_rejects_what_it_cannot_play() {
+        let replies = session("START 20\nBEGIN\nABOUT\nPONDER\n");
+        assert!(replies[0].starts_with("ERROR"));
+        assert_eq!(replies[1], "ERROR no game started");
+        assert!(replies[2].starts_with("name="));
+        assert_eq!(replies[3], "UNKNOWN PONDER");
+    }
+
+    #[test]
+    fn brains_only_play_five_in_a_row() {
+        assert!(plays(5, &Standard));
+        assert!(!plays(3, &Standard));
+        assert!(!plays(5, &Misere));
+    }
+
+    #[test]
+    fn brains_run_as_subprocesses() {
+        // a stand-in brain that always answers the third cell of the top row
+        let script = std::env::temp_dir().join(format!("brain-{}.sh", std::process::id()));
+        fs::write(
+            &script,
+            "while read command rest; do case $command in \
+             START) echo OK;; \
+             DONE) echo MESSAGE thinking; echo 2,0;; \
+             END) exit;; \
+             esac; done\n",
+        )
+        .unwrap();
+
+        let mut brain = Piskvork::new(
+            format!("sh {}", script.display()),
+            Duration::from_millis(10),
+        );
+        let position: Position = "x..../.o.../...../...../..... x k5".parse().unwrap();
+        for _ in 0..2 {
+            assert_eq!(
+                brain.choose_move(&position, &Standard, &Cancel::default()),
+                Some(2)
+            );
+        }
+        assert_eq!(
+            brain.choose_move(&position, &Misere, &Cancel::default()),
+            None
+        );
+        fs::remove_file(script).ok();
+    }
+
+    #[test]
+    fn stuck_brains_are_stopped() {
+        // a stand-in brain that starts games but never plays
+        let script = std::env::temp_dir().join(format!("stuck-{}.sh", std::process::id()));
+        fs::write(
+            &script,
+            "while read command rest; do case $command in \
+             START) echo OK;; \
+             END) exit;; \
+             esac; done\n",
+        )
+        .unwrap();
+        let mut brain = Piskvork::new(
+            format!("sh {}", script.display()),
+            Duration::from_millis(10),
+        );
+        let position = Position::new(7, 7, 5);
+
+        let started = Instant::now();
+        assert_eq!(
+            brain.choose_move(&position, &Standard, &Cancel::default()),
+            None
+        );
+        assert!(started.elapsed() >= REPLY_MARGIN);
+        assert!(brain.process.is_none());
+
+        let cancel = Cancel::default();
+        cancel.cancel();
+        let started = Instant::now();
+        assert_eq!(brain.choose_move(&position, &Standard, &cancel), None);
+        assert!(started.elapsed() < REPLY_MARGIN);
+        fs::remove_file(script).ok();
+    }
+}
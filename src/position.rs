@@ -0,0 +1,538 @@
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use crate::bitboard::{self, Bitboard, Lines};
+use crate::game::Player;
+use crate::symmetry::{self, Transform};
+use crate::zobrist;
+
+// Largest board edge the notation (and the terminal) will accept
+pub const MAX_DIMENSION: u8 = 16;
+
+// Whether a game can be set up on this board: no side empty or longer than MAX_DIMENSION,
+// and a win length from 2 up to the longer side
+pub fn playable(width: u8, height: u8, win_length: u8) -> bool {
+    width.min(height) > 0
+        && width.max(height) <= MAX_DIMENSION
+        && (2..=width.max(height)).contains(&win_length)
+}
+
+// A bare board state: contents, dimensions, win length and side to move.
+// Each side's pieces are a bitboard, cell index = y * width + x
+#[derive(Clone)]
+pub struct Position {
+    width: u8,
+    height: u8,
+    win_length: u8,
+    x: Bitboard,
+    o: Bitboard,
+    turn: Player,
+    lines: Arc<Lines>,
+    symmetries: Arc<Vec<Vec<usize>>>,
+    // Hash of the board as seen through each of `Transform::ALL`, identity first
+    hashes: [u64; 8],
+}
+
+impl Position {
+    pub fn new(width: u8, height: u8, win_length: u8) -> Position {
+        Position {
+            width,
+            height,
+            win_length,
+            x: Bitboard::default(),
+            o: Bitboard::default(),
+            turn: Player::X,
+            lines: bitboard::lines(width, height, win_length),
+            symmetries: symmetry::cell_maps(width, height),
+            hashes: [zobrist::geometry(width, height, win_length); 8],
+        }
+    }
+
+    pub fn with_cells(
+        width: u8,
+        height: u8,
+        win_length: u8,
+        cells: Vec<Player>,
+        turn: Player,
+    ) -> Position {
+        assert_eq!(cells.len(), width as usize * height as usize);
+        let mut position = Position::new(width, height, win_length);
+        for (index, cell) in cells.iter().enumerate() {
+            match cell {
+                Player::X => position.x.set(index),
+                Player::O => position.o.set(index),
+                Player::None => continue,
+            }
+            position.toggle_hashes(cell, index, false);
+        }
+        if turn == Player::O {
+            for hash in position.hashes.iter_mut() {
+                *hash ^= zobrist::side();
+            }
+        }
+        position.turn = turn;
+        position
+    }
+
+    pub fn width(&self) -> u8 {
+        self.width
+    }
+
+    pub fn height(&self) -> u8 {
+        self.height
+    }
+
+    pub fn win_length(&self) -> u8 {
+        self.win_length
+    }
+
+    pub fn turn(&self) -> &Player {
+        &self.turn
+    }
+
+    pub fn get(&self, index: usize) -> Player {
+        if self.x.get(index) {
+            Player::X
+        } else if self.o.get(index) {
+            Player::O
+        } else {
+            Player::None
+        }
+    }
+
+    // Row-major copy of the board, for drawing and comparisons
+    pub fn cells(&self) -> Vec<Player> {
+        (0..self.width as usize * self.height as usize)
+            .map(|index| self.get(index))
+            .collect()
+    }
+
+    pub fn pieces(&self, player: &Player) -> &Bitboard {
+        match player {
+            Player::X => &self.x,
+            Player::O => &self.o,
+            Player::None => unreachable!(),
+        }
+    }
+
+    pub fn empty_cells(&self) -> Bitboard {
+        Bitboard::full(self.width as usize * self.height as usize).without(&(self.x | self.o))
+    }
+
+    pub fn is_full(&self) -> bool {
+        (self.x | self.o).count() == self.width as u32 * self.height as u32
+    }
+
+    pub fn lines(&self) -> &Lines {
+        &self.lines
+    }
+
+    // Whether `player` has `win_length` pieces in a row anywhere on the board
+    pub fn has_line(&self, player: &Player) -> bool {
+        let pieces = self.pieces(player);
+        self.lines.all().iter().any(|line| pieces.contains(line))
+    }
+
+    // Cheaper check for the lines passing through a single cell, e.g. the last move
+    pub fn has_line_through(&self, player: &Player, index: usize) -> bool {
+        let pieces = self.pieces(player);
+        self.lines.through(index).any(|line| pieces.contains(line))
+    }
+
+    pub fn index(&self, x: u8, y: u8) -> usize {
+        y as usize * self.width as usize + x as usize
+    }
+
+    // Zobrist hash of the pieces and side to move, kept up to date by play and undo
+    pub fn hash(&self) -> u64 {
+        self.hashes[0]
+    }
+
+    // The smallest hash among the symmetric variants of this position, equal for all of
+    // them, and the transform that produces it
+    pub fn canonical_hash(&self) -> (u64, Transform) {
+        self.hashes
+            .iter()
+            .copied()
+            .zip(
+                Transform::valid_for(self.width, self.height)
+                    .iter()
+                    .copied(),
+            )
+            .min_by_key(|(hash, _)| *hash)
+            .unwrap()
+    }
+
+    fn toggle_hashes(&mut self, player: &Player, index: usize, switch_side: bool) {
+        let side = if switch_side { zobrist::side() } else { 0 };
+        for (hash, map) in self.hashes.iter_mut().zip(self.symmetries.iter()) {
+            *hash ^= zobrist::piece(player, map[index]) ^ side;
+        }
+    }
+
+    // Places the piece of the side to move and passes the turn
+    pub fn play(&mut self, index: usize) {
+        match self.turn {
+            Player::X => self.x.set(index),
+            Player::O => self.o.set(index),
+            Player::None => unreachable!(),
+        }
+        self.toggle_hashes(&self.turn.clone(), index, true);
+        self.turn = self.turn.opponent();
+    }
+
+    // Takes back the piece at `index`, which must be the last one played
+    pub fn undo(&mut self, index: usize) {
+        self.turn = self.turn.opponent();
+        match self.turn {
+            Player::X => self.x.clear(index),
+            Player::O => self.o.clear(index),
+            Player::None => unreachable!(),
+        }
+        self.toggle_hashes(&self.turn.clone(), index, true);
+    }
+}
+
+impl PartialEq for Position {
+    fn eq(&self, other: &Position) -> bool {
+        (self.width, self.height, self.win_length) == (other.width, other.height, other.win_length)
+            && self.x == other.x
+            && self.o == other.o
+            && self.turn == other.turn
+    }
+}
+
+impl fmt::Debug for Position {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Position({})", self)
+    }
+}
+
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (y, row) in self.cells().chunks(self.width as usize).enumerate() {
+            if y > 0 {
+                write!(f, "/")?;
+            }
+            for cell in row {
+                write!(f, "{}", cell_symbol(cell))?;
+            }
+        }
+        write!(f, " {} k{}", cell_symbol(&self.turn), self.win_length)
+    }
+}
+
+fn cell_symbol(player: &Player) -> char {
+    match player {
+        Player::X => 'x',
+        Player::O => 'o',
+        Player::None => '.',
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum NotationErrorKind {
+    MissingField(&'static str),
+    TrailingInput,
+    UnexpectedCharacter(char),
+    EmptyRow(usize),
+    RaggedRow {
+        row: usize,
+        expected: usize,
+        found: usize,
+    },
+    TooLarge,
+    InvalidSideToMove,
+    InvalidWinLength,
+}
+
+// `column` is the 1-based character offset into the input where parsing failed
+#[derive(Clone, PartialEq, Debug)]
+pub struct NotationError {
+    pub column: usize,
+    pub kind: NotationErrorKind,
+}
+
+impl fmt::Display for NotationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "column {}: ", self.column)?;
+        match &self.kind {
+            NotationErrorKind::MissingField(field) => write!(f, "missing {}", field),
+            NotationErrorKind::TrailingInput => write!(f, "unexpected trailing input"),
+            NotationErrorKind::UnexpectedCharacter(c) => {
+                write!(f, "unexpected character {:?} in board", c)
+            }
+            NotationErrorKind::EmptyRow(row) => write!(f, "row {} is empty", row + 1),
+            NotationErrorKind::RaggedRow {
+                row,
+                expected,
+                found,
+            } => write!(
+                f,
+                "row {} has {} cells, expected {}",
+                row + 1,
+                found,
+                expected
+            ),
+            NotationErrorKind::TooLarge => {
+                write!(f, "board is larger than {0}x{0}", MAX_DIMENSION)
+            }
+            NotationErrorKind::InvalidSideToMove => write!(f, "side to move must be 'x' or 'o'"),
+            NotationErrorKind::InvalidWinLength => write!(
+                f,
+                "win length must be 'k' followed by a number between 2 and the board size"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for NotationError {}
+
+impl FromStr for Position {
+    type Err = NotationError;
+
+    // Format: rows top to bottom separated by '/', then side to move, then win length
+    // e.g. "x.o/.x./..o x k3"
+    fn from_str(s: &str) -> Result<Position, NotationError> {
+        let error = |column: usize, kind: NotationErrorKind| NotationError { column, kind };
+
+        let mut fields = Vec::new();
+        let mut start = None;
+        for (column, c) in s.chars().enumerate() {
+            match (c.is_whitespace(), start) {
+                (false, None) => start = Some(column),
+                (true, Some(from)) => {
+                    fields.push((from, s.chars().skip(from).take(column - from).collect()));
+                    start = None;
+                }
+                _ => {}
+            }
+        }
+        if let Some(from) = start {
+            fields.push((from, s.chars().skip(from).collect::<String>()));
+        }
+        let end = s.chars().count() + 1;
+
+        let (board_column, board) = fields
+            .first()
+            .ok_or(error(end, NotationErrorKind::MissingField("board")))?;
+        let (turn_column, turn) = fields
+            .get(1)
+            .ok_or(error(end, NotationErrorKind::MissingField("side to move")))?;
+        let (k_column, k) = fields
+            .get(2)
+            .ok_or(error(end, NotationErrorKind::MissingField("win length")))?;
+        if let Some((column, _)) = fields.get(3) {
+            return Err(error(column + 1, NotationErrorKind::TrailingInput));
+        }
+
+        let mut cells = Vec::new();
+        let mut width = None;
+        let mut row = 0;
+        let mut row_length = 0;
+        for (offset, c) in board.chars().chain(std::iter::once('/')).enumerate() {
+            let column = board_column + offset + 1;
+            match c {
+                'x' | 'X' => cells.push(Player::X),
+                'o' | 'O' => cells.push(Player::O),
+                '.' => cells.push(Player::None),
+                '/' => {
+                    if row_length == 0 {
+                        return Err(error(column, NotationErrorKind::EmptyRow(row)));
+                    }
+                    match width {
+                        None => width = Some(row_length),
+                        Some(expected) if expected != row_length => {
+                            return Err(error(
+                                column,
+                                NotationErrorKind::RaggedRow {
+                                    row,
+                                    expected,
+                                    found: row_length,
+                                },
+                            ));
+                        }
+                        _ => {}
+                    }
+                    row += 1;
+                    row_length = 0;
+                    continue;
+                }
+                other => {
+                    return Err(error(column, NotationErrorKind::UnexpectedCharacter(other)));
+                }
+            }
+            row_length += 1;
+            if row_length > MAX_DIMENSION as usize || row > MAX_DIMENSION as usize - 1 {
+                return Err(error(column, NotationErrorKind::TooLarge));
+            }
+        }
+        let width = width.unwrap() as u8;
+        let height = row as u8;
+
+        let turn = match turn.as_str() {
+            "x" | "X" => Player::X,
+            "o" | "O" => Player::O,
+            _ => {
+                return Err(error(turn_column + 1, NotationErrorKind::InvalidSideToMove));
+            }
+        };
+
+        let win_length = k
+            .strip_prefix(['k', 'K'])
+            .and_then(|n| n.parse::<u8>().ok())
+            .filter(|n| playable(width, height, *n))
+            .ok_or(error(k_column + 1, NotationErrorKind::InvalidWinLength))?;
+
+        Ok(Position::with_cells(width, height, win_length, cells, turn))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::game::Player::*;
+
+    #[test]
+    fn round_trip() {
+        for notation in [
+            "x.o/.x./..o x k3",
+            "..../..../.xo./.... o k4",
+            "xo./... x k2",
+        ] {
+            let position: Position = notation.parse().unwrap();
+            assert_eq!(position.to_string(), notation);
+        }
+    }
+
+    #[test]
+    fn parse_fields() {
+        let position: Position = "x.o/.x./..o O K3".parse().unwrap();
+        assert_eq!(position.width, 3);
+        assert_eq!(position.height, 3);
+        assert_eq!(position.win_length, 3);
+        assert_eq!(position.turn, O);
+        assert_eq!(
+            position.cells(),
+            vec![X, None, O, None, X, None, None, None, O]
+        );
+
+        let wide: Position = "...../..x.. o k3".parse().unwrap();
+        assert_eq!((wide.width(), wide.height()), (5, 2));
+        assert_eq!(wide.get(wide.index(2, 1)), X);
+    }
+
+    #[test]
+    fn parse_errors() {
+        let column_and_kind = |s: &str| {
+            let e = s.parse::<Position>().unwrap_err();
+            (e.column, e.kind)
+        };
+
+        assert_eq!(
+            column_and_kind(""),
+            (1, NotationErrorKind::MissingField("board"))
+        );
+        assert_eq!(
+            column_and_kind("x.o/.x./..o"),
+            (12, NotationErrorKind::MissingField("side to move"))
+        );
+        assert_eq!(
+            column_and_kind("x.o/.x./..o x"),
+            (14, NotationErrorKind::MissingField("win length"))
+        );
+        assert_eq!(
+            column_and_kind("x.o/.x./..o x k3 extra"),
+            (18, NotationErrorKind::TrailingInput)
+        );
+        assert_eq!(
+            column_and_kind("x.o/.z./..o x k3"),
+            (6, NotationErrorKind::UnexpectedCharacter('z'))
+        );
+        assert_eq!(
+            column_and_kind("x.o//..o x k3"),
+            (5, NotationErrorKind::EmptyRow(1))
+        );
+        assert_eq!(
+            column_and_kind("x.o/.x/..o x k3"),
+            (
+                7,
+                NotationErrorKind::RaggedRow {
+                    row: 1,
+                    expected: 3,
+                    found: 2
+                }
+            )
+        );
+        assert_eq!(
+            column_and_kind("x.o/.x./..o - k3"),
+            (13, NotationErrorKind::InvalidSideToMove)
+        );
+        assert_eq!(
+            column_and_kind("x.o/.x./..o x k4"),
+            (15, NotationErrorKind::InvalidWinLength)
+        );
+        assert_eq!(
+            column_and_kind("x.o/.x./..o x k1"),
+            (15, NotationErrorKind::InvalidWinLength)
+        );
+        assert_eq!(
+            column_and_kind("x.o/.x./..o x 3"),
+            (15, NotationErrorKind::InvalidWinLength)
+        );
+        assert_eq!(
+            column_and_kind("................. x k3"),
+            (17, NotationErrorKind::TooLarge)
+        );
+    }
+
+    #[test]
+    fn hash_follows_play_and_undo() {
+        let mut position: Position = "x../.o./... x k3".parse().unwrap();
+        let before = position.hash();
+
+        position.play(8);
+        let parsed: Position = "x../.o./..x o k3".parse().unwrap();
+        assert_eq!(position.hash(), parsed.hash());
+        assert_ne!(position.hash(), before);
+
+        position.undo(8);
+        assert_eq!(position.hash(), before);
+        assert_eq!(position.to_string(), "x../.o./... x k3");
+    }
+
+    #[test]
+    fn canonical_hash_ignores_symmetry() {
+        let position: Position = "xo./.../..o x k3".parse().unwrap();
+        let (hash, _) = position.canonical_hash();
+        for transform in Transform::ALL {
+            let variant = transform.apply(&position);
+            let (variant_hash, used) = variant.canonical_hash();
+            assert_eq!(variant_hash, hash);
+            assert_eq!(used.apply(&variant).hash(), hash);
+        }
+
+        let other: Position = "x.o/.../..o x k3".parse().unwrap();
+        assert_ne!(other.canonical_hash().0, hash);
+
+        // only the four symmetries of a rectangle apply to it
+        let wide: Position = "x..../..... o k3".parse().unwrap();
+        let turned: Position = "...../....x o k3".parse().unwrap();
+        let (hash, _) = wide.canonical_hash();
+        for variant in [&wide, &turned] {
+            let (variant_hash, used) = variant.canonical_hash();
+            assert_eq!(variant_hash, hash);
+            assert_eq!(used.apply(variant).hash(), hash);
+        }
+    }
+
+    #[test]
+    fn hash_distinguishes_side_to_move_and_geometry() {
+        let x_to_move: Position = "x../.../... x k3".parse().unwrap();
+        let o_to_move: Position = "x../.../... o k3".parse().unwrap();
+        let longer_win: Position = "x../.../... x k2".parse().unwrap();
+        assert_ne!(x_to_move.hash(), o_to_move.hash());
+        assert_ne!(x_to_move.hash(), longer_win.hash());
+    }
+}
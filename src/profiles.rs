@@ -0,0 +1,223 @@
+// AI opponents with a name, a strength and a playing style, read from a config file:
+//
+//   # comments start with a hash
+//   [Brawler]
+//   engine = minimax        minimax or mcts
+//   time = 1000             milliseconds per move
+//   depth = 6               optional, minimax only: how many plies it looks ahead
+//   style = aggressive      balanced, aggressive, defensive or trappy
+//
+// Each [section] is one opponent. Without a file the built-in ones below are offered
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::ai::{Budget, Mcts, Minimax, SharedEngine, Style};
+use crate::logutil::{LogStatus, log};
+use crate::patterns;
+use crate::rules::Rules;
+
+pub const DEFAULT_PATH: &str = "profiles.cfg";
+
+const BUILT_IN: &str = "\
+[Brawler]
+engine = minimax
+time = 1000
+style = aggressive
+
+[Wall]
+engine = minimax
+time = 1000
+style = defensive
+
+[Trickster]
+engine = minimax
+time = 1000
+style = trappy
+
+[Rookie]
+engine = minimax
+time = 500
+depth = 2
+style = balanced
+
+[Gambler]
+engine = mcts
+time = 1000
+style = aggressive
+";
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Kind {
+    Minimax,
+    Mcts,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Profile {
+    pub name: String,
+    kind: Kind,
+    pub time: Duration,
+    depth: u32,
+    style: Style,
+}
+
+impl Profile {
+    fn named(name: &str) -> Profile {
+        Profile {
+            name: name.to_string(),
+            kind: Kind::Minimax,
+            time: Duration::from_secs(1),
+            depth: u32::MAX,
+            style: Style::Balanced,
+        }
+    }
+
+    // An engine playing as this profile, with the trained weights for `win_length` if any.
+    // With `fixed_work` it searches for the work its time usually buys instead, see
+    // Minimax::with_fixed_work
+    pub fn build(
+        &self,
+        win_length: u8,
+        rules: &dyn Rules,
+        seed: u64,
+        threads: usize,
+        fixed_work: bool,
+    ) -> SharedEngine {
+        let weights = patterns::find(win_length, rules);
+        match self.kind {
+            Kind::Minimax => Arc::new(Mutex::new(
+                Minimax::new(self.depth, self.time)
+                    .with_fixed_work(fixed_work)
+                    .with_weights(weights)
+                    .with_threads(threads)
+                    .with_style(self.style),
+            )),
+            Kind::Mcts => Arc::new(Mutex::new(
+                Mcts::new(Budget::Time(self.time), seed)
+                    .with_fixed_work(fixed_work)
+                    .with_weights(weights)
+                    .with_threads(threads)
+                    .with_style(self.style),
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({})", self.name, self.style)
+    }
+}
+
+// The profiles in `text`, or the first line that is not understood
+pub fn parse(text: &str) -> Result<Vec<Profile>, String> {
+    let mut profiles: Vec<Profile> = Vec::new();
+    for (number, line) in (1..).zip(text.lines()) {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        let error = |message: String| format!("line {}: {}", number, message);
+        if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            if name.trim().is_empty() {
+                return Err(error("empty profile name".to_string()));
+            }
+            profiles.push(Profile::named(name.trim()));
+            continue;
+        }
+        let Some(profile) = profiles.last_mut() else {
+            return Err(error("setting before the first [profile]".to_string()));
+        };
+        let Some((key, value)) = line.split_once('=') else {
+            return Err(error(format!("expected key = value, got {:?}", line)));
+        };
+        let (key, value) = (key.trim(), value.trim());
+        let number = || {
+            value
+                .parse::<u32>()
+                .map_err(|_| error(format!("invalid {} {:?}", key, value)))
+        };
+        match key {
+            "engine" => {
+                profile.kind = match value {
+                    "minimax" => Kind::Minimax,
+                    "mcts" => Kind::Mcts,
+                    _ => return Err(error(format!("unknown engine {:?}", value))),
+                }
+            }
+            "time" => profile.time = Duration::from_millis(number()? as u64),
+            "depth" => profile.depth = number()?.max(1),
+            "style" => profile.style = value.parse().map_err(error)?,
+            _ => return Err(error(format!("unknown setting {:?}", key))),
+        }
+    }
+    if profiles.is_empty() {
+        return Err("no profiles".to_string());
+    }
+    Ok(profiles)
+}
+
+// The profiles from `path`, or the built-in ones when it is missing or broken
+pub fn load(path: &Path) -> Vec<Profile> {
+    let parsed = match fs::read_to_string(path) {
+        Ok(text) => parse(&text),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return built_in(),
+        Err(e) => Err(e.to_string()),
+    };
+    parsed.unwrap_or_else(|e| {
+        log(
+            LogStatus::WARN,
+            format!(
+                "Using built-in AI profiles, {} is unusable: {}",
+                path.display(),
+                e
+            )
+            .as_str(),
+        );
+        built_in()
+    })
+}
+
+pub fn built_in() -> Vec<Profile> {
+    parse(BUILT_IN).expect("built-in profiles parse")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn profiles_parse_with_defaults() {
+        let profiles = parse(
+            "# opponents\n[Brute]\nengine = mcts  # quick\ntime = 250\n\n[Slow]\nstyle = trappy\n",
+        )
+        .unwrap();
+        assert_eq!(profiles.len(), 2);
+        assert_eq!(profiles[0].kind, Kind::Mcts);
+        assert_eq!(profiles[0].time, Duration::from_millis(250));
+        assert_eq!(profiles[0].style, Style::Balanced);
+        assert_eq!(profiles[1].to_string(), "Slow (trappy)");
+        assert_eq!(profiles[1].depth, u32::MAX);
+        assert_eq!(built_in().len(), 5);
+    }
+
+    #[test]
+    fn bad_profiles_name_the_line() {
+        assert_eq!(
+            parse("time = 5").unwrap_err(),
+            "line 1: setting before the first [profile]"
+        );
+        assert_eq!(
+            parse("[A]\n\nstyle = sneaky").unwrap_err(),
+            "line 3: unknown style \"sneaky\""
+        );
+        assert!(parse("[A]\ncolour = red").is_err());
+        assert!(parse("[A]\ntime = soon").is_err());
+        assert!(parse("# nothing\n").is_err());
+        assert_eq!(load(Path::new("no/such/profiles.cfg")), built_in());
+    }
+}
@@ -0,0 +1,451 @@
+// A line-based text protocol for engines running as separate programs. The host writes
+// commands to the engine's stdin, one per line, and reads replies from its stdout:
+//
+//   newgame <width> <height> <win length> <rules>   engine replies "ready"
+//   position <board notation>                        no reply
+//   go <milliseconds>                                engine replies "bestmove <x> <y>",
+//                                                    or "bestmove none" when it has no move
+//   quit                                             engine exits
+//
+// Rules are named as Rules::name, "standard" or "misere". The board notation is the one
+// `--position` takes, e.g. "xx./oo./... x k3". Before "bestmove" an engine may send any
+// number of "info <text>" lines, and it answers commands it cannot follow with
+// "error <text>". Hosts skip lines they do not understand
+use std::io::{self, BufRead, BufReader, Write};
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::Arc;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::ai::{Cancel, Engine, Evaluation};
+use crate::arena::EngineSpec;
+use crate::logutil::{LogStatus, log};
+use crate::position::{self, Position};
+use crate::rules::{self, Rules};
+
+// An engine served to another program, built from `spec` for the time each move is given.
+// It is rebuilt only when that time changes, so engines that split their time between
+// searches get all of it while keeping what they learn between moves
+pub struct Timed {
+    spec: EngineSpec,
+    seed: u64,
+    built: Option<(Duration, Box<dyn Engine>)>,
+}
+
+impl Timed {
+    pub fn new(spec: EngineSpec, seed: u64) -> Timed {
+        Timed {
+            spec,
+            seed,
+            built: None,
+        }
+    }
+
+    pub fn analyse(
+        &mut self,
+        position: &Position,
+        rules: &dyn Rules,
+        time: Duration,
+    ) -> Option<(usize, Evaluation)> {
+        let (_, engine) = match &mut self.built {
+            Some(built) if built.0 == time => built,
+            built => built.insert((time, self.spec.clone().with_time(time).build(self.seed))),
+        };
+        engine.analyse(
+            position,
+            rules,
+            &Cancel::with_deadline(Instant::now() + time),
+        )
+    }
+}
+
+fn move_text(position: &Position, index: Option<usize>) -> String {
+    let width = position.width() as usize;
+    match index {
+        Some(index) => format!("{} {}", index % width, index / width),
+        None => "none".to_string(),
+    }
+}
+
+// Answers protocol commands from `input` with `engine` until "quit" or the end of input
+pub fn serve(engine: &mut Timed, input: impl BufRead, mut output: impl Write) -> io::Result<()> {
+    let mut rules: Arc<dyn Rules> = Arc::new(rules::Standard);
+    let mut position = Position::new(3, 3, 3);
+    for line in input.lines() {
+        let line = line?;
+        let (command, rest) = line.trim().split_once(' ').unwrap_or((line.trim(), ""));
+        match command {
+            "newgame" => {
+                let fields: Vec<&str> = rest.split_whitespace().collect();
+                let numbers: Vec<u8> = fields
+                    .iter()
+                    .take(3)
+                    .filter_map(|f| f.parse().ok())
+                    .collect();
+                match (
+                    &numbers[..],
+                    fields.get(3).and_then(|name| rules::named(name)),
+                ) {
+                    ([width, height, win_length], Some(named))
+                        if position::playable(*width, *height, *win_length) =>
+                    {
+                        position = Position::new(*width, *height, *win_length);
+                        rules = named;
+                        writeln!(output, "ready")?;
+                    }
+                    _ => writeln!(output, "error invalid newgame {:?}", rest)?,
+                }
+            }
+            "position" => match rest.parse::<Position>() {
+                Ok(parsed) => position = parsed,
+                Err(e) => writeln!(output, "error invalid position: {}", e)?,
+            },
+            "go" => {
+                let Ok(millis) = rest.trim().parse::<u64>() else {
+                    writeln!(output, "error invalid time {:?}", rest)?;
+                    continue;
+                };
+                let time = Duration::from_millis(millis);
+                let analysis = engine.analyse(&position, rules.as_ref(), time);
+                if let Some((_, evaluation)) = &analysis {
+                    writeln!(output, "info {}", evaluation)?;
+                }
+                let index = analysis.map(|(index, _)| index);
+                writeln!(output, "bestmove {}", move_text(&position, index))?;
+            }
+            "quit" => break,
+            "" => {}
+            _ => writeln!(output, "error unknown command {:?}", command)?,
+        }
+        output.flush()?;
+    }
+    Ok(())
+}
+
+// How much longer than its time for a move a program may take to answer before it is
+// given up on and stopped
+pub const REPLY_MARGIN: Duration = Duration::from_secs(2);
+// How often a wait for a reply checks whether it has been cancelled
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+// A running engine program. `farewell` is the command that asks it to exit. Its output is
+// read on a thread of its own, so waiting for a reply can give up
+pub struct Process {
+    child: Child,
+    input: ChildStdin,
+    lines: Receiver<String>,
+    farewell: &'static str,
+    // the last command that set up a game, so it is only sent again when the board or rules
+    // change
+    pub setup: Option<String>,
+}
+
+impl Process {
+    // `command` is the program followed by its arguments, split on whitespace
+    pub fn spawn(command: &str, farewell: &'static str) -> io::Result<Process> {
+        let mut words = command.split_whitespace();
+        let program = words
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "empty command"))?;
+        let mut child = Command::new(program)
+            .args(words)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()?;
+        let input = child.stdin.take().unwrap();
+        let output = BufReader::new(child.stdout.take().unwrap());
+        let (sender, lines) = mpsc::channel();
+        thread::spawn(move || {
+            for line in output.lines() {
+                let Ok(line) = line else { break };
+                if sender.send(line.trim().to_string()).is_err() {
+                    break;
+                }
+            }
+        });
+        Ok(Process {
+            child,
+            input,
+            lines,
+            farewell,
+            setup: None,
+        })
+    }
+
+    pub fn send(&mut self, line: &str) -> io::Result<()> {
+        writeln!(self.input, "{}", line)?;
+        self.input.flush()
+    }
+
+    // The next line the program writes, trimmed, if it comes before `due` and `cancel` is
+    // not called. A program that misses its reply is left mid-answer, so callers drop it
+    pub fn read_line(&mut self, due: Instant, cancel: &Cancel) -> io::Result<String> {
+        loop {
+            if cancel.was_cancelled() {
+                return Err(io::Error::new(io::ErrorKind::Interrupted, "cancelled"));
+            }
+            let left = due.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "engine did not answer in time",
+                ));
+            }
+            match self.lines.recv_timeout(left.min(POLL_INTERVAL)) {
+                Ok(line) => return Ok(line),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "engine closed its output",
+                    ));
+                }
+            }
+        }
+    }
+
+    // The first reply starting with `keyword`, without it. Logs the info lines before it
+    fn expect(&mut self, keyword: &str, due: Instant, cancel: &Cancel) -> io::Result<String> {
+        loop {
+            let line = self.read_line(due, cancel)?;
+            let (reply, rest) = line.split_once(' ').unwrap_or((&line, ""));
+            if reply == keyword {
+                return Ok(rest.to_string());
+            }
+            match reply {
+                "info" => log(LogStatus::DEBUG, format!("Engine: {}", rest).as_str()),
+                "error" => log(LogStatus::WARN, format!("Engine error: {}", rest).as_str()),
+                _ => {}
+            }
+        }
+    }
+
+    fn best_move(
+        &mut self,
+        position: &Position,
+        rules: &dyn Rules,
+        time: Duration,
+        cancel: &Cancel,
+    ) -> io::Result<Option<usize>> {
+        let due = Instant::now() + time + REPLY_MARGIN;
+        let game = format!(
+            "newgame {} {} {} {}",
+            position.width(),
+            position.height(),
+            position.win_length(),
+            rules.name()
+        );
+        if self.setup.as_ref() != Some(&game) {
+            self.send(&game)?;
+            self.expect("ready", due, cancel)?;
+            self.setup = Some(game);
+        }
+        self.send(&format!("position {}", position))?;
+        self.send(&format!("go {}", time.as_millis()))?;
+
+        let reply = self.expect("bestmove", due, cancel)?;
+        if reply == "none" {
+            return Ok(None);
+        }
+        let mut coordinates = reply.split_whitespace().map(str::parse::<u8>);
+        match (coordinates.next(), coordinates.next()) {
+            (Some(Ok(x)), Some(Ok(y))) if x < position.width() && y < position.height() => {
+                Ok(Some(position.index(x, y)))
+            }
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unexpected bestmove {:?}", reply),
+            )),
+        }
+    }
+}
+
+// Cancelling is not worth more than a debug line; anything else went wrong
+pub fn failure_status(error: &io::Error) -> LogStatus {
+    match error.kind() {
+        io::ErrorKind::Interrupted => LogStatus::DEBUG,
+        _ => LogStatus::ERROR,
+    }
+}
+
+impl Drop for Process {
+    fn drop(&mut self) {
+        self.send(self.farewell).ok();
+        if !matches!(self.child.try_wait(), Ok(Some(_))) {
+            self.child.kill().ok();
+        }
+        self.child.wait().ok();
+    }
+}
+
+// An engine in another program, spoken to over the protocol above. `command` is the
+// program followed by its arguments, split on whitespace. The program is started on the
+// first move and stopped when it fails, is cancelled or overruns its time by REPLY_MARGIN,
+// to be started afresh on the next
+pub struct External {
+    command: String,
+    time_limit: Duration,
+    process: Option<Process>,
+}
+
+impl External {
+    pub fn new(command: String, time_limit: Duration) -> External {
+        External {
+            command,
+            time_limit,
+            process: None,
+        }
+    }
+}
+
+impl Engine for External {
+    fn name(&self) -> String {
+        format!("external ({})", self.command)
+    }
+
+    fn choose_move(
+        &mut self,
+        position: &Position,
+        rules: &dyn Rules,
+        cancel: &Cancel,
+    ) -> Option<usize> {
+        let result = match &mut self.process {
+            Some(process) => Ok(process),
+            None => {
+                Process::spawn(&self.command, "quit").map(|process| self.process.insert(process))
+            }
+        }
+        .and_then(|process| process.best_move(position, rules, self.time_limit, cancel));
+        result
+            .inspect_err(|e| {
+                log(
+                    failure_status(e),
+                    format!("External engine {:?} failed: {}", self.command, e).as_str(),
+                );
+                self.process = None;
+            })
+            .ok()
+            .flatten()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::rules::Standard;
+
+    fn session(commands: &str) -> Vec<String> {
+        let mut engine = Timed::new("minimax".parse().unwrap(), 1);
+        let mut output = Vec::new();
+        serve(&mut engine, Cursor::new(commands), &mut output).unwrap();
+        String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .map(str::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn serves_moves_for_positions() {
+        let replies = session("newgame 3 3 3 standard\nposition xx./oo./... x k3\ngo 1000\n");
+        assert_eq!(replies, ["ready", "info win in 1", "bestmove 2 0"]);
+
+        let replies =
+            session("newgame 3 3 3 misere\nposition xxx/oo./... o k3\ngo 10\nquit\ngo 10\n");
+        assert_eq!(replies, ["ready", "bestmove none"]);
+    }
+
+    #[test]
+    fn reports_bad_commands() {
+        let replies = session("newgame 3 3 chess\nposition xx\ngo soon\nponder\n");
+        assert_eq!(replies.len(), 4);
+        assert!(replies.iter().all(|reply| reply.starts_with("error ")));
+
+        let replies = session(
+            "newgame 20 20 5 standard\nnewgame 3 3 0 standard\nnewgame 3 3 4 standard\n\
+             newgame 0 3 3 standard\n",
+        );
+        assert_eq!(replies.len(), 4);
+        assert!(
+            replies
+                .iter()
+                .all(|reply| reply.starts_with("error invalid newgame"))
+        );
+    }
+
+    #[test]
+    fn external_engines_run_as_subprocesses() {
+        // a stand-in engine that always plays the top right corner
+        let script = std::env::temp_dir().join(format!("engine-{}.sh", std::process::id()));
+        fs::write(
+            &script,
+            "while read command rest; do case $command in \
+             newgame) echo ready;; \
+             go) echo info thinking; echo bestmove 2 0;; \
+             quit) exit;; \
+             esac; done\n",
+        )
+        .unwrap();
+
+        let mut engine = External::new(
+            format!("sh {}", script.display()),
+            Duration::from_millis(10),
+        );
+        let position: Position = "x../.o./... x k3".parse().unwrap();
+        for _ in 0..2 {
+            let index = engine.choose_move(&position, &Standard, &Cancel::default());
+            assert_eq!(index, Some(2));
+        }
+
+        let mut missing = External::new("./no-such-engine".to_string(), Duration::from_millis(10));
+        assert_eq!(
+            missing.choose_move(&position, &Standard, &Cancel::default()),
+            None
+        );
+        fs::remove_file(script).ok();
+    }
+
+    #[test]
+    fn silent_engines_are_given_up_on() {
+        // a stand-in engine that sets up games but never answers "go"
+        let script = std::env::temp_dir().join(format!("silent-{}.sh", std::process::id()));
+        fs::write(
+            &script,
+            "while read command rest; do case $command in \
+             newgame) echo ready;; \
+             quit) exit;; \
+             esac; done\n",
+        )
+        .unwrap();
+        let mut engine = External::new(
+            format!("sh {}", script.display()),
+            Duration::from_millis(10),
+        );
+        let position: Position = "x../.o./... x k3".parse().unwrap();
+
+        let started = Instant::now();
+        assert_eq!(
+            engine.choose_move(&position, &Standard, &Cancel::default()),
+            None
+        );
+        assert!(started.elapsed() >= REPLY_MARGIN);
+        assert!(engine.process.is_none());
+
+        let cancel = Cancel::default();
+        let stop = cancel.clone();
+        let canceller = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            stop.cancel();
+        });
+        let started = Instant::now();
+        assert_eq!(engine.choose_move(&position, &Standard, &cancel), None);
+        assert!(started.elapsed() < REPLY_MARGIN);
+        canceller.join().unwrap();
+        fs::remove_file(script).ok();
+    }
+}
@@ -0,0 +1,442 @@
+// Post-game analysis. Every move of a finished game is rated against the alternatives the
+// advisor saw, one move at a time in the background, and the review screen steps through
+// the annotated moves
+use std::cmp::Ordering;
+use std::fmt;
+use std::io::Stdout;
+use std::sync::Arc;
+
+use crossterm::style::{Color, Stylize};
+use crossterm::{ExecutableCommand, cursor, terminal};
+
+use crate::agent::THINK_TIME;
+use crate::ai::{Evaluation, Search, SharedEngine};
+use crate::game::{Game, Player, draw_board};
+use crate::logutil::{LogStatus, log};
+use crate::position::Position;
+use crate::rules::{self, Rules};
+use crate::{ApplicationState, ResultMenu};
+
+// How much of the expected score a move may give away before it counts as an inaccuracy
+// or a blunder. Proven results turn a win into a draw or a draw into a loss for 0.5
+const INACCURACY: f64 = 0.1;
+const BLUNDER: f64 = 0.3;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Verdict {
+    Best,
+    Inaccuracy,
+    Blunder,
+    // The engine had no opinion on the move
+    Unrated,
+}
+
+impl fmt::Display for Verdict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Verdict::Best => write!(f, "best"),
+            Verdict::Inaccuracy => write!(f, "inaccuracy"),
+            Verdict::Blunder => write!(f, "blunder"),
+            Verdict::Unrated => write!(f, "unrated"),
+        }
+    }
+}
+
+// The share of the game the side to move can expect, from 0 for a loss to 1 for a win
+fn expected(evaluation: &Evaluation) -> Option<f64> {
+    match evaluation {
+        Evaluation::Win(_) => Some(1.0),
+        Evaluation::Draw => Some(0.5),
+        Evaluation::Loss(_) => Some(0.0),
+        Evaluation::Rate(rate) => Some(rate.clamp(0.0, 1.0)),
+        Evaluation::Score(score) => {
+            Some((0.5 + *score as f64 / (2 * rules::EVAL_LIMIT) as f64).clamp(0.0, 1.0))
+        }
+        Evaluation::Unknown => None,
+    }
+}
+
+// Orders evaluations for the side to move: by expected score, then quicker wins and slower
+// losses
+fn compare(a: &Evaluation, b: &Evaluation) -> Ordering {
+    let (ea, eb) = (expected(a).unwrap_or(0.5), expected(b).unwrap_or(0.5));
+    ea.total_cmp(&eb).then(match (a, b) {
+        (Evaluation::Win(a), Evaluation::Win(b)) => b.cmp(a),
+        (Evaluation::Loss(a), Evaluation::Loss(b)) => a.cmp(b),
+        _ => Ordering::Equal,
+    })
+}
+
+pub fn classify(best: &Evaluation, played: &Evaluation) -> Verdict {
+    let (Some(best_score), Some(played_score)) = (expected(best), expected(played)) else {
+        return Verdict::Unrated;
+    };
+    let loss = best_score - played_score;
+    if loss >= BLUNDER {
+        return Verdict::Blunder;
+    }
+    match (best, played) {
+        _ if loss >= INACCURACY => Verdict::Inaccuracy,
+        // still winning, or still lost, but taking longer about it or hurrying it along
+        (Evaluation::Win(_), Evaluation::Win(_)) | (Evaluation::Loss(_), Evaluation::Loss(_))
+            if compare(best, played) == Ordering::Greater =>
+        {
+            Verdict::Inaccuracy
+        }
+        _ => Verdict::Best,
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Annotation {
+    pub index: usize,
+    pub mover: Player,
+    pub played: Evaluation,
+    // The advisor's favourite, if it rated anything
+    pub best: Option<(usize, Evaluation)>,
+    pub verdict: Verdict,
+}
+
+// Judges `index` played in `position` by how the advisor `rated` every move there
+pub fn annotate(position: &Position, index: usize, rated: &[(usize, Evaluation)]) -> Annotation {
+    let played = rated
+        .iter()
+        .find(|(cell, _)| *cell == index)
+        .map_or(Evaluation::Unknown, |(_, evaluation)| *evaluation);
+    let best = rated
+        .iter()
+        .copied()
+        .filter(|(_, evaluation)| *evaluation != Evaluation::Unknown)
+        .max_by(|(_, a), (_, b)| compare(a, b));
+    let verdict = match &best {
+        Some((_, best)) => classify(best, &played),
+        None => Verdict::Unrated,
+    };
+    Annotation {
+        index,
+        mover: position.turn().clone(),
+        played,
+        best,
+        verdict,
+    }
+}
+
+// The move that lost the game for `loser`: their first blunder into a proven loss, or
+// failing a proof their first blunder
+pub fn losing_move(annotations: &[Annotation], loser: &Player) -> Option<usize> {
+    let blunders = || {
+        annotations
+            .iter()
+            .enumerate()
+            .filter(|(_, a)| a.mover == *loser && a.verdict == Verdict::Blunder)
+    };
+    blunders()
+        .find(|(_, a)| matches!(a.played, Evaluation::Loss(_)))
+        .or_else(|| blunders().next())
+        .map(|(ply, _)| ply)
+}
+
+fn verdict_colour(verdict: &Verdict) -> Option<Color> {
+    match verdict {
+        Verdict::Best => Some(Color::Green),
+        Verdict::Inaccuracy => Some(Color::Yellow),
+        Verdict::Blunder => Some(Color::Red),
+        Verdict::Unrated => None,
+    }
+}
+
+#[derive(Clone)]
+pub struct Review {
+    win: Player,
+    // kept to go back to the result screen with
+    game: Box<Game>,
+    start: Position,
+    moves: Vec<usize>,
+    rules: Arc<dyn Rules>,
+    engine: SharedEngine,
+    // For the first moves, in order; the next one is being rated by `search`
+    annotations: Vec<Annotation>,
+    search: Option<Search<Vec<(usize, Evaluation)>>>,
+    // How many moves are on the board
+    shown: usize,
+    leave: bool,
+}
+
+impl Review {
+    pub fn new(win: Player, mut game: Game) -> Review {
+        let (start, moves) = game.moves();
+        let rules = game.rules();
+        let engine = game.advisor();
+        log(
+            LogStatus::INFO,
+            format!("Analysing {} moves from {}", moves.len(), start).as_str(),
+        );
+        let mut review = Review {
+            win,
+            game: Box::new(game),
+            start,
+            shown: moves.len(),
+            moves,
+            rules,
+            engine,
+            annotations: Vec::new(),
+            search: None,
+            leave: false,
+        };
+        review.analyse_next();
+        review
+    }
+
+    // The position before move `ply`
+    fn before(&self, ply: usize) -> Position {
+        let mut position = self.start.clone();
+        for index in &self.moves[..ply] {
+            self.rules.apply(&mut position, *index);
+        }
+        position
+    }
+
+    fn analyse_next(&mut self) {
+        let ply = self.annotations.len();
+        if ply == self.moves.len() {
+            self.search = None;
+            return;
+        }
+        let (engine, position, rules) = (self.engine.clone(), self.before(ply), self.rules.clone());
+        self.search = Some(Search::spawn(THINK_TIME * 2, move |stop| {
+            engine
+                .lock()
+                .unwrap()
+                .rate_moves(&position, rules.as_ref(), stop)
+        }));
+    }
+
+    pub fn is_busy(&self) -> bool {
+        self.search.is_some()
+    }
+
+    fn losing_move(&self) -> Option<usize> {
+        match self.win {
+            Player::None => None,
+            ref winner => losing_move(&self.annotations, &winner.opponent()),
+        }
+    }
+
+    pub fn draw(&self, stdout: &mut Stdout) {
+        let (w, h) = terminal::size().expect("Failed to get terminal size");
+        let shown = self.before(self.shown);
+        let annotation = self
+            .shown
+            .checked_sub(1)
+            .and_then(|ply| self.annotations.get(ply));
+        draw_board(stdout, &shown, |index| {
+            let last = self.shown.checked_sub(1).map(|ply| self.moves[ply]);
+            if last != Some(index) {
+                return None;
+            }
+            annotation.map_or(Some(Color::White), |a| verdict_colour(&a.verdict))
+        });
+
+        let width = shown.width() as usize;
+        let cell = |index: usize| format!("({}, {})", index % width, index / width);
+        let mut lines = Vec::new();
+        lines.push(match (self.shown, annotation) {
+            (0, _) => format!("Start of the game, {} moves played", self.moves.len()),
+            (ply, None) => format!(
+                "Move {}: {}, not analysed yet",
+                ply,
+                cell(self.moves[ply - 1])
+            ),
+            (ply, Some(a)) => format!(
+                "Move {}: {:?} {}, {} ({})",
+                ply,
+                a.mover,
+                cell(a.index),
+                a.verdict,
+                a.played
+            ),
+        });
+        if let Some(Annotation {
+            best: Some((best, evaluation)),
+            verdict: Verdict::Inaccuracy | Verdict::Blunder,
+            ..
+        }) = annotation
+        {
+            lines.push(format!("Best was {}, {}", cell(*best), evaluation));
+        }
+        lines.push(match (&self.search, self.losing_move()) {
+            (_, Some(ply)) => format!("The game was lost at move {}", ply + 1),
+            (Some(_), None) => format!(
+                "Analysing move {} of {}...",
+                self.annotations.len() + 1,
+                self.moves.len()
+            ),
+            (None, None) => "No losing move found".to_string(),
+        });
+        lines.push("Left/right to step through moves, Enter to go back".to_string());
+
+        let top = (h / 2 + shown.height() as u16 / 2 + 2).min(h.saturating_sub(1));
+        for (row, line) in (0..).zip(lines) {
+            stdout
+                .execute(cursor::MoveTo(
+                    (w / 2).saturating_sub(line.len() as u16 / 2),
+                    (top + row).min(h.saturating_sub(1)),
+                ))
+                .ok();
+            print!(
+                "{}",
+                if row == 0 {
+                    line.bold()
+                } else {
+                    line.stylize()
+                }
+            );
+        }
+    }
+
+    pub fn update(mut self) -> ApplicationState {
+        if self.leave {
+            if let Some(search) = self.search.take() {
+                search.cancel();
+            }
+            return ApplicationState::Result(ResultMenu::new(self.win, *self.game));
+        }
+        if let Some(rated) = self.search.as_ref().and_then(Search::finished) {
+            let ply = self.annotations.len();
+            let annotation = annotate(&self.before(ply), self.moves[ply], rated);
+            log(
+                LogStatus::DEBUG,
+                format!("Move {}: {:?}", ply + 1, annotation).as_str(),
+            );
+            self.annotations.push(annotation);
+            self.analyse_next();
+        }
+        ApplicationState::Review(self)
+    }
+
+    #[allow(non_snake_case)]
+    pub fn handleButtonLeft(&mut self) {
+        self.shown = self.shown.saturating_sub(1);
+    }
+
+    #[allow(non_snake_case)]
+    pub fn handleButtonRight(&mut self) {
+        self.shown = (self.shown + 1).min(self.moves.len());
+    }
+
+    #[allow(non_snake_case)]
+    pub fn handleConfirm(&mut self) {
+        self.leave = true;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::agent::{Scripted, shared};
+    use crate::ai::{Budget, Cancel, Engine, Mcts};
+    use crate::rules::Standard;
+
+    #[test]
+    fn verdicts_follow_the_lost_expectation() {
+        use Evaluation::*;
+        assert_eq!(classify(&Win(2), &Win(2)), Verdict::Best);
+        assert_eq!(classify(&Win(2), &Win(3)), Verdict::Inaccuracy);
+        assert_eq!(classify(&Win(2), &Draw), Verdict::Blunder);
+        assert_eq!(classify(&Draw, &Loss(3)), Verdict::Blunder);
+        assert_eq!(classify(&Loss(3), &Loss(1)), Verdict::Inaccuracy);
+        assert_eq!(classify(&Rate(0.6), &Rate(0.55)), Verdict::Best);
+        assert_eq!(classify(&Rate(0.6), &Rate(0.45)), Verdict::Inaccuracy);
+        assert_eq!(classify(&Rate(0.6), &Rate(0.2)), Verdict::Blunder);
+        assert_eq!(classify(&Draw, &Unknown), Verdict::Unrated);
+    }
+
+    #[test]
+    fn quiet_big_boards_have_no_blunders() {
+        let mut position = Position::new(15, 15, 5);
+        position.play(7 * 15 + 7);
+        let rated = Mcts::new(Budget::Iterations(5000), 7).rate_moves(
+            &position,
+            &Standard,
+            &Cancel::default(),
+        );
+        let annotations: Vec<Annotation> = Standard
+            .legal_moves(&position)
+            .into_iter()
+            .map(|index| annotate(&position, index, &rated))
+            .collect();
+        assert!(annotations.iter().all(|a| a.verdict != Verdict::Blunder));
+        // the reply next to the centre stone is a natural one
+        let adjacent = &annotations.iter().find(|a| a.index == 7 * 15 + 8).unwrap();
+        assert_ne!(adjacent.verdict, Verdict::Inaccuracy);
+        assert!(annotations.iter().any(|a| a.verdict == Verdict::Best));
+    }
+
+    #[test]
+    fn annotations_name_the_best_alternative() {
+        let position: Position = "xx./oo./... o k3".parse().unwrap();
+        let rated = [
+            (2, Evaluation::Draw),
+            (5, Evaluation::Win(1)),
+            (6, Evaluation::Loss(1)),
+        ];
+        let annotation = annotate(&position, 6, &rated);
+        assert_eq!(annotation.mover, Player::O);
+        assert_eq!(annotation.best, Some((5, Evaluation::Win(1))));
+        assert_eq!(annotation.verdict, Verdict::Blunder);
+        assert_eq!(annotate(&position, 5, &rated).verdict, Verdict::Best);
+        assert_eq!(annotate(&position, 5, &[]).verdict, Verdict::Unrated);
+    }
+
+    #[test]
+    fn review_finds_where_the_game_was_lost() {
+        // O answers the centre with an edge, which loses; X then wins the top row
+        let players = [
+            shared(Scripted::new(vec![4, 0, 6, 2])),
+            shared(Scripted::new(vec![1, 8, 3])),
+        ];
+        let mut state = ApplicationState::Game(Game::new(3, Arc::new(Standard), players));
+        while matches!(&state, ApplicationState::Game(_)) {
+            std::thread::sleep(Duration::from_millis(1));
+            state = state.update();
+        }
+        let ApplicationState::Result(result) = state else {
+            panic!("game should be over");
+        };
+        assert_eq!(result.win, Player::X);
+
+        let mut state = ApplicationState::Review(Review::new(result.win, result.game));
+        while state.is_busy() {
+            std::thread::sleep(Duration::from_millis(1));
+            state = state.update();
+        }
+        let ApplicationState::Review(mut review) = state else {
+            panic!("should still be reviewing");
+        };
+        assert_eq!(review.annotations.len(), 7);
+        assert_eq!(review.annotations[1].verdict, Verdict::Blunder);
+        assert!(matches!(review.annotations[1].played, Evaluation::Loss(_)));
+        assert_eq!(review.losing_move(), Some(1));
+        assert!(
+            review
+                .annotations
+                .iter()
+                .filter(|a| a.mover == Player::X)
+                .all(|a| a.verdict != Verdict::Blunder)
+        );
+
+        assert_eq!(review.shown, 7);
+        review.handleButtonRight();
+        assert_eq!(review.shown, 7);
+        for _ in 0..8 {
+            review.handleButtonLeft();
+        }
+        assert_eq!(review.shown, 0);
+
+        review.handleConfirm();
+        assert!(matches!(review.update(), ApplicationState::Result(_)));
+    }
+}
@@ -0,0 +1,292 @@
+use std::sync::Arc;
+
+use crate::game::Player;
+use crate::position::Position;
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum Outcome {
+    Win(Player),
+    Draw,
+}
+
+// Everything that decides how a game is played. Game, the UI and the AI only go through
+// this trait, so a variant only needs a new implementation
+pub trait Rules: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    // Moves available to the side to move. Callers check `outcome` first; this does not
+    fn legal_moves(&self, position: &Position) -> Vec<usize> {
+        position.empty_cells().iter().collect()
+    }
+
+    fn apply(&self, position: &mut Position, index: usize) {
+        position.play(index);
+    }
+
+    // Takes back `index`, which must be the last move passed to `apply`
+    fn undo(&self, position: &mut Position, index: usize) {
+        position.undo(index);
+    }
+
+    // None while the game is still going
+    fn outcome(&self, position: &Position) -> Option<Outcome>;
+
+    // Same as `outcome` for a position that was not over before `last_move` was played.
+    // Searches call this after every move, so rulesets should override it when they can
+    // do better than rescanning the board
+    fn outcome_after(&self, position: &Position, last_move: usize) -> Option<Outcome> {
+        let _ = last_move;
+        self.outcome(position)
+    }
+
+    // True when no sequence of moves can lead to anything but a draw, so searches can stop
+    // early. Only a hint: the game itself still runs until `outcome` says it is over
+    fn certain_draw(&self, position: &Position) -> bool {
+        let _ = position;
+        false
+    }
+
+    // Heuristic value of an unfinished position for the side to move, within
+    // -EVAL_LIMIT..=EVAL_LIMIT. Searches use it where they stop short of the end of the game;
+    // 0 means no opinion
+    fn evaluate(&self, position: &Position) -> i32 {
+        let _ = position;
+        0
+    }
+
+    // Whether completing a line wins for the side that completed it. Threat-based searches
+    // only apply when it does
+    fn lines_win(&self) -> bool {
+        true
+    }
+
+    // Value of a finished game for `player`: 1 for a win, 0 for a draw, -1 for a loss
+    fn score(&self, outcome: &Outcome, player: &Player) -> i32 {
+        match outcome {
+            Outcome::Win(winner) if winner == player => 1,
+            Outcome::Win(_) => -1,
+            Outcome::Draw => 0,
+        }
+    }
+}
+
+pub const EVAL_LIMIT: i32 = 100_000;
+
+// The ruleset with this `name`, for rules given as text
+pub fn named(name: &str) -> Option<Arc<dyn Rules>> {
+    match name {
+        "standard" => Some(Arc::new(Standard)),
+        "misere" => Some(Arc::new(Misere)),
+        _ => None,
+    }
+}
+
+// Weight of a line that can still be completed and already holds `pieces` of one side
+pub fn line_weight(pieces: u32) -> i32 {
+    match pieces {
+        0 => 0,
+        n => 1 << (2 * n).min(12),
+    }
+}
+
+// Sum of line weights over every line `player` could still complete
+pub fn open_lines(position: &Position, player: &Player) -> i32 {
+    let mine = *position.pieces(player);
+    let theirs = *position.pieces(&player.opponent());
+    position
+        .lines()
+        .all()
+        .iter()
+        .filter(|line| (theirs & **line).is_empty())
+        .map(|line| line_weight((mine & *line).count()))
+        .sum()
+}
+
+// Every line already holds pieces of both sides
+fn all_lines_blocked(position: &Position) -> bool {
+    let (x, o) = (*position.pieces(&Player::X), *position.pieces(&Player::O));
+    position
+        .lines()
+        .all()
+        .iter()
+        .all(|line| !(x & *line).is_empty() && !(o & *line).is_empty())
+}
+
+fn line_balance(position: &Position) -> i32 {
+    let turn = position.turn();
+    (open_lines(position, turn) - open_lines(position, &turn.opponent()))
+        .clamp(-EVAL_LIMIT, EVAL_LIMIT)
+}
+
+// k in a row wins, a full board is a draw
+pub struct Standard;
+
+impl Rules for Standard {
+    fn name(&self) -> &'static str {
+        "standard"
+    }
+
+    fn outcome(&self, position: &Position) -> Option<Outcome> {
+        for player in [Player::X, Player::O] {
+            if position.has_line(&player) {
+                return Some(Outcome::Win(player));
+            }
+        }
+        position.is_full().then_some(Outcome::Draw)
+    }
+
+    fn outcome_after(&self, position: &Position, last_move: usize) -> Option<Outcome> {
+        let mover = position.turn().opponent();
+        if position.has_line_through(&mover, last_move) {
+            return Some(Outcome::Win(mover));
+        }
+        position.is_full().then_some(Outcome::Draw)
+    }
+
+    fn certain_draw(&self, position: &Position) -> bool {
+        all_lines_blocked(position)
+    }
+
+    fn evaluate(&self, position: &Position) -> i32 {
+        line_balance(position)
+    }
+}
+
+// Whoever completes k in a row loses
+pub struct Misere;
+
+impl Rules for Misere {
+    fn name(&self) -> &'static str {
+        "misere"
+    }
+
+    fn outcome(&self, position: &Position) -> Option<Outcome> {
+        for player in [Player::X, Player::O] {
+            if position.has_line(&player) {
+                return Some(Outcome::Win(player.opponent()));
+            }
+        }
+        position.is_full().then_some(Outcome::Draw)
+    }
+
+    fn outcome_after(&self, position: &Position, last_move: usize) -> Option<Outcome> {
+        let mover = position.turn().opponent();
+        if position.has_line_through(&mover, last_move) {
+            return Some(Outcome::Win(mover.opponent()));
+        }
+        position.is_full().then_some(Outcome::Draw)
+    }
+
+    fn lines_win(&self) -> bool {
+        false
+    }
+
+    fn certain_draw(&self, position: &Position) -> bool {
+        all_lines_blocked(position)
+    }
+
+    // building lines is what loses here
+    fn evaluate(&self, position: &Position) -> i32 {
+        -line_balance(position)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn outcome(rules: &dyn Rules, notation: &str) -> Option<Outcome> {
+        rules.outcome(&notation.parse().unwrap())
+    }
+
+    #[test]
+    fn standard_lines() {
+        assert_eq!(outcome(&Standard, ".../.../... x k3"), None);
+        assert_eq!(
+            outcome(&Standard, "xxx/o.o/.o. o k3"),
+            Some(Outcome::Win(Player::X))
+        );
+        assert_eq!(
+            outcome(&Standard, "ox./o.x/ox. x k3"),
+            Some(Outcome::Win(Player::O))
+        );
+        assert_eq!(
+            outcome(&Standard, "xo./ox./..x o k3"),
+            Some(Outcome::Win(Player::X))
+        );
+        assert_eq!(
+            outcome(&Standard, "xox/oxo/x.o o k3"),
+            Some(Outcome::Win(Player::X))
+        );
+        assert_eq!(outcome(&Standard, "xoo/oox/xxo x k3"), Some(Outcome::Draw));
+    }
+
+    #[test]
+    fn partial_lines_do_not_win() {
+        assert_eq!(outcome(&Standard, "oo./ox./.ox x k3"), None);
+        assert_eq!(outcome(&Standard, "xo./ox./.oo x k3"), None);
+        assert_eq!(outcome(&Standard, "xo./o../xo. o k3"), None);
+    }
+
+    #[test]
+    fn win_length_shorter_than_board() {
+        assert_eq!(outcome(&Standard, "...../.xxx./..... o k4"), None);
+        assert_eq!(
+            outcome(&Standard, "...../.xxxx/..... o k4"),
+            Some(Outcome::Win(Player::X))
+        );
+        assert_eq!(
+            outcome(&Standard, "..o../...o./....o o k3"),
+            Some(Outcome::Win(Player::O))
+        );
+    }
+
+    #[test]
+    fn misere_completing_a_line_loses() {
+        assert_eq!(
+            outcome(&Misere, "xxx/o.o/.o. o k3"),
+            Some(Outcome::Win(Player::O))
+        );
+        assert_eq!(Misere.score(&Outcome::Win(Player::O), &Player::X), -1);
+    }
+
+    #[test]
+    fn evaluation_favours_open_lines() {
+        let position: Position = ".../.x./... o k3".parse().unwrap();
+        assert!(Standard.evaluate(&position) < 0);
+        assert!(Misere.evaluate(&position) > 0);
+
+        // a blocked line is worth nothing to either side
+        let blocked: Position = "xxo/.../... x k3".parse().unwrap();
+        let open: Position = "xx./.../..o x k3".parse().unwrap();
+        assert!(Standard.evaluate(&open) > Standard.evaluate(&blocked));
+    }
+
+    #[test]
+    fn blocked_boards_are_certain_draws() {
+        let blocked: Position = "xo./ox./o.. x k3".parse().unwrap();
+        assert!(!Standard.certain_draw(&blocked));
+        let blocked: Position = "xo./oxx/oxo x k3".parse().unwrap();
+        assert!(Standard.certain_draw(&blocked));
+        assert!(Misere.certain_draw(&blocked));
+    }
+
+    #[test]
+    fn legal_moves_are_empty_cells() {
+        let position: Position = "xx./oo./... x k3".parse().unwrap();
+        assert_eq!(Standard.legal_moves(&position), vec![2, 5, 6, 7, 8]);
+    }
+
+    #[test]
+    fn outcome_after_matches_full_scan() {
+        for rules in [&Standard as &dyn Rules, &Misere] {
+            let mut position: Position = "xx./oo./... x k3".parse().unwrap();
+            position.play(2);
+            assert_eq!(rules.outcome_after(&position, 2), rules.outcome(&position));
+
+            let mut position: Position = "xx./oo./... x k3".parse().unwrap();
+            position.play(8);
+            assert_eq!(rules.outcome_after(&position, 8), None);
+        }
+    }
+}
@@ -0,0 +1,202 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock};
+
+use crate::game::Player;
+use crate::position::Position;
+
+// The dihedral symmetries of a board. Rotations are clockwise
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Transform {
+    Identity,
+    Rotate90,
+    Rotate180,
+    Rotate270,
+    FlipHorizontal,
+    FlipVertical,
+    Transpose,
+    AntiTranspose,
+}
+
+impl Transform {
+    pub const ALL: [Transform; 8] = [
+        Transform::Identity,
+        Transform::Rotate90,
+        Transform::Rotate180,
+        Transform::Rotate270,
+        Transform::FlipHorizontal,
+        Transform::FlipVertical,
+        Transform::Transpose,
+        Transform::AntiTranspose,
+    ];
+
+    // Quarter turns and diagonal flips only map a board onto itself when it is square
+    pub fn valid_for(width: u8, height: u8) -> &'static [Transform] {
+        if width == height {
+            &Transform::ALL
+        } else {
+            &[
+                Transform::Identity,
+                Transform::Rotate180,
+                Transform::FlipHorizontal,
+                Transform::FlipVertical,
+            ]
+        }
+    }
+
+    // Where the cell at (x, y) ends up after the transform
+    pub fn map(self, (x, y): (u8, u8), width: u8, height: u8) -> (u8, u8) {
+        let (right, bottom) = (width - 1, height - 1);
+        match self {
+            Transform::Identity => (x, y),
+            Transform::Rotate90 => (bottom - y, x),
+            Transform::Rotate180 => (right - x, bottom - y),
+            Transform::Rotate270 => (y, right - x),
+            Transform::FlipHorizontal => (right - x, y),
+            Transform::FlipVertical => (x, bottom - y),
+            Transform::Transpose => (y, x),
+            Transform::AntiTranspose => (bottom - y, right - x),
+        }
+    }
+
+    pub fn inverse(self) -> Transform {
+        match self {
+            Transform::Rotate90 => Transform::Rotate270,
+            Transform::Rotate270 => Transform::Rotate90,
+            other => other,
+        }
+    }
+
+    pub fn map_index(self, index: usize, width: u8, height: u8) -> usize {
+        let (x, y) = (index % width as usize, index / width as usize);
+        let (tx, ty) = self.map((x as u8, y as u8), width, height);
+        ty as usize * width as usize + tx as usize
+    }
+
+    pub fn apply(self, position: &Position) -> Position {
+        let (width, height) = (position.width(), position.height());
+        let mut cells = vec![Player::None; width as usize * height as usize];
+        for y in 0..height {
+            for x in 0..width {
+                let (tx, ty) = self.map((x, y), width, height);
+                cells[position.index(tx, ty)] = position.get(position.index(x, y));
+            }
+        }
+        Position::with_cells(
+            width,
+            height,
+            position.win_length(),
+            cells,
+            position.turn().clone(),
+        )
+    }
+}
+
+type MapCache = Mutex<HashMap<(u8, u8), Arc<Vec<Vec<usize>>>>>;
+
+// For each transform in `Transform::valid_for`, where every cell index ends up
+pub fn cell_maps(width: u8, height: u8) -> Arc<Vec<Vec<usize>>> {
+    static CACHE: OnceLock<MapCache> = OnceLock::new();
+    CACHE
+        .get_or_init(Default::default)
+        .lock()
+        .unwrap()
+        .entry((width, height))
+        .or_insert_with(|| {
+            let cells = width as usize * height as usize;
+            let maps = Transform::valid_for(width, height)
+                .iter()
+                .map(|t| (0..cells).map(|i| t.map_index(i, width, height)).collect())
+                .collect();
+            Arc::new(maps)
+        })
+        .clone()
+}
+
+fn cell_rank(player: &Player) -> u8 {
+    match player {
+        Player::None => 0,
+        Player::X => 1,
+        Player::O => 2,
+    }
+}
+
+// Picks the lexicographically smallest of the symmetric variants of a position, so that
+// rotated and mirrored boards share one representative. Returns the transform that maps
+// the given position onto that representative
+pub fn canonical(position: &Position) -> (Position, Transform) {
+    Transform::valid_for(position.width(), position.height())
+        .iter()
+        .map(|transform| (transform.apply(position), *transform))
+        .min_by(|(a, _), (b, _)| {
+            a.cells()
+                .iter()
+                .map(cell_rank)
+                .cmp(b.cells().iter().map(cell_rank))
+        })
+        .unwrap()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn square_transforms_are_distinct() {
+        let position: Position = "xo./.../... o k3".parse().unwrap();
+        let mut seen: Vec<String> = Transform::ALL
+            .iter()
+            .map(|t| t.apply(&position).to_string())
+            .collect();
+        seen.sort();
+        seen.dedup();
+        assert_eq!(seen.len(), 8);
+
+        assert_eq!(
+            Transform::Rotate90.apply(&position).to_string(),
+            "..x/..o/... o k3"
+        );
+        assert_eq!(
+            Transform::Transpose.apply(&position).to_string(),
+            "x../o../... o k3"
+        );
+    }
+
+    #[test]
+    fn inverse_undoes_transform() {
+        let position: Position = "xo./..o/... o k3".parse().unwrap();
+        for transform in Transform::ALL {
+            assert_eq!(
+                transform.inverse().apply(&transform.apply(&position)),
+                position
+            );
+            for index in 0..9 {
+                let there = transform.map_index(index, 3, 3);
+                assert_eq!(transform.inverse().map_index(there, 3, 3), index);
+            }
+        }
+    }
+
+    #[test]
+    fn rectangular_transforms() {
+        let position: Position = "x..../...o. x k3".parse().unwrap();
+        let transforms = Transform::valid_for(position.width(), position.height());
+        assert_eq!(transforms.len(), 4);
+        assert_eq!(
+            Transform::Rotate180.apply(&position).to_string(),
+            ".o.../....x x k3"
+        );
+    }
+
+    #[test]
+    fn symmetric_positions_share_canonical_form() {
+        let position: Position = "x../.o./... x k3".parse().unwrap();
+        let (representative, _) = canonical(&position);
+
+        for transform in Transform::ALL {
+            let variant = transform.apply(&position);
+            let (variant_canonical, used) = canonical(&variant);
+            assert_eq!(variant_canonical, representative);
+            assert_eq!(used.apply(&variant), representative);
+        }
+    }
+}
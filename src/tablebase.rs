@@ -0,0 +1,479 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::game::Player;
+use crate::logutil::{LogStatus, log};
+use crate::position::Position;
+use crate::rules::{Outcome, Rules};
+
+const MAGIC: &[u8; 4] = b"TTTB";
+const VERSION: u16 = 1;
+// Distances are stored in six bits, so solving stops at boards of this many cells
+const MAX_CELLS: usize = 16;
+
+// Game-theoretic value for the side to move, with the number of plies until the game
+// ends under perfect play
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Value {
+    Win(u8),
+    Draw,
+    Loss(u8),
+}
+
+impl Value {
+    fn encode(self) -> u8 {
+        match self {
+            Value::Win(plies) => 0x80 | plies,
+            Value::Loss(plies) => 0x40 | plies,
+            Value::Draw => 0,
+        }
+    }
+
+    fn decode(byte: u8) -> Option<Value> {
+        match (byte >> 6, byte & 0x3f) {
+            (2, plies) if plies > 0 => Some(Value::Win(plies)),
+            (1, plies) if plies > 0 => Some(Value::Loss(plies)),
+            (0, 0) => Some(Value::Draw),
+            _ => None,
+        }
+    }
+
+    fn of_outcome(outcome: &Outcome, rules: &dyn Rules, mover: &Player) -> Value {
+        match rules.score(outcome, mover) {
+            1 => Value::Win(1),
+            -1 => Value::Loss(1),
+            _ => Value::Draw,
+        }
+    }
+
+    // The same value one ply earlier, for the side that made the move
+    fn before(self) -> Value {
+        match self {
+            Value::Win(plies) => Value::Loss(plies + 1),
+            Value::Loss(plies) => Value::Win(plies + 1),
+            Value::Draw => Value::Draw,
+        }
+    }
+
+    // Higher is better: quicker wins, then draws, then slower losses
+    fn rank(self) -> i32 {
+        match self {
+            Value::Win(plies) => 100 - plies as i32,
+            Value::Draw => 0,
+            Value::Loss(plies) => plies as i32 - 100,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum TableError {
+    Io(io::Error),
+    BadMagic,
+    UnsupportedVersion(u16),
+    Truncated,
+    ChecksumMismatch,
+    InvalidValue(u8),
+    // A list whose length does not fit the rest of the file
+    WrongCount { expected: usize, found: usize },
+    TooLarge,
+}
+
+impl fmt::Display for TableError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TableError::Io(e) => write!(f, "{}", e),
+            TableError::BadMagic => write!(f, "not a table file"),
+            TableError::UnsupportedVersion(version) => {
+                write!(f, "table version {} is not supported", version)
+            }
+            TableError::Truncated => write!(f, "table is truncated"),
+            TableError::ChecksumMismatch => write!(f, "checksum mismatch"),
+            TableError::InvalidValue(byte) => write!(f, "invalid value byte {:#04x}", byte),
+            TableError::WrongCount { expected, found } => {
+                write!(f, "{} entries where {} belong", found, expected)
+            }
+            TableError::TooLarge => {
+                write!(f, "only boards of up to {} cells can be solved", MAX_CELLS)
+            }
+        }
+    }
+}
+
+impl std::error::Error for TableError {}
+
+impl From<io::Error> for TableError {
+    fn from(e: io::Error) -> TableError {
+        TableError::Io(e)
+    }
+}
+
+// FNV-1a, enough to catch truncated or corrupted files
+pub fn checksum(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+// Every file the program keeps is sealed the same way: a 4 byte magic, a version u16, the
+// body, then an FNV-1a checksum u64 of everything before it
+pub fn seal(magic: &[u8; 4], version: u16, body: &[u8]) -> Vec<u8> {
+    let mut bytes = magic.to_vec();
+    bytes.extend(version.to_le_bytes());
+    bytes.extend(body);
+    bytes.extend(checksum(&bytes).to_le_bytes());
+    bytes
+}
+
+// A reader over the body of a sealed file, once its magic, version and checksum check out
+pub fn unseal<'a>(
+    bytes: &'a [u8],
+    magic: &[u8; 4],
+    version: u16,
+) -> Result<Reader<'a>, TableError> {
+    let mut reader = Reader(bytes);
+    if reader.take(4)? != magic {
+        return Err(TableError::BadMagic);
+    }
+    let found = reader.u16()?;
+    if found != version {
+        return Err(TableError::UnsupportedVersion(found));
+    }
+    let Some(length) = reader.0.len().checked_sub(8) else {
+        return Err(TableError::Truncated);
+    };
+    let (sealed, sum) = bytes.split_at(bytes.len() - 8);
+    if checksum(sealed) != u64::from_le_bytes(sum.try_into().unwrap()) {
+        return Err(TableError::ChecksumMismatch);
+    }
+    Ok(Reader(&reader.0[..length]))
+}
+
+// Writes `bytes` to `path`, making its directory first
+pub fn write_file(path: &Path, bytes: &[u8]) -> Result<(), TableError> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(path, bytes)?;
+    Ok(())
+}
+
+// What `load` reads from `path` if it `fits`, otherwise `fresh`. Only a file that is there
+// but unusable is worth a warning
+pub fn open_or_default<T>(
+    path: &Path,
+    load: impl FnOnce(&Path) -> Result<T, TableError>,
+    fits: impl FnOnce(&T) -> bool,
+    fresh: impl FnOnce() -> T,
+) -> T {
+    let problem = match load(path) {
+        Ok(loaded) if fits(&loaded) => {
+            log(
+                LogStatus::INFO,
+                format!("Loaded {}", path.display()).as_str(),
+            );
+            return loaded;
+        }
+        Ok(_) => Some("written for another board".to_string()),
+        Err(TableError::Io(e)) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => Some(e.to_string()),
+    };
+    if let Some(problem) = problem {
+        log(
+            LogStatus::WARN,
+            format!("Ignoring {}: {}", path.display(), problem).as_str(),
+        );
+    }
+    fresh()
+}
+
+// Reads fields front to back, failing with Truncated when the input runs out
+pub struct Reader<'a>(pub &'a [u8]);
+
+impl<'a> Reader<'a> {
+    pub fn take(&mut self, count: usize) -> Result<&'a [u8], TableError> {
+        if self.0.len() < count {
+            return Err(TableError::Truncated);
+        }
+        let (head, rest) = self.0.split_at(count);
+        self.0 = rest;
+        Ok(head)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, TableError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> Result<u16, TableError> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, TableError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, TableError> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    // Fails unless everything has been read, as a count that does not match the data would
+    pub fn end(&self) -> Result<(), TableError> {
+        match self.0.is_empty() {
+            true => Ok(()),
+            false => Err(TableError::Truncated),
+        }
+    }
+}
+
+// The value of every unfinished position reachable on one board under one ruleset, keyed
+// by canonical hash so each set of symmetric positions is stored once
+//
+// Sealed as "TTTB" (see `seal`). The body, little endian: width u8, height u8, win length
+// u8, rules name length u8 and bytes, entry count u32, entries of hash u64 and value u8
+// sorted by hash
+pub struct Tablebase {
+    width: u8,
+    height: u8,
+    win_length: u8,
+    rules: String,
+    values: HashMap<u64, Value>,
+}
+
+impl Tablebase {
+    pub fn solve(
+        width: u8,
+        height: u8,
+        win_length: u8,
+        rules: &dyn Rules,
+    ) -> Result<Tablebase, TableError> {
+        if width as usize * height as usize > MAX_CELLS {
+            return Err(TableError::TooLarge);
+        }
+        let mut table = Tablebase {
+            width,
+            height,
+            win_length,
+            rules: rules.name().to_string(),
+            values: HashMap::new(),
+        };
+        table.search(&mut Position::new(width, height, win_length), rules);
+        Ok(table)
+    }
+
+    fn search(&mut self, position: &mut Position, rules: &dyn Rules) -> Value {
+        let (key, _) = position.canonical_hash();
+        if let Some(value) = self.values.get(&key) {
+            return *value;
+        }
+        let mover = position.turn().clone();
+        let mut best = Value::Draw;
+        for (i, index) in rules.legal_moves(position).into_iter().enumerate() {
+            rules.apply(position, index);
+            let value = match rules.outcome_after(position, index) {
+                Some(outcome) => Value::of_outcome(&outcome, rules, &mover),
+                None => self.search(position, rules).before(),
+            };
+            rules.undo(position, index);
+            if i == 0 || value.rank() > best.rank() {
+                best = value;
+            }
+        }
+        self.values.insert(key, best);
+        best
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    fn covers(&self, position: &Position, rules: &dyn Rules) -> bool {
+        (position.width(), position.height(), position.win_length())
+            == (self.width, self.height, self.win_length)
+            && rules.name() == self.rules
+    }
+
+    fn probe(&self, position: &Position) -> Option<Value> {
+        self.values.get(&position.canonical_hash().0).copied()
+    }
+
+    // The perfect move for the side to move and the value it keeps, preferring the quickest
+    // win or slowest loss. None when the table is for another board or ruleset
+    pub fn best_move(&self, position: &Position, rules: &dyn Rules) -> Option<(usize, Value)> {
+        if !self.covers(position, rules) || rules.outcome(position).is_some() {
+            return None;
+        }
+        let mover = position.turn().clone();
+        let mut position = position.clone();
+        let mut best: Option<(usize, Value)> = None;
+        for index in rules.legal_moves(&position) {
+            rules.apply(&mut position, index);
+            let value = match rules.outcome_after(&position, index) {
+                Some(outcome) => Some(Value::of_outcome(&outcome, rules, &mover)),
+                None => self.probe(&position).map(Value::before),
+            };
+            rules.undo(&mut position, index);
+            let value = value?;
+            if best.is_none_or(|(_, best)| value.rank() > best.rank()) {
+                best = Some((index, value));
+            }
+        }
+        best
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend([self.width, self.height, self.win_length]);
+        bytes.push(self.rules.len() as u8);
+        bytes.extend(self.rules.as_bytes());
+        bytes.extend((self.values.len() as u32).to_le_bytes());
+
+        let mut entries: Vec<(&u64, &Value)> = self.values.iter().collect();
+        entries.sort_unstable_by_key(|(key, _)| **key);
+        for (key, value) in entries {
+            bytes.extend(key.to_le_bytes());
+            bytes.push(value.encode());
+        }
+        seal(MAGIC, VERSION, &bytes)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Tablebase, TableError> {
+        let mut reader = unseal(bytes, MAGIC, VERSION)?;
+        let (width, height, win_length) = (reader.u8()?, reader.u8()?, reader.u8()?);
+        let name_length = reader.u8()? as usize;
+        let rules = String::from_utf8_lossy(reader.take(name_length)?).into_owned();
+        let count = reader.u32()? as usize;
+        let mut values = HashMap::with_capacity(count);
+        for _ in 0..count {
+            let key = reader.u64()?;
+            let byte = reader.u8()?;
+            values.insert(
+                key,
+                Value::decode(byte).ok_or(TableError::InvalidValue(byte))?,
+            );
+        }
+        reader.end()?;
+        Ok(Tablebase {
+            width,
+            height,
+            win_length,
+            rules,
+            values,
+        })
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), TableError> {
+        write_file(path, &self.to_bytes())
+    }
+
+    pub fn load(path: &Path) -> Result<Tablebase, TableError> {
+        Tablebase::from_bytes(&fs::read(path)?)
+    }
+}
+
+// Where `solve` writes a table by default and where the menu looks for one
+pub fn default_path(width: u8, height: u8, win_length: u8, rules: &dyn Rules) -> PathBuf {
+    PathBuf::from(format!(
+        "tables/{}x{}k{}-{}.ttb",
+        width,
+        height,
+        win_length,
+        rules.name()
+    ))
+}
+
+// The table for a board if one has been solved, logging why not otherwise
+pub fn find(width: u8, height: u8, win_length: u8, rules: &dyn Rules) -> Option<Tablebase> {
+    let path = default_path(width, height, win_length, rules);
+    open_or_default(
+        &path,
+        |path| Tablebase::load(path).map(Some),
+        |_| true,
+        || None,
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::rules::{Misere, Standard};
+
+    #[test]
+    fn solves_3x3() {
+        let table = Tablebase::solve(3, 3, 3, &Standard).unwrap();
+        // 765 distinct positions up to symmetry, minus the 138 finished ones
+        assert_eq!(table.len(), 765 - 138);
+        assert_eq!(table.probe(&Position::new(3, 3, 3)), Some(Value::Draw));
+
+        let position: Position = "xx./oo./x.. o k3".parse().unwrap();
+        assert_eq!(
+            table.best_move(&position, &Standard),
+            Some((5, Value::Win(1)))
+        );
+        let position: Position = "x../.o./..x o k3".parse().unwrap();
+        assert_eq!(table.probe(&position), Some(Value::Draw));
+        // the last corner threatens two lines at once
+        let position: Position = "x../.o./o.x x k3".parse().unwrap();
+        assert_eq!(
+            table.best_move(&position, &Standard),
+            Some((2, Value::Win(3)))
+        );
+
+        assert_eq!(table.best_move(&position, &Misere), None);
+        assert_eq!(
+            table.best_move(&"..../..../..../.... x k4".parse().unwrap(), &Standard),
+            None
+        );
+    }
+
+    #[test]
+    fn round_trips_through_bytes() {
+        let table = Tablebase::solve(3, 3, 3, &Misere).unwrap();
+        let bytes = table.to_bytes();
+        let loaded = Tablebase::from_bytes(&bytes).unwrap();
+        assert_eq!(loaded.len(), table.len());
+        assert_eq!(loaded.values, table.values);
+        assert_eq!(loaded.rules, "misere");
+        assert!(matches!(
+            Tablebase::solve(5, 5, 4, &Standard),
+            Err(TableError::TooLarge)
+        ));
+    }
+
+    #[test]
+    fn sealed_files_check_their_envelope() {
+        let bytes = seal(b"TEST", 3, &[1, 2, 3]);
+        let mut reader = unseal(&bytes, b"TEST", 3).unwrap();
+        assert_eq!(reader.take(3).unwrap(), [1, 2, 3]);
+        assert!(reader.end().is_ok());
+
+        let mut corrupted = bytes.clone();
+        corrupted[7] ^= 1;
+        assert!(matches!(
+            unseal(&corrupted, b"TEST", 3),
+            Err(TableError::ChecksumMismatch)
+        ));
+        assert!(matches!(
+            unseal(&bytes, b"TEST", 2),
+            Err(TableError::UnsupportedVersion(3))
+        ));
+        assert!(matches!(
+            unseal(&bytes[..5], b"TEST", 3),
+            Err(TableError::Truncated)
+        ));
+        assert!(matches!(
+            unseal(&bytes[..9], b"TEST", 3),
+            Err(TableError::Truncated)
+        ));
+        assert!(matches!(
+            unseal(&bytes, b"TTTB", 3),
+            Err(TableError::BadMagic)
+        ));
+        assert!(matches!(Reader(&[0]).end(), Err(TableError::Truncated)));
+
+        let path = Path::new("no/such/file");
+        let opened = open_or_default(path, |path| Ok(fs::read(path)?), |_| true, || vec![7]);
+        assert_eq!(opened, [7]);
+    }
+}
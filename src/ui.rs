@@ -1,31 +1,61 @@
+mod adaptive;
+mod agent;
+mod ai;
+mod arena;
+mod bitboard;
 mod game;
 mod logutil;
+mod menace;
+mod patterns;
+mod piskvork;
+mod position;
+mod profiles;
+mod protocol;
+mod review;
+mod rng;
+mod rules;
+mod symmetry;
+mod tablebase;
+mod zobrist;
 
+use agent::{EngineAgent, Human, Remote, Scripted, SharedAgent, THINK_TIME, shared};
+use ai::{Budget, Engine, Mcts, Minimax, SharedEngine, ThreatSpace, WORK_BACKSTOP};
 use crossterm::event::{self, *};
 use crossterm::style::{ContentStyle, PrintStyledContent, StyledContent, Stylize};
 use crossterm::terminal::{self, disable_raw_mode, enable_raw_mode};
 use crossterm::{ExecutableCommand, cursor};
 use game::*;
+use position::Position;
+use review::Review;
+use rules::{Misere, Rules, Standard};
 use std::io::{Stdout, Write, stdout};
 use std::panic::set_hook;
+use std::path::{Path, PathBuf};
 use std::process::exit;
 use std::str::FromStr;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tablebase::TableError;
 
 use self::logutil::log;
 
 #[derive(Clone)]
 enum ApplicationState {
     Menu(ApplicationMenu),
+    Setup(SetupMenu),
     Game(Game),
     Result(ResultMenu),
+    Review(Review),
 }
 
 impl ApplicationState {
     fn draw(&self, stdout: &mut Stdout) {
         match self {
             ApplicationState::Menu(menu) => menu.draw(stdout),
+            ApplicationState::Setup(setup) => setup.draw(stdout),
             ApplicationState::Result(result) => result.draw(stdout),
             ApplicationState::Game(game) => game.draw(stdout),
+            ApplicationState::Review(review) => review.draw(stdout),
         }
     }
 
@@ -33,16 +63,22 @@ impl ApplicationState {
     fn handleButtonLeft(&mut self) {
         match self {
             ApplicationState::Game(game) => game.handleButtonLeft(),
+            ApplicationState::Review(review) => review.handleButtonLeft(),
+            ApplicationState::Setup(setup) => setup.cycle(-1),
+            ApplicationState::Menu(menu) => menu.side = menu.side.previous(),
             _ => {}
-        };
+        }
     }
 
     #[allow(non_snake_case)]
     fn handleButtonRight(&mut self) {
         match self {
             ApplicationState::Game(game) => game.handleButtonRight(),
+            ApplicationState::Review(review) => review.handleButtonRight(),
+            ApplicationState::Setup(setup) => setup.cycle(1),
+            ApplicationState::Menu(menu) => menu.side = menu.side.next(),
             _ => {}
-        };
+        }
     }
 
     #[allow(non_snake_case)]
@@ -50,7 +86,9 @@ impl ApplicationState {
         match self {
             ApplicationState::Game(game) => game.handleButtonDown(),
             ApplicationState::Menu(menu) => menu.try_increment(),
+            ApplicationState::Setup(setup) => setup.try_increment(),
             ApplicationState::Result(result) => result.try_increment(),
+            ApplicationState::Review(_) => {}
         };
     }
 
@@ -59,32 +97,136 @@ impl ApplicationState {
         match self {
             ApplicationState::Game(game) => game.handleButtonUp(),
             ApplicationState::Menu(menu) => menu.try_decrement(),
+            ApplicationState::Setup(setup) => setup.try_decrement(),
             ApplicationState::Result(result) => result.try_decrement(),
+            ApplicationState::Review(_) => {}
         };
     }
 
+    // Esc leaves a game or the game setup for the menu, cancelling whoever is thinking, and a
+    // review for the result screen
+    #[allow(non_snake_case)]
+    fn handleAbort(&mut self) {
+        match self {
+            ApplicationState::Game(game) => game.abort(),
+            ApplicationState::Review(review) => return review.handleConfirm(),
+            ApplicationState::Setup(_) => {}
+            _ => return,
+        }
+        *self = ApplicationState::Menu(ApplicationMenu::new());
+    }
+
+    // Something is running in the background and the screen needs redrawing without input
+    fn is_busy(&self) -> bool {
+        match self {
+            ApplicationState::Game(game) => game.is_busy(),
+            ApplicationState::Review(review) => review.is_busy(),
+            _ => false,
+        }
+    }
+
+    #[allow(non_snake_case)]
+    fn handleOverlay(&mut self) {
+        if let ApplicationState::Game(game) = self {
+            game.handleOverlay()
+        }
+    }
+
+    #[allow(non_snake_case)]
+    fn handleHint(&mut self) {
+        if let ApplicationState::Game(game) = self {
+            game.handleHint()
+        }
+    }
+
+    #[allow(non_snake_case)]
+    fn handlePause(&mut self) {
+        if let ApplicationState::Game(game) = self {
+            game.handlePause()
+        }
+    }
+
+    #[allow(non_snake_case)]
+    fn handleStep(&mut self) {
+        if let ApplicationState::Game(game) = self {
+            game.handleStep()
+        }
+    }
+
+    #[allow(non_snake_case)]
+    fn handleSpeed(&mut self, faster: bool) {
+        if let ApplicationState::Game(game) = self {
+            game.handleSpeed(faster)
+        }
+    }
+
+    #[allow(non_snake_case)]
+    fn handleUndo(&mut self) {
+        if let ApplicationState::Game(game) = self {
+            game.handleUndo()
+        }
+    }
+
     #[allow(non_snake_case)]
     fn handleConfirm(&mut self) {
         match self {
             ApplicationState::Menu(menu) => menu.confirmed = true,
+            ApplicationState::Setup(setup) => setup.handleConfirm(),
             ApplicationState::Game(game) => game.handleConfirm(),
             ApplicationState::Result(result) => result.handleConfirm(),
+            ApplicationState::Review(review) => review.handleConfirm(),
         }
     }
 
     fn update(self) -> ApplicationState {
         match self {
             ApplicationState::Menu(menu) => menu.update(),
+            ApplicationState::Setup(setup) => setup.update(),
             ApplicationState::Game(game) => game.update(),
             ApplicationState::Result(result) => result.update(),
+            ApplicationState::Review(review) => review.update(),
         }
     }
 }
 
+fn time_seed() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0)
+}
+
+// The seed all randomness in this run comes from: `--seed <n>`, or the clock. It is logged
+// at startup and with any panic, so a session can be replayed
+fn session_seed() -> u64 {
+    static SEED: OnceLock<u64> = OnceLock::new();
+    *SEED.get_or_init(|| {
+        cli_arg("--seed")
+            .and_then(|seed| seed.parse().ok())
+            .unwrap_or_else(time_seed)
+    })
+}
+
+// A seed for the next engine or coin flip, drawn in turn from the session seed, so the same
+// seed and the same inputs make the same choices
+fn next_seed() -> u64 {
+    static SEEDS: OnceLock<Mutex<rng::Rng>> = OnceLock::new();
+    SEEDS
+        .get_or_init(|| Mutex::new(rng::Rng::new(session_seed())))
+        .lock()
+        .unwrap()
+        .next_u64()
+}
+
+// A menu entry and the screen it opens, given the side the human takes against the AI
+type MenuEntry = (String, fn(Side) -> ApplicationState);
+
 #[derive(Clone)]
 struct ApplicationMenu {
     selected: usize,
-    selection: Vec<(String, fn() -> ApplicationState)>,
+    selection: Vec<MenuEntry>,
+    // The side the human takes in games against the AI
+    side: Side,
     confirmed: bool,
 }
 
@@ -93,18 +235,106 @@ impl ApplicationMenu {
         ApplicationMenu {
             selected: 0,
             selection: vec![
-                ("Play with AI".to_string(), || {
-                    ApplicationState::Game(Game::new(3, true))
+                ("Play with AI".to_string(), |side| {
+                    let minimax = Minimax::new(u32::MAX, Duration::from_secs(1))
+                        .with_fixed_work(fixed_work())
+                        .with_tablebase(tablebase::find(3, 3, 3, &Standard));
+                    ApplicationState::Game(Game::new(
+                        3,
+                        Arc::new(Standard),
+                        versus(side, engine(minimax)),
+                    ))
+                }),
+                ("Play with AI (4x4)".to_string(), |side| {
+                    let minimax = Minimax::new(u32::MAX, Duration::from_secs(1))
+                        .with_fixed_work(fixed_work())
+                        .with_tablebase(tablebase::find(4, 4, 4, &Standard));
+                    ApplicationState::Game(Game::new(
+                        4,
+                        Arc::new(Standard),
+                        versus(side, engine(minimax)),
+                    ))
+                }),
+                ("Play with AI (6x6, 4 in a row)".to_string(), |side| {
+                    let minimax = Minimax::new(u32::MAX, Duration::from_secs(1))
+                        .with_fixed_work(fixed_work())
+                        .with_weights(patterns::find(4, &Standard))
+                        .with_threads(search_threads());
+                    let ai = engine(minimax);
+                    let position = Position::new(6, 6, 4);
+                    ApplicationState::Game(Game::from_position(
+                        position,
+                        Arc::new(Standard),
+                        versus(side, ai),
+                    ))
+                }),
+                ("Play with AI (MCTS)".to_string(), |side| {
+                    let mcts = Mcts::new(Budget::Iterations(5000), next_seed());
+                    ApplicationState::Game(Game::new(
+                        3,
+                        Arc::new(Standard),
+                        versus(side, engine(mcts)),
+                    ))
+                }),
+                ("Play with AI (MCTS, 15x15)".to_string(), |side| {
+                    let mcts = Mcts::new(Budget::Time(Duration::from_secs(1)), next_seed())
+                        .with_fixed_work(fixed_work())
+                        .with_weights(patterns::find(5, &Standard))
+                        .with_threads(search_threads());
+                    let ai = engine(mcts);
+                    let position = Position::new(15, 15, 5);
+                    ApplicationState::Game(Game::from_position(
+                        position,
+                        Arc::new(Standard),
+                        versus(side, ai),
+                    ))
+                }),
+                ("Play with AI (threats, 15x15)".to_string(), |side| {
+                    let minimax = Minimax::new(u32::MAX, Duration::from_secs(1))
+                        .with_fixed_work(fixed_work())
+                        .with_weights(patterns::find(5, &Standard))
+                        .with_threads(search_threads());
+                    let threats =
+                        ThreatSpace::new(8, Duration::from_millis(300), Box::new(minimax))
+                            .with_fixed_work(fixed_work());
+                    let ai = engine(threats);
+                    let position = Position::new(15, 15, 5);
+                    ApplicationState::Game(Game::from_position(
+                        position,
+                        Arc::new(Standard),
+                        versus(side, ai),
+                    ))
+                }),
+                ("Play with AI (learning)".to_string(), |side| {
+                    let ai = learner(3, 3, 3, &Standard);
+                    ApplicationState::Game(Game::new(3, Arc::new(Standard), versus(side, ai)))
                 }),
-                ("Play locally".to_string(), || {
-                    ApplicationState::Game(Game::new(3, false))
+                ("Play with AI (adaptive)".to_string(), |side| {
+                    let ai = adaptive(3, 3, 3, &Standard);
+                    ApplicationState::Game(Game::new(3, Arc::new(Standard), versus(side, ai)))
                 }),
-                ("Exit".to_string(), || {
+                ("Play locally".to_string(), |_| {
+                    ApplicationState::Game(Game::new(3, Arc::new(Standard), [human(), human()]))
+                }),
+                ("Play misere locally".to_string(), |_| {
+                    ApplicationState::Game(Game::new(3, Arc::new(Misere), [human(), human()]))
+                }),
+                ("Play against a personality".to_string(), |side| {
+                    ApplicationState::Setup(SetupMenu::personality(side))
+                }),
+                ("Watch AI vs AI".to_string(), |_| {
+                    ApplicationState::Setup(SetupMenu::spectator())
+                }),
+                ("Custom game".to_string(), |_| {
+                    ApplicationState::Setup(SetupMenu::new())
+                }),
+                ("Exit".to_string(), |_| {
                     stdout().execute(cursor::Show).ok();
                     disable_raw_mode().ok();
                     exit(0);
                 }),
             ],
+            side: Side::X,
             confirmed: false,
         }
     }
@@ -126,7 +356,22 @@ impl ApplicationMenu {
 
     fn draw(&self, stdout: &mut Stdout) {
         let (w, h) = terminal::size().expect("Failed to retrieve size");
-        for (index, (option, _)) in self.selection.iter().enumerate() {
+        // from the middle of the screen down if the entries fit, otherwise higher up and
+        // scrolled to keep the selection in view
+        let rows = (h as usize).saturating_sub(2).max(1);
+        let first = self.selected.saturating_sub(rows - 1);
+        let top = (h / 2).min(h.saturating_sub(self.selection.len().min(rows) as u16));
+        let side = format!("< Against the AI you play {} >", self.side);
+        stdout
+            .execute(cursor::MoveTo(
+                (w / 2).saturating_sub(side.len() as u16 / 2),
+                top.saturating_sub(2),
+            ))
+            .expect("err")
+            .execute(PrintStyledContent(side.dark_grey()))
+            .ok();
+        let shown = self.selection.iter().enumerate().skip(first).take(rows);
+        for (row, (index, (option, _))) in shown.enumerate() {
             let content_length = option.len() as u16;
             let mut content = StyledContent::new(ContentStyle::new(), option);
             if index == self.selected {
@@ -134,8 +379,8 @@ impl ApplicationMenu {
             }
             stdout
                 .execute(cursor::MoveTo(
-                    w / 2 - content_length / 2,
-                    h / 2 + index as u16,
+                    (w / 2).saturating_sub(content_length / 2),
+                    top + row as u16,
                 ))
                 .expect("err")
                 .execute(PrintStyledContent(content))
@@ -150,25 +395,341 @@ impl ApplicationMenu {
             return ApplicationState::Menu(self);
         }
 
-        self.selection.get(self.selected).unwrap().1()
+        self.selection.get(self.selected).unwrap().1(self.side)
+    }
+}
+
+fn human() -> SharedAgent {
+    shared(Human::default())
+}
+
+// A human and `ai`, seated as the menu's side setting says
+fn versus(side: Side, ai: SharedAgent) -> [SharedAgent; 2] {
+    side.seat(human(), ai, &mut rng::Rng::new(next_seed()))
+}
+
+fn engine(engine: impl Engine + 'static) -> SharedAgent {
+    let engine: SharedEngine = Arc::new(Mutex::new(engine));
+    shared(EngineAgent::new(engine, think_time(THINK_TIME)))
+}
+
+// A MENACE player that keeps what it learns in the board's default file
+fn learner(width: u8, height: u8, win_length: u8, rules: &dyn Rules) -> SharedAgent {
+    let menace = menace::shared(width, height, win_length, rules, next_seed());
+    let path = menace::default_path(width, height, win_length, rules);
+    shared(menace::Learner::new(menace, Some(path)))
+}
+
+// An opponent that gets stronger or weaker with the human's results on the board, kept in
+// the board's default file between sessions
+fn adaptive(width: u8, height: u8, win_length: u8, rules: &dyn Rules) -> SharedAgent {
+    let path = adaptive::default_path(width, height, win_length, rules);
+    let record = adaptive::open(&path, width, height, win_length, rules);
+    shared(adaptive::Opponent::new(
+        record,
+        Some(path),
+        next_seed(),
+        fixed_work(),
+    ))
+}
+
+// Threads for the AI's searches on big boards: `--threads <n>`, by default one, which keeps
+// searches reproducible for a seed
+fn search_threads() -> usize {
+    cli_arg("--threads")
+        .and_then(|threads| threads.parse().ok())
+        .unwrap_or(1)
+}
+
+// Whether the AI's searches stop after a set amount of work rather than at their time
+// limits. A clock stops them at a different point on every run, so with `--seed` they do,
+// and a replayed session gets the same moves
+fn fixed_work() -> bool {
+    cli_arg("--seed").is_some()
+}
+
+// How long an agent may think on a move its engine is meant to spend `time` on
+fn think_time(time: Duration) -> Duration {
+    match fixed_work() {
+        true => time * WORK_BACKSTOP,
+        false => time,
+    }
+}
+
+// The value following `name` on the command line
+fn cli_arg(name: &str) -> Option<String> {
+    let args: Vec<String> = std::env::args().collect();
+    let i = args.iter().position(|a| a == name)?;
+    args.get(i + 1).cloned()
+}
+
+// Who can sit on either side of a custom game
+const PLAYER_KINDS: [&str; 9] = [
+    "Human",
+    "Minimax",
+    "MCTS",
+    "Threat space",
+    "Learning",
+    "External",
+    "Piskvork",
+    "Remote",
+    "Scripted",
+];
+const BOARDS: [(u8, u8); 4] = [(3, 3), (4, 4), (6, 4), (15, 5)];
+const SETUP_ROWS: usize = 7;
+
+// Picks any two players, a board and the rules for a game
+#[derive(Clone)]
+struct SetupMenu {
+    // Indexes into PLAYER_KINDS, followed by the AI profiles
+    players: [usize; 2],
+    profiles: Vec<profiles::Profile>,
+    board: usize,
+    misere: bool,
+    delay: usize,
+    selected: usize,
+    confirmed: bool,
+}
+
+impl SetupMenu {
+    fn new() -> SetupMenu {
+        // `--profiles <path>` for opponents other than those in profiles::DEFAULT_PATH
+        let path = cli_arg("--profiles").unwrap_or(profiles::DEFAULT_PATH.to_string());
+        SetupMenu {
+            players: [0, 1],
+            profiles: profiles::load(Path::new(&path)),
+            board: 0,
+            misere: false,
+            delay: 3,
+            selected: 0,
+            confirmed: false,
+        }
+    }
+
+    // Two engines against each other, to watch
+    fn spectator() -> SetupMenu {
+        SetupMenu {
+            players: [1, 2],
+            ..SetupMenu::new()
+        }
+    }
+
+    // A human against the first AI profile, seated as `side` says
+    fn personality(side: Side) -> SetupMenu {
+        let menu = SetupMenu::new();
+        let [x, o] = side.seat(0, PLAYER_KINDS.len(), &mut rng::Rng::new(next_seed()));
+        SetupMenu {
+            players: [x, o],
+            ..menu
+        }
+    }
+
+    fn kind(&self, kind: usize) -> String {
+        match PLAYER_KINDS.get(kind) {
+            Some(name) => name.to_string(),
+            None => self.profiles[kind - PLAYER_KINDS.len()].to_string(),
+        }
+    }
+
+    fn rules(&self) -> Arc<dyn Rules> {
+        if self.misere {
+            Arc::new(Misere)
+        } else {
+            Arc::new(Standard)
+        }
+    }
+
+    // Why the game as set up cannot start, if it cannot
+    fn problem(&self) -> Option<&'static str> {
+        let (_, k) = BOARDS[self.board];
+        let piskvork = self
+            .players
+            .iter()
+            .any(|kind| PLAYER_KINDS.get(*kind) == Some(&"Piskvork"));
+        (piskvork && !piskvork::plays(k, self.rules().as_ref()))
+            .then_some("Piskvork brains only play five in a row under standard rules")
+    }
+
+    fn rows(&self) -> [String; SETUP_ROWS] {
+        let (size, k) = BOARDS[self.board];
+        [
+            format!("X: {}", self.kind(self.players[0])),
+            format!("O: {}", self.kind(self.players[1])),
+            format!("Board: {0}x{0}, {1} in a row", size, k),
+            format!("Rules: {}", if self.misere { "misere" } else { "standard" }),
+            format!("Move delay: {} ms", MOVE_DELAYS[self.delay].as_millis()),
+            "Start".to_string(),
+            "Back".to_string(),
+        ]
+    }
+
+    // Steps the option on the selected row back or forward
+    fn cycle(&mut self, step: isize) {
+        let turn = |value: usize, count: usize| {
+            (value as isize + step).rem_euclid(count as isize) as usize
+        };
+        match self.selected {
+            0 | 1 => {
+                let side = &mut self.players[self.selected];
+                *side = turn(*side, PLAYER_KINDS.len() + self.profiles.len());
+            }
+            2 => self.board = turn(self.board, BOARDS.len()),
+            3 => self.misere = !self.misere,
+            4 => self.delay = turn(self.delay, MOVE_DELAYS.len()),
+            _ => {}
+        }
+    }
+
+    fn try_increment(&mut self) {
+        self.selected = (self.selected + 1).min(SETUP_ROWS - 1);
+    }
+
+    fn try_decrement(&mut self) {
+        self.selected = self.selected.saturating_sub(1);
+    }
+
+    #[allow(non_snake_case)]
+    fn handleConfirm(&mut self) {
+        match self.selected {
+            5 | 6 => self.confirmed = true,
+            _ => self.cycle(1),
+        }
+    }
+
+    fn draw(&self, stdout: &mut Stdout) {
+        let (w, h) = terminal::size().expect("Failed to retrieve size");
+        for (index, row) in self.rows().iter().enumerate() {
+            let option = if index == self.selected && index < 5 {
+                format!("< {} >", row)
+            } else {
+                row.clone()
+            };
+            let mut content = StyledContent::new(ContentStyle::new(), option.as_str());
+            if index == self.selected {
+                content = content.black().on_white();
+            }
+            stdout
+                .execute(cursor::MoveTo(
+                    (w / 2).saturating_sub(option.len() as u16 / 2),
+                    h / 2 + index as u16,
+                ))
+                .expect("err")
+                .execute(PrintStyledContent(content))
+                .ok();
+        }
+        if let Some(problem) = self.problem() {
+            stdout
+                .execute(cursor::MoveTo(
+                    (w / 2).saturating_sub(problem.len() as u16 / 2),
+                    h / 2 + SETUP_ROWS as u16 + 1,
+                ))
+                .expect("err")
+                .execute(PrintStyledContent(problem.red()))
+                .ok();
+        }
+        stdout.flush().ok();
+    }
+
+    fn agent(&self, kind: usize, rules: &dyn Rules) -> SharedAgent {
+        let (size, k) = BOARDS[self.board];
+        let Some(kind) = PLAYER_KINDS.get(kind) else {
+            let profile = &self.profiles[kind - PLAYER_KINDS.len()];
+            let engine = profile.build(k, rules, next_seed(), search_threads(), fixed_work());
+            return shared(EngineAgent::new(engine, think_time(profile.time)));
+        };
+        let minimax = || {
+            Minimax::new(u32::MAX, THINK_TIME)
+                .with_fixed_work(fixed_work())
+                .with_tablebase(tablebase::find(size, size, k, rules))
+                .with_weights(patterns::find(k, rules))
+                .with_threads(search_threads())
+        };
+        match *kind {
+            "Minimax" => engine(minimax()),
+            "MCTS" => engine(
+                Mcts::new(Budget::Time(THINK_TIME), next_seed())
+                    .with_fixed_work(fixed_work())
+                    .with_weights(patterns::find(k, rules))
+                    .with_threads(search_threads()),
+            ),
+            "Threat space" => engine(
+                ThreatSpace::new(8, Duration::from_millis(300), Box::new(minimax()))
+                    .with_fixed_work(fixed_work()),
+            ),
+            "Learning" => learner(size, size, k, rules),
+            // `--engine "<program> <args>"`, see protocol.rs
+            "External" => engine(protocol::External::new(
+                cli_arg("--engine").unwrap_or("./engine".to_string()),
+                THINK_TIME,
+            )),
+            // `--piskvork "<program> <args>"`, a Gomocup brain for boards of five in a row
+            "Piskvork" => engine(piskvork::Piskvork::new(
+                cli_arg("--piskvork").unwrap_or("./pbrain".to_string()),
+                THINK_TIME,
+            )),
+            // `--remote <host:port>`, see agent::Remote for the protocol. Remote players may be
+            // people, so they get longer than engines
+            "Remote" => shared(Remote::new(
+                cli_arg("--remote").unwrap_or("127.0.0.1:7878".to_string()),
+                Duration::from_secs(60),
+            )),
+            // `--script "x,y x,y ..."`, the same moves for whichever side is scripted
+            "Scripted" => {
+                let moves = cli_arg("--script")
+                    .unwrap_or_default()
+                    .split_whitespace()
+                    .filter_map(|cell| {
+                        let (x, y) = cell.split_once(',')?;
+                        let (x, y) = (x.parse::<u8>().ok()?, y.parse::<u8>().ok()?);
+                        (x < size && y < size).then_some(y as usize * size as usize + x as usize)
+                    })
+                    .collect();
+                shared(Scripted::new(moves))
+            }
+            _ => human(),
+        }
+    }
+
+    fn update(mut self) -> ApplicationState {
+        if !self.confirmed {
+            return ApplicationState::Setup(self);
+        }
+        if self.selected == 6 {
+            return ApplicationState::Menu(ApplicationMenu::new());
+        }
+        // the problem is already on screen, so Start just does nothing
+        if self.problem().is_some() {
+            self.confirmed = false;
+            return ApplicationState::Setup(self);
+        }
+        let rules = self.rules();
+        let players = self.players.map(|kind| self.agent(kind, rules.as_ref()));
+        let (size, k) = BOARDS[self.board];
+        ApplicationState::Game(
+            Game::from_position(Position::new(size, size, k), rules, players)
+                .with_move_delay(MOVE_DELAYS[self.delay]),
+        )
     }
 }
 
 #[derive(Clone)]
 struct ResultMenu {
     win: Player,
+    game: Game,
     selection: Vec<String>,
     selected: usize,
     confirm: bool,
 }
 
 impl ResultMenu {
-    fn new(win: Player) -> ResultMenu {
+    fn new(win: Player, game: Game) -> ResultMenu {
         ResultMenu {
             win,
+            game,
             selection: vec![
                 String::from_str("Return to Menu").unwrap(),
                 String::from_str("Restart").unwrap(),
+                String::from_str("Analyze").unwrap(),
             ],
             selected: 0,
             confirm: false,
@@ -193,12 +754,38 @@ impl ResultMenu {
             .unwrap()
             .execute(PrintStyledContent(winner_text))
             .ok();
+
+        // so games won with help can be told apart
+        let hints = match self.game.hints() {
+            0 => "No hints used".to_string(),
+            n => format!("Hints used: {}", n),
+        };
+        stdout
+            .execute(cursor::MoveTo(
+                (w / 2).saturating_sub(hints.len() as u16 / 2),
+                h / 2 + 1,
+            ))
+            .unwrap()
+            .execute(PrintStyledContent(hints.dark_grey()))
+            .ok();
+        // such as the adaptive opponent's new level
+        let statuses = self.game.statuses();
+        for (row, status) in statuses.iter().enumerate() {
+            stdout
+                .execute(cursor::MoveTo(
+                    (w / 2).saturating_sub(status.len() as u16 / 2),
+                    h / 2 + 2 + row as u16,
+                ))
+                .unwrap()
+                .execute(PrintStyledContent(status.clone().dark_grey()))
+                .ok();
+        }
         for (index, content) in self.selection.iter().enumerate() {
             let content_length = content.len() as u16;
             stdout
                 .execute(cursor::MoveTo(
                     w / 2 - content_length / 2,
-                    h / 2 + index as u16 + 2,
+                    h / 2 + index as u16 + 2 + statuses.len() as u16,
                 ))
                 .expect("err")
                 .execute(PrintStyledContent(if index == self.selected {
@@ -216,7 +803,8 @@ impl ResultMenu {
         }
         match self.selection[self.selected].as_str() {
             "Return to Menu" => ApplicationState::Menu(ApplicationMenu::new()),
-            "Restart" => ApplicationState::Game(Game::new(3, false)),
+            "Restart" => ApplicationState::Game(self.game.restart()),
+            "Analyze" => ApplicationState::Review(Review::new(self.win.clone(), self.game.clone())),
             _ => unimplemented!(),
         }
     }
@@ -242,25 +830,366 @@ impl ResultMenu {
     }
 }
 
+// The arguments of the subcommands that work on one board: `<width> <height> <win length>`
+// first, then `--misere`, `--out <path>` and numeric options in any order
+struct BoardArgs<'a> {
+    args: &'a [String],
+    usage: &'static str,
+    width: u8,
+    height: u8,
+    win_length: u8,
+    rules: Box<dyn Rules>,
+}
+
+impl<'a> BoardArgs<'a> {
+    // Exits with `usage` unless the board is given and can be played
+    fn parse(args: &'a [String], usage: &'static str) -> BoardArgs<'a> {
+        let fail = || -> ! {
+            eprintln!("usage: {}", usage);
+            exit(2);
+        };
+        let numbers: Vec<u8> = args
+            .iter()
+            .take(3)
+            .map(|a| a.parse().unwrap_or_else(|_| fail()))
+            .collect();
+        let [width, height, win_length] = numbers[..] else {
+            fail()
+        };
+        if !position::playable(width, height, win_length) {
+            fail();
+        }
+        let rules: Box<dyn Rules> = if args.iter().any(|a| a == "--misere") {
+            Box::new(Misere)
+        } else {
+            Box::new(Standard)
+        };
+        BoardArgs {
+            args,
+            usage,
+            width,
+            height,
+            win_length,
+            rules,
+        }
+    }
+
+    fn fail(&self) -> ! {
+        eprintln!("usage: {}", self.usage);
+        exit(2);
+    }
+
+    // The number following `name`, if it is there
+    fn option(&self, name: &str) -> Option<u64> {
+        let i = self.args.iter().position(|a| a == name)?;
+        Some(
+            self.args
+                .get(i + 1)?
+                .parse()
+                .unwrap_or_else(|_| self.fail()),
+        )
+    }
+
+    // `--out <path>`, or `default` without it
+    fn out(&self, default: PathBuf) -> PathBuf {
+        match self.args.iter().position(|a| a == "--out") {
+            Some(i) => self
+                .args
+                .get(i + 1)
+                .map(Into::into)
+                .unwrap_or_else(|| self.fail()),
+            None => default,
+        }
+    }
+}
+
+// Plays `games` in tenths so the trend is visible, printing what `play` reports for each
+fn in_tenths(games: u32, mut play: impl FnMut(u32) -> String) {
+    let batch = games.div_ceil(10).max(1);
+    let mut played = 0;
+    while played < games {
+        let count = batch.min(games - played);
+        let report = play(count);
+        played += count;
+        println!("{:>8} games: {}", played, report);
+    }
+}
+
+// Exits once a subcommand's output is saved, with `done` on success
+fn exit_saved(saved: Result<(), TableError>, path: &Path, done: impl FnOnce() -> String) -> ! {
+    match saved {
+        Ok(()) => {
+            println!("{}", done());
+            exit(0);
+        }
+        Err(e) => {
+            eprintln!("Could not write {}: {}", path.display(), e);
+            exit(1);
+        }
+    }
+}
+
+// `solve <width> <height> <win length> [--misere] [--out <path>]` writes the solution table
+// for a small board, by default to where the menu looks for it
+fn solve(args: &[String]) -> ! {
+    let board = BoardArgs::parse(
+        args,
+        "solve <width> <height> <win length> [--misere] [--out <path>]",
+    );
+    let (width, height, win_length) = (board.width, board.height, board.win_length);
+    let rules = board.rules.as_ref();
+    let path = board.out(tablebase::default_path(width, height, win_length, rules));
+
+    let started = std::time::Instant::now();
+    let table = match tablebase::Tablebase::solve(width, height, win_length, rules) {
+        Ok(table) => table,
+        Err(e) => {
+            eprintln!("Could not solve {}x{}: {}", width, height, e);
+            exit(1);
+        }
+    };
+    exit_saved(table.save(&path), &path, || {
+        format!(
+            "Solved {} positions in {:.1}s, written to {}",
+            table.len(),
+            started.elapsed().as_secs_f32(),
+            path.display()
+        )
+    })
+}
+
+// `train <width> <height> <win length> [--misere] [--games <n>] [--seed <n>] [--out <path>]`
+// has MENACE play itself and adds what it learns to the boxes learning players use
+fn train(args: &[String]) -> ! {
+    let board = BoardArgs::parse(
+        args,
+        "train <width> <height> <win length> [--misere] [--games <n>] [--seed <n>] \
+         [--out <path>]",
+    );
+    let (width, height, win_length) = (board.width, board.height, board.win_length);
+    let rules = board.rules.as_ref();
+    let games = board.option("--games").unwrap_or(10_000) as u32;
+    // checked here, but taken from the session like all other randomness
+    board.option("--seed");
+    let seed = session_seed();
+    let path = board.out(menace::default_path(width, height, win_length, rules));
+
+    let mut menace = menace::open(&path, width, height, win_length, rules, seed);
+    println!("Training with seed {}", seed);
+    let started = std::time::Instant::now();
+    in_tenths(games, |count| {
+        let [x, draws, o] = menace.train(count, rules);
+        format!("X won {}, drawn {}, O won {}", x, draws, o)
+    });
+    exit_saved(menace.save(&path), &path, || {
+        format!(
+            "Trained {} boxes in {:.1}s, written to {}",
+            menace.len(),
+            started.elapsed().as_secs_f32(),
+            path.display()
+        )
+    })
+}
+
+// `train-eval <width> <height> <win length> [--misere] [--games <n>] [--seed <n>]
+// [--out <path>]` trains the pattern weights for a win length by self-play on a board of
+// the given size. Runs with the same seed and starting weights train the same weights
+fn train_eval(args: &[String]) -> ! {
+    let board = BoardArgs::parse(
+        args,
+        "train-eval <width> <height> <win length> [--misere] [--games <n>] [--seed <n>] \
+         [--out <path>]",
+    );
+    let (width, height, win_length) = (board.width, board.height, board.win_length);
+    let rules = board.rules.as_ref();
+    let games = board.option("--games").unwrap_or(1000) as u32;
+    board.option("--seed");
+    let seed = session_seed();
+    let path = board.out(patterns::default_path(win_length, rules));
+
+    // carry on from earlier training when there is any
+    let mut weights = match patterns::Weights::load(&path) {
+        Ok(weights) if weights.covers(&Position::new(width, height, win_length), rules) => {
+            println!("Continuing from {}", path.display());
+            weights
+        }
+        _ => patterns::Weights::new(win_length, rules),
+    };
+    println!("Training with seed {}", seed);
+    let mut rng = rng::Rng::new(seed);
+    let started = std::time::Instant::now();
+    in_tenths(games, |count| {
+        let ([x, draws, o], error) = weights.train(width, height, count, rules, &mut rng);
+        format!(
+            "X won {}, drawn {}, O won {}, TD error {:.4}, {:.1}s",
+            x,
+            draws,
+            o,
+            error,
+            started.elapsed().as_secs_f32()
+        )
+    });
+    exit_saved(weights.save(&path), &path, || {
+        format!("Weights written to {}", path.display())
+    })
+}
+
+// `engine [<engine>] [--seed <n>] [--threads <n>]` serves a built-in engine over the
+// protocol in protocol.rs on stdin and stdout, so other programs can play against it.
+// Engines are written as for `arena`
+fn serve_engine(args: &[String]) -> ! {
+    let spec = args
+        .first()
+        .filter(|a| !a.starts_with("--"))
+        .map(String::as_str)
+        .unwrap_or("minimax");
+    let spec: arena::EngineSpec = spec.parse().unwrap_or_else(|e| {
+        eprintln!(
+            "{}\nusage: engine [minimax|mcts|threats] [--seed <n>] [--threads <n>]",
+            e
+        );
+        exit(2);
+    });
+    let spec = spec.with_threads(search_threads());
+    log(
+        logutil::LogStatus::INFO,
+        format!(
+            "Serving {} over the engine protocol with seed {}",
+            spec,
+            session_seed()
+        )
+        .as_str(),
+    );
+    let mut engine = protocol::Timed::new(spec, session_seed());
+    let stdin = std::io::stdin();
+    match protocol::serve(&mut engine, stdin.lock(), stdout()) {
+        Ok(()) => exit(0),
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(1);
+        }
+    }
+}
+
+// `piskvork [<engine>] [--seed <n>] [--threads <n>]` plays as a Gomocup brain over the
+// Piskvork protocol on stdin and stdout, by default with the threat space engine
+fn serve_piskvork(args: &[String]) -> ! {
+    let spec = args
+        .first()
+        .filter(|a| !a.starts_with("--"))
+        .map(String::as_str)
+        .unwrap_or("threats");
+    let spec: arena::EngineSpec = spec.parse().unwrap_or_else(|e| {
+        eprintln!(
+            "{}\nusage: piskvork [minimax|mcts|threats] [--seed <n>] [--threads <n>]",
+            e
+        );
+        exit(2);
+    });
+    let spec = spec.with_threads(search_threads());
+    log(
+        logutil::LogStatus::INFO,
+        format!(
+            "Serving {} as a Piskvork brain with seed {}",
+            spec,
+            session_seed()
+        )
+        .as_str(),
+    );
+    let mut engine = protocol::Timed::new(spec, session_seed());
+    let stdin = std::io::stdin();
+    match piskvork::serve(&mut engine, stdin.lock(), stdout()) {
+        Ok(()) => exit(0),
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(1);
+        }
+    }
+}
+
+// `arena <engine> <engine>... [options]` plays engines against each other without the TUI
+// and prints their results; see arena::USAGE
+fn run_arena(args: &[String]) -> ! {
+    let (specs, settings) = match arena::Settings::from_args(args) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            eprintln!("{}\n{}", e, arena::USAGE);
+            exit(2);
+        }
+    };
+    let pairings = arena::run(&specs, &settings);
+    println!("{}", arena::report(&specs, &pairings));
+    if let Err(e) = std::fs::write(
+        settings.csv_path(),
+        arena::csv(&specs, &settings, &pairings),
+    ) {
+        eprintln!("Could not write {}: {}", settings.csv_path(), e);
+        exit(1);
+    }
+    println!("Results written to {}", settings.csv_path());
+    exit(0);
+}
+
 #[allow(non_snake_case)]
 fn main() {
     set_hook(Box::new(|p| {
         stdout().execute(cursor::Show).ok();
         disable_raw_mode().ok();
 
-        log(
-            logutil::LogStatus::FATAL,
-            format!(
-                "Paniced at line {} of {}, {}",
-                p.location().unwrap().line(),
-                p.location().unwrap().file(),
-                p.payload().downcast_ref::<&str>().unwrap()
-            )
-            .as_str(),
+        let message = format!(
+            "Paniced at line {} of {}, {} (seed {})",
+            p.location().unwrap().line(),
+            p.location().unwrap().file(),
+            p.payload()
+                .downcast_ref::<&str>()
+                .copied()
+                .or_else(|| p.payload().downcast_ref::<String>().map(String::as_str))
+                .unwrap_or("unknown cause"),
+            session_seed()
         );
+        log(logutil::LogStatus::FATAL, message.as_str());
+        // the terminal is back to normal, so commands don't just exit without a word
+        eprintln!("{}", message);
     }));
 
-    let mut applicationState = ApplicationState::Menu(ApplicationMenu::new());
+    let args: Vec<String> = std::env::args().collect();
+    match args.get(1).map(String::as_str) {
+        Some("solve") => solve(&args[2..]),
+        Some("arena") => run_arena(&args[2..]),
+        Some("train") => train(&args[2..]),
+        Some("train-eval") => train_eval(&args[2..]),
+        Some("engine") => serve_engine(&args[2..]),
+        Some("piskvork") => serve_piskvork(&args[2..]),
+        _ => {}
+    }
+    // only seeded sessions bound their searches by work, so only they replay move for move
+    let replay = match fixed_work() {
+        true => "replays it",
+        false => "repeats its random choices, but its searches were timed",
+    };
+    log(
+        logutil::LogStatus::INFO,
+        format!("Session seed {} (--seed {0} {})", session_seed(), replay).as_str(),
+    );
+
+    // `--position <notation>` jumps straight into a game from a pasted board state
+    let mut applicationState = match args.iter().position(|a| a == "--position") {
+        Some(i) => {
+            let notation = args.get(i + 1).map(String::as_str).unwrap_or("");
+            match notation.parse::<Position>() {
+                Ok(position) => ApplicationState::Game(Game::from_position(
+                    position,
+                    Arc::new(Standard),
+                    [human(), human()],
+                )),
+                Err(e) => {
+                    eprintln!("Invalid position {:?}: {}", notation, e);
+                    exit(2);
+                }
+            }
+        }
+        None => ApplicationState::Menu(ApplicationMenu::new()),
+    };
     let mut stdout = stdout();
     enable_raw_mode().ok();
     stdout
@@ -271,6 +1200,19 @@ fn main() {
     applicationState.draw(&mut stdout);
 
     loop {
+        // poll instead of blocking so a thinking AI can be redrawn and picked up when done
+        if !event::poll(Duration::from_millis(100)).unwrap_or(false) {
+            if applicationState.is_busy() {
+                applicationState = applicationState.update();
+                stdout
+                    .execute(terminal::Clear(terminal::ClearType::All))
+                    .ok();
+                applicationState.draw(&mut stdout);
+                stdout.flush().ok();
+            }
+            continue;
+        }
+
         let KeyEvent {
             code,
             modifiers,
@@ -295,9 +1237,20 @@ fn main() {
                 applicationState.handleButtonLeft()
             }
             (KeyCode::Enter, KeyModifiers::NONE) => applicationState.handleConfirm(),
+            (KeyCode::Char('u'), KeyModifiers::NONE) => applicationState.handleUndo(),
+            (KeyCode::Char('h'), KeyModifiers::NONE) => applicationState.handleHint(),
+            (KeyCode::Char('v'), KeyModifiers::NONE) => applicationState.handleOverlay(),
+            (KeyCode::Char(' '), KeyModifiers::NONE) => applicationState.handlePause(),
+            (KeyCode::Char('n'), KeyModifiers::NONE) => applicationState.handleStep(),
+            (KeyCode::Char('+'), _) => applicationState.handleSpeed(true),
+            (KeyCode::Char('-'), KeyModifiers::NONE) => applicationState.handleSpeed(false),
+            (KeyCode::Esc, KeyModifiers::NONE) => applicationState.handleAbort(),
             (KeyCode::Char('c'), KeyModifiers::CONTROL) => {
+                if let ApplicationState::Game(game) = &mut applicationState {
+                    game.abort();
+                }
                 disable_raw_mode().ok();
-                stdout.execute(cursor::Hide).ok();
+                stdout.execute(cursor::Show).ok();
                 exit(0);
             }
 
@@ -0,0 +1,51 @@
+use std::sync::OnceLock;
+
+use crate::game::Player;
+use crate::position::MAX_DIMENSION;
+use crate::rng::Rng;
+
+const CELLS: usize = MAX_DIMENSION as usize * MAX_DIMENSION as usize;
+
+// Fixed so that hashes stay the same between runs and can be stored alongside saved games
+const SEED: u64 = 0x5eed_7ac7_0e00_2025;
+
+struct Keys {
+    pieces: [[u64; CELLS]; 2],
+    side: u64,
+}
+
+fn keys() -> &'static Keys {
+    static KEYS: OnceLock<Keys> = OnceLock::new();
+    KEYS.get_or_init(|| {
+        let mut rng = Rng::new(SEED);
+        let mut pieces = [[0; CELLS]; 2];
+        for side in pieces.iter_mut() {
+            for key in side.iter_mut() {
+                *key = rng.next_u64();
+            }
+        }
+        Keys {
+            pieces,
+            side: rng.next_u64(),
+        }
+    })
+}
+
+// Key for `player` occupying `index`
+pub fn piece(player: &Player, index: usize) -> u64 {
+    match player {
+        Player::X => keys().pieces[0][index],
+        Player::O => keys().pieces[1][index],
+        Player::None => 0,
+    }
+}
+
+// Toggled whenever the side to move changes
+pub fn side() -> u64 {
+    keys().side
+}
+
+// Starting hash of an empty board, so equal piece layouts on different boards don't collide
+pub fn geometry(width: u8, height: u8, win_length: u8) -> u64 {
+    Rng::new(SEED ^ ((width as u64) << 16 | (height as u64) << 8 | win_length as u64)).next_u64()
+}